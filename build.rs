@@ -1,6 +1,6 @@
 fn main() {
     tonic_build::configure()
         .build_server(true)
-        .compile(&["proto/predict.proto"], &["proto"])
+        .compile(&["proto/predict.proto", "proto/onnx.proto"], &["proto"])
         .unwrap();
 }
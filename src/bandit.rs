@@ -0,0 +1,452 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_distr::{Beta, Distribution};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::config::{ArmConfig, BanditConfig, BanditPolicy};
+use crate::error::Error;
+
+/// What an arm has earned so far
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ArmStats {
+    /// Successful predictions served by the arm
+    pub pulls: u64,
+    /// Predictions that received a reward
+    pub rewards: u64,
+    pub reward_sum: f64,
+}
+
+impl ArmStats {
+    /// Mean reward, or `None` before the first reward
+    pub fn mean_reward(&self) -> Option<f64> {
+        (self.rewards > 0).then(|| self.reward_sum / self.rewards as f64)
+    }
+
+    /// Draw a mean reward from the Beta posterior, starting from a uniform prior. Rewards
+    /// are between 0 and 1 under Thompson sampling, so a sum outside `[0, rewards]` can only
+    /// come from statistics saved under another policy and is clamped.
+    fn sample(&self, rng: &mut impl Rng) -> f64 {
+        let rewards = self.rewards as f64;
+        let successes = self.reward_sum.clamp(0.0, rewards);
+        match Beta::new(1.0 + successes, 1.0 + rewards - successes) {
+            Ok(posterior) => posterior.sample(rng),
+            // Only a non-finite sum gets here, which tells us nothing about the arm
+            Err(_) => rng.gen(),
+        }
+    }
+}
+
+/// An arm and its statistics as reported over HTTP
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArmReport {
+    #[serde(flatten)]
+    pub config: ArmConfig,
+    #[serde(flatten)]
+    pub stats: ArmStats,
+    pub mean_reward: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BanditReport {
+    pub name: String,
+    pub policy: BanditPolicy,
+    pub arms: Vec<ArmReport>,
+}
+
+/// Shortest time between two saves of a bandit's statistics
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Predictions waiting for a reward, oldest first so expired ones can be dropped cheaply
+#[derive(Debug, Default)]
+struct Pending {
+    arms: HashMap<Uuid, (usize, Instant)>,
+    order: VecDeque<(Instant, Uuid)>,
+}
+
+/// `Bandit` routes requests for a logical model name between several arms.
+///
+/// Each successful prediction is remembered against the arm that served it until a reward
+/// is posted for its `prediction_id`, `feedback_ttl_secs` passes or `max_pending` newer
+/// predictions are waiting. Rewards update the arm's statistics, which steer the policy
+/// towards the better arms and are saved to `state_path` so they survive restarts. Saves
+/// happen in the background at most once per second, so a burst of rewards is written
+/// once.
+///
+#[derive(Debug)]
+pub struct Bandit {
+    pub config: BanditConfig,
+    stats: Mutex<Vec<ArmStats>>,
+    pending: Mutex<Pending>,
+    /// Latest statistics for the task saving them, when `state_path` is set
+    saved_tx: Option<watch::Sender<Vec<ArmStats>>>,
+}
+
+impl Bandit {
+    /// Must be called from within the tokio runtime when `state_path` is set, which starts
+    /// the task saving the statistics
+    pub fn new(config: BanditConfig) -> Self {
+        let stats = load_stats(&config);
+        let saved_tx = config.state_path.clone().map(|path| {
+            let (saved_tx, saved_rx) = watch::channel(stats.clone());
+            tokio::spawn(save_stats(config.clone(), path, saved_rx));
+            saved_tx
+        });
+
+        Self {
+            config,
+            stats: Mutex::new(stats),
+            pending: Mutex::new(Pending::default()),
+            saved_tx,
+        }
+    }
+
+    /// Pick the arm to serve a request. Returns its index into the configured arms.
+    pub fn choose(&self) -> usize {
+        let stats = self.stats.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        let indices: Vec<usize> = (0..stats.len()).collect();
+
+        match self.config.policy {
+            BanditPolicy::EpsilonGreedy { epsilon } => {
+                // Arms without rewards yet are tried before exploiting the others
+                let untried: Vec<usize> = indices
+                    .iter()
+                    .copied()
+                    .filter(|&index| stats[index].rewards == 0)
+                    .collect();
+                // Epsilon was checked to be between 0 and 1 when loading the config
+                if rng.gen_bool(epsilon) {
+                    *indices.choose(&mut rng).unwrap()
+                } else if let Some(&index) = untried.choose(&mut rng) {
+                    index
+                } else {
+                    indices
+                        .into_iter()
+                        .max_by(|&a, &b| {
+                            let a = stats[a].mean_reward().unwrap_or_default();
+                            let b = stats[b].mean_reward().unwrap_or_default();
+                            a.total_cmp(&b)
+                        })
+                        .unwrap()
+                }
+            }
+            BanditPolicy::ThompsonSampling => indices
+                .into_iter()
+                .map(|index| (index, stats[index].sample(&mut rng)))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index)
+                .unwrap(),
+        }
+    }
+
+    pub fn arm(&self, index: usize) -> &ArmConfig {
+        &self.config.arms[index]
+    }
+
+    /// Remember which arm served a successful prediction so it can be rewarded later
+    pub fn record_pull(&self, prediction_id: Uuid, arm: usize) {
+        self.stats.lock().unwrap()[arm].pulls += 1;
+        metrics::increment_counter!("proton_bandit_pulls_total", &self.labels(arm));
+
+        let ttl = Duration::from_secs(self.config.feedback_ttl_secs);
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        // Drop expired predictions, and the oldest ones once too many are waiting. The
+        // order can still hold predictions that were rewarded, which count towards the
+        // limit until they expire.
+        while let Some(&(at, id)) = pending.order.front() {
+            if now.duration_since(at) < ttl && pending.order.len() < self.config.max_pending {
+                break;
+            }
+            pending.order.pop_front();
+            pending.arms.remove(&id);
+        }
+        pending.arms.insert(prediction_id, (arm, now));
+        pending.order.push_back((now, prediction_id));
+    }
+
+    /// Whether a prediction served by this bandit is still waiting for a reward
+    pub fn is_pending(&self, prediction_id: &Uuid) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .arms
+            .contains_key(prediction_id)
+    }
+
+    /// Credit the arm that served a prediction with a reward and queue the statistics to be
+    /// saved
+    pub fn feedback(&self, prediction_id: &Uuid, reward: f64) -> Result<(), Error> {
+        let in_range = match self.config.policy {
+            BanditPolicy::EpsilonGreedy { .. } => reward.is_finite(),
+            BanditPolicy::ThompsonSampling => (0.0..=1.0).contains(&reward),
+        };
+        if !in_range {
+            return Err(Error::InvalidArgument(format!(
+                "reward {} is out of range for bandit {}",
+                reward, self.config.name
+            )));
+        }
+
+        // Each prediction can be rewarded once
+        let ttl = Duration::from_secs(self.config.feedback_ttl_secs);
+        let (arm, _) = self
+            .pending
+            .lock()
+            .unwrap()
+            .arms
+            .remove(prediction_id)
+            .filter(|(_, at)| at.elapsed() < ttl)
+            .ok_or_else(|| {
+                Error::ModelNotFound(format!(
+                    "prediction {} is unknown or has expired",
+                    prediction_id
+                ))
+            })?;
+
+        let mut stats = self.stats.lock().unwrap();
+        stats[arm].rewards += 1;
+        stats[arm].reward_sum += reward;
+
+        let labels = self.labels(arm);
+        metrics::increment_counter!("proton_bandit_rewards_total", &labels);
+        if let Some(mean_reward) = stats[arm].mean_reward() {
+            metrics::gauge!("proton_bandit_mean_reward", mean_reward, &labels);
+        }
+
+        if let Some(saved_tx) = &self.saved_tx {
+            saved_tx.send_replace(stats.clone());
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> BanditReport {
+        let stats = self.stats.lock().unwrap();
+        let arms = self
+            .config
+            .arms
+            .iter()
+            .zip(stats.iter())
+            .map(|(config, stats)| ArmReport {
+                config: config.clone(),
+                stats: stats.clone(),
+                mean_reward: stats.mean_reward(),
+            })
+            .collect();
+
+        BanditReport {
+            name: self.config.name.clone(),
+            policy: self.config.policy.clone(),
+            arms,
+        }
+    }
+
+    fn labels(&self, arm: usize) -> [(&'static str, String); 2] {
+        [
+            ("bandit", self.config.name.clone()),
+            ("arm", self.config.arms[arm].name.clone()),
+        ]
+    }
+}
+
+/// Save the latest statistics whenever they change, waiting `SAVE_INTERVAL` between
+/// saves. Stops once the bandit is dropped.
+async fn save_stats(
+    config: BanditConfig,
+    path: String,
+    mut saved_rx: watch::Receiver<Vec<ArmStats>>,
+) {
+    while saved_rx.changed().await.is_ok() {
+        let stats = saved_rx.borrow_and_update().clone();
+        let by_arm: HashMap<String, ArmStats> = config
+            .arms
+            .iter()
+            .map(|arm| arm.name.clone())
+            .zip(stats)
+            .collect();
+
+        let path = path.clone();
+        let saved = tokio::task::spawn_blocking(move || write_stats(&path, &by_arm)).await;
+        if let Ok(Err(err)) = saved {
+            tracing::error!("bandit {} failed to save state: {}", config.name, err);
+        }
+        tokio::time::sleep(SAVE_INTERVAL).await;
+    }
+}
+
+/// Write the statistics keyed by arm name, through a temporary file so a crash mid write
+/// doesn't lose the previous state
+fn write_stats(path: &str, by_arm: &HashMap<String, ArmStats>) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+    let data = serde_json::to_vec(by_arm).map_err(|err| err.to_string())?;
+    fs::write(&tmp_path, data).map_err(|err| err.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|err| err.to_string())
+}
+
+/// Read the saved statistics of each configured arm. Arms that weren't saved, e.g. ones
+/// added since, or whose saved statistics don't add up start from scratch.
+fn load_stats(config: &BanditConfig) -> Vec<ArmStats> {
+    let mut saved: HashMap<String, ArmStats> = config
+        .state_path
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|data| match serde_json::from_slice(&data) {
+            Ok(saved) => Some(saved),
+            Err(err) => {
+                tracing::warn!("bandit {} ignoring saved state: {}", config.name, err);
+                None
+            }
+        })
+        .unwrap_or_default();
+
+    config
+        .arms
+        .iter()
+        .map(|arm| {
+            let stats = saved.remove(&arm.name).unwrap_or_default();
+            if !stats.reward_sum.is_finite() || stats.rewards > stats.pulls {
+                tracing::warn!(
+                    "bandit {} resetting invalid saved state of arm {}",
+                    config.name,
+                    arm.name
+                );
+                return ArmStats::default();
+            }
+            stats
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config(policy: BanditPolicy, max_pending: usize) -> BanditConfig {
+        BanditConfig {
+            name: "squeezenet".to_string(),
+            policy,
+            arms: ["a", "b"]
+                .iter()
+                .map(|name| ArmConfig {
+                    name: name.to_string(),
+                    model: format!("squeezenet-{}", name),
+                    version: None,
+                })
+                .collect(),
+            state_path: None,
+            feedback_ttl_secs: 3600,
+            max_pending,
+        }
+    }
+
+    fn greedy() -> BanditPolicy {
+        BanditPolicy::EpsilonGreedy { epsilon: 0.0 }
+    }
+
+    #[test]
+    fn greedy_exploits_the_best_arm() {
+        let bandit = Bandit::new(config(greedy(), 100));
+        for (arm, reward) in [(0, 0.2), (1, 0.8)] {
+            let prediction_id = Uuid::new_v4();
+            bandit.record_pull(prediction_id, arm);
+            bandit.feedback(&prediction_id, reward).unwrap();
+        }
+
+        assert!((0..100).all(|_| bandit.choose() == 1));
+    }
+
+    #[test]
+    fn each_prediction_is_rewarded_once() {
+        let bandit = Bandit::new(config(greedy(), 100));
+        let prediction_id = Uuid::new_v4();
+        bandit.record_pull(prediction_id, 0);
+
+        assert!(bandit.feedback(&prediction_id, 1.0).is_ok());
+        assert!(bandit.feedback(&prediction_id, 1.0).is_err());
+        assert_eq!(bandit.report().arms[0].stats.rewards, 1);
+    }
+
+    #[test]
+    fn thompson_sampling_rejects_rewards_outside_0_to_1() {
+        let bandit = Bandit::new(config(BanditPolicy::ThompsonSampling, 100));
+        let prediction_id = Uuid::new_v4();
+        bandit.record_pull(prediction_id, 0);
+
+        assert!(bandit.feedback(&prediction_id, 1.5).is_err());
+        assert!(bandit.is_pending(&prediction_id));
+    }
+
+    #[test]
+    fn oldest_pending_predictions_are_dropped_beyond_max_pending() {
+        let bandit = Bandit::new(config(greedy(), 3));
+        let ids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        for id in ids.iter() {
+            bandit.record_pull(*id, 0);
+        }
+
+        assert!(!bandit.is_pending(&ids[0]));
+        assert!(!bandit.is_pending(&ids[1]));
+        assert!(ids[2..].iter().all(|id| bandit.is_pending(id)));
+        assert_eq!(bandit.pending.lock().unwrap().order.len(), 3);
+    }
+
+    #[test]
+    fn validate_rejects_bad_epsilon() {
+        assert!(config(greedy(), 100).validate().is_ok());
+        for epsilon in [f64::NAN, -0.1, 1.1, f64::INFINITY] {
+            let policy = BanditPolicy::EpsilonGreedy { epsilon };
+            assert!(config(policy, 100).validate().is_err());
+        }
+        assert!(config(greedy(), 0).validate().is_err());
+    }
+
+    #[test]
+    fn thompson_sampling_tolerates_any_saved_statistics() {
+        let mut rng = rand::thread_rng();
+        for (rewards, reward_sum) in [
+            (0, 0.0),
+            (10, 7.0),
+            (10, 25.0),
+            (10, -3.0),
+            (0, 1e300),
+            (10, f64::NAN),
+            (10, f64::INFINITY),
+            (10, f64::NEG_INFINITY),
+        ] {
+            let stats = ArmStats {
+                pulls: rewards,
+                rewards,
+                reward_sum,
+            };
+            let sample = stats.sample(&mut rng);
+            assert!((0.0..=1.0).contains(&sample), "{:?} gave {}", stats, sample);
+        }
+    }
+
+    #[test]
+    fn invalid_saved_statistics_are_reset() {
+        let path = env::temp_dir().join(format!("proton-bandit-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"a": {"pulls": 4, "rewards": 2, "reward_sum": 1.5},
+                "b": {"pulls": 1, "rewards": 3, "reward_sum": 1.0}}"#,
+        )
+        .unwrap();
+        let mut config = config(BanditPolicy::ThompsonSampling, 100);
+        config.state_path = Some(path.to_string_lossy().into_owned());
+
+        let stats = load_stats(&config);
+        fs::remove_file(&path).unwrap();
+        assert_eq!((stats[0].pulls, stats[0].rewards), (4, 2));
+        assert_eq!(stats[0].reward_sum, 1.5);
+        assert_eq!((stats[1].pulls, stats[1].rewards), (0, 0));
+        assert_eq!(stats[1].reward_sum, 0.0);
+    }
+}
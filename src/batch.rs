@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use crate::metadata::TensorInfo;
+use crate::tensor::{DataType, Tensor};
+use crate::worker::Message;
+
+/// Requests can share a batch when they have the same inputs with the same dtypes and
+/// the same dimensions apart from the first
+type Signature = Vec<(String, DataType, Vec<usize>)>;
+
+/// Whether requests can be stacked for a model with these inputs, which needs the first
+/// dimension of every input to be dynamic. Models exported with a fixed batch size, e.g.
+/// `[1, 3, 224, 224]`, reject stacked inputs.
+pub fn supports_batching(inputs: &[TensorInfo]) -> bool {
+    inputs
+        .iter()
+        .all(|input| matches!(input.shape.first(), Some(None)))
+}
+
+/// Number of items a request contributes to a batch, i.e. the length of the first axis
+/// shared by all of its inputs. Returns `None` if the request can't be batched.
+pub fn batch_size(inputs: &HashMap<String, Tensor>) -> Option<usize> {
+    let mut sizes = inputs
+        .values()
+        .map(|tensor| tensor.shape().first().copied());
+    let first = sizes.next()??;
+
+    sizes.all(|size| size == Some(first)).then_some(first)
+}
+
+fn signature(inputs: &HashMap<String, Tensor>) -> Option<Signature> {
+    batch_size(inputs)?;
+
+    let mut signature: Signature = inputs
+        .iter()
+        .map(|(name, tensor)| (name.clone(), tensor.dtype(), tensor.shape()[1..].to_vec()))
+        .collect();
+    signature.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Some(signature)
+}
+
+/// Partition messages into groups that can be stacked into a single run, keeping arrival
+/// order within each group. Messages that can't be batched get a group of their own, as
+/// does every message when the model isn't `batchable`, see [`supports_batching`].
+pub fn group(messages: Vec<Message>, batchable: bool) -> Vec<Vec<Message>> {
+    if !batchable {
+        return messages.into_iter().map(|message| vec![message]).collect();
+    }
+
+    let mut groups: Vec<(Option<Signature>, Vec<Message>)> = Vec::new();
+
+    for message in messages {
+        let signature = signature(&message.inputs);
+        let group = signature.as_ref().and_then(|signature| {
+            groups
+                .iter_mut()
+                .find(|(other, _)| other.as_ref() == Some(signature))
+        });
+
+        match group {
+            Some((_, group)) => group.push(message),
+            None => groups.push((signature, vec![message])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Concatenate each named input along the first axis. Returns the stacked inputs and the
+/// number of items each request contributed, or `None` if the requests don't share a
+/// signature.
+pub fn stack(
+    inputs: Vec<HashMap<String, Tensor>>,
+) -> Option<(HashMap<String, Tensor>, Vec<usize>)> {
+    let sizes = inputs
+        .iter()
+        .map(batch_size)
+        .collect::<Option<Vec<usize>>>()?;
+
+    let mut columns: HashMap<String, Vec<Tensor>> = HashMap::new();
+    for request_inputs in inputs {
+        for (name, tensor) in request_inputs {
+            columns.entry(name).or_default().push(tensor);
+        }
+    }
+
+    let stacked = columns
+        .into_iter()
+        .map(|(name, tensors)| {
+            // Every request must have provided the input
+            if tensors.len() != sizes.len() {
+                return None;
+            }
+            Some((name, Tensor::concatenate(tensors)?))
+        })
+        .collect::<Option<HashMap<String, Tensor>>>()?;
+
+    Some((stacked, sizes))
+}
+
+/// Split each named output of a batched run back into per request outputs. Returns `None`
+/// if an output isn't batched along its first axis.
+pub fn unstack(
+    outputs: HashMap<String, Tensor>,
+    sizes: &[usize],
+) -> Option<Vec<HashMap<String, Tensor>>> {
+    let mut unstacked = vec![HashMap::new(); sizes.len()];
+    for (name, tensor) in outputs {
+        for (request_outputs, part) in unstacked.iter_mut().zip(tensor.split(sizes)?) {
+            request_outputs.insert(name.clone(), part);
+        }
+    }
+
+    Some(unstacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::priority::Priority;
+    use ndarray::ArrayD;
+    use std::time::Instant;
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    fn input(shape: Vec<Option<usize>>) -> TensorInfo {
+        TensorInfo {
+            name: "data_0".to_string(),
+            dtype: "float32".to_string(),
+            shape,
+        }
+    }
+
+    #[test]
+    fn dynamic_first_dimension_supports_batching() {
+        let inputs = vec![input(vec![None, Some(3), Some(224), Some(224)])];
+        assert!(supports_batching(&inputs));
+    }
+
+    #[test]
+    fn fixed_first_dimension_does_not_support_batching() {
+        let inputs = vec![input(vec![Some(1), Some(3), Some(224), Some(224)])];
+        assert!(!supports_batching(&inputs));
+
+        let mixed = vec![input(vec![None, Some(3)]), input(vec![Some(1), Some(3)])];
+        assert!(!supports_batching(&mixed));
+        assert!(!supports_batching(&[input(vec![])]));
+    }
+
+    fn floats(shape: &[usize]) -> Tensor {
+        let len = shape.iter().product();
+        let data = (0..len).map(|value| value as f32).collect();
+        Tensor::Float32(ArrayD::from_shape_vec(shape.to_vec(), data).unwrap())
+    }
+
+    fn strings(values: &[&str]) -> Tensor {
+        let data = values.iter().map(|value| value.to_string()).collect();
+        Tensor::String(ArrayD::from_shape_vec(vec![values.len()], data).unwrap())
+    }
+
+    fn inputs(tensors: Vec<(&str, Tensor)>) -> HashMap<String, Tensor> {
+        tensors
+            .into_iter()
+            .map(|(name, tensor)| (name.to_string(), tensor))
+            .collect()
+    }
+
+    fn message(inputs: HashMap<String, Tensor>) -> Message {
+        let (response_tx, _) = oneshot::channel();
+        Message {
+            prediction_id: Uuid::new_v4(),
+            model_name: "resnet".to_string(),
+            inputs,
+            deadline: None,
+            priority: Priority::Normal,
+            enqueued_at: Instant::now(),
+            response_tx,
+        }
+    }
+
+    fn group_sizes(groups: &[Vec<Message>]) -> Vec<Vec<usize>> {
+        groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|message| batch_size(&message.inputs).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_requests_by_signature_in_arrival_order() {
+        let messages = vec![
+            message(inputs(vec![("data_0", floats(&[1, 3]))])),
+            message(inputs(vec![("data_0", floats(&[2, 4]))])),
+            message(inputs(vec![("data_0", floats(&[4, 3]))])),
+            message(inputs(vec![("text", strings(&["a", "b"]))])),
+            message(inputs(vec![("data_0", floats(&[2, 3]))])),
+            message(inputs(vec![("text", strings(&["c"]))])),
+        ];
+
+        let groups = group(messages, true);
+        assert_eq!(
+            group_sizes(&groups),
+            vec![vec![1, 4, 2], vec![2], vec![2, 1]]
+        );
+    }
+
+    #[test]
+    fn requests_that_cant_be_batched_get_a_group_each() {
+        let ragged = inputs(vec![("a", floats(&[1, 3])), ("b", floats(&[2, 3]))]);
+        let scalar = inputs(vec![("a", Tensor::Float32(ArrayD::from_elem(vec![], 1.0)))]);
+        let messages = vec![
+            message(ragged.clone()),
+            message(ragged),
+            message(scalar.clone()),
+            message(scalar),
+        ];
+
+        let groups = group(messages, true);
+        assert_eq!(groups.len(), 4);
+        assert!(groups.iter().all(|group| group.len() == 1));
+    }
+
+    #[test]
+    fn models_with_a_fixed_first_dimension_run_requests_one_by_one() {
+        let messages = (0..3)
+            .map(|_| message(inputs(vec![("data_0", floats(&[1, 3]))])))
+            .collect();
+
+        let groups = group(messages, false);
+        assert_eq!(group_sizes(&groups), vec![vec![1], vec![1], vec![1]]);
+    }
+
+    #[test]
+    fn stacks_and_unstacks_mixed_batch_sizes() {
+        let requests = vec![
+            inputs(vec![("data_0", floats(&[1, 2])), ("text", strings(&["a"]))]),
+            inputs(vec![
+                ("data_0", floats(&[3, 2])),
+                ("text", strings(&["b", "c", "d"])),
+            ]),
+            inputs(vec![
+                ("data_0", floats(&[2, 2])),
+                ("text", strings(&["e", "f"])),
+            ]),
+        ];
+
+        let (stacked, sizes) = stack(requests.clone()).unwrap();
+        assert_eq!(sizes, vec![1, 3, 2]);
+        assert_eq!(stacked["data_0"].shape(), &[6, 2]);
+        let Tensor::String(text) = &stacked["text"] else {
+            panic!("strings should stay strings");
+        };
+        assert_eq!(
+            text.iter().collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+
+        let unstacked = unstack(stacked, &sizes).unwrap();
+        assert_eq!(unstacked.len(), 3);
+        for (outputs, request) in unstacked.iter().zip(&requests) {
+            assert_eq!(outputs["data_0"].shape(), request["data_0"].shape());
+            let (Tensor::String(output), Tensor::String(input)) =
+                (&outputs["text"], &request["text"])
+            else {
+                panic!("strings should stay strings");
+            };
+            assert_eq!(output, input);
+        }
+    }
+
+    #[test]
+    fn stack_rejects_requests_with_different_signatures() {
+        let missing = vec![
+            inputs(vec![("a", floats(&[1, 2])), ("b", floats(&[1, 2]))]),
+            inputs(vec![("a", floats(&[1, 2]))]),
+        ];
+        assert!(stack(missing).is_none());
+
+        let dtypes = vec![
+            inputs(vec![("a", floats(&[1]))]),
+            inputs(vec![("a", strings(&["x"]))]),
+        ];
+        assert!(stack(dtypes).is_none());
+
+        let ragged = vec![inputs(vec![("a", floats(&[1, 2])), ("b", floats(&[2, 2]))])];
+        assert!(stack(ragged).is_none());
+    }
+
+    #[test]
+    fn unstack_rejects_outputs_not_batched_along_the_first_axis() {
+        let outputs = inputs(vec![("scores", floats(&[3, 10])), ("count", floats(&[1]))]);
+        assert!(unstack(outputs, &[1, 2]).is_none());
+    }
+}
@@ -22,8 +22,10 @@ async fn send_request(client: &Client, data: &InferenceRequest) -> (String, Dura
         StatusCode::OK => {
             match response.json::<InferenceResponse>().await {
                 Ok(parsed) => println!(
-                    "Success for model {:?}, prediction_id: {:?}",
-                    parsed.model_name, parsed.prediction_id
+                    "Success for model {:?}, prediction_id: {:?}, outputs: {:?}",
+                    parsed.model_name,
+                    parsed.prediction_id,
+                    parsed.outputs.keys().collect::<Vec<_>>()
                 ),
                 Err(err) => panic!("The response didn't match InferenceResponse {:?}", err),
             };
@@ -36,9 +38,7 @@ async fn send_request(client: &Client, data: &InferenceRequest) -> (String, Dura
 
 #[tokio::main]
 async fn main() {
-    let mut models: Vec<&dyn Model> = Vec::new();
-    models.push(&MaskRCNN {});
-    models.push(&Squeezenet {});
+    let models: Vec<&dyn Model> = vec![&MaskRCNN {}, &Squeezenet {}];
 
     let client = Client::new();
     let mut rng = thread_rng();
@@ -49,7 +49,12 @@ async fn main() {
         println!("Sending request for {:?}", &model.name());
         let data = InferenceRequest {
             model_name: model.name(),
-            data: model.dummy_data(),
+            model_version: None,
+            routing_key: None,
+            include_intermediate: false,
+            tenant: None,
+            labels: HashMap::new(),
+            inputs: model.dummy_inputs(),
         };
 
         let client = client.clone();
@@ -60,10 +65,7 @@ async fn main() {
     let mut elapsed_times: HashMap<String, Vec<Duration>> = HashMap::new();
     for future in futures {
         let (model_name, duration) = future.await.unwrap();
-        elapsed_times
-            .entry(model_name)
-            .or_insert_with(Vec::new)
-            .push(duration);
+        elapsed_times.entry(model_name).or_default().push(duration);
     }
 
     analyze_results(elapsed_times);
@@ -6,7 +6,7 @@ use tonic::transport::Channel;
 use tonic::Request;
 
 use proton::predictor::predictor_client::PredictorClient;
-use proton::predictor::InferenceRequest;
+use proton::predictor::{InferenceRequest, Tensor};
 use proton::utils::{analyze_results, MaskRCNN, Model, Squeezenet};
 
 const NUM_REQUESTS: usize = 20;
@@ -19,8 +19,10 @@ async fn send_request(
 
     let response = client.predict(request).await.unwrap().into_inner();
     println!(
-        "Success for model {}, prediction_id={}",
-        response.model_name, response.prediction_id
+        "Success for model {}, prediction_id={}, outputs={:?}",
+        response.model_name,
+        response.prediction_id,
+        response.outputs.keys().collect::<Vec<_>>()
     );
 
     (response.model_name, start_time.elapsed())
@@ -28,9 +30,7 @@ async fn send_request(
 
 #[tokio::main]
 async fn main() {
-    let mut models: Vec<&dyn Model> = Vec::new();
-    models.push(&MaskRCNN {});
-    models.push(&Squeezenet {});
+    let models: Vec<&dyn Model> = vec![&MaskRCNN {}, &Squeezenet {}];
 
     let client = PredictorClient::connect("http://0.0.0.0:50051")
         .await
@@ -44,10 +44,15 @@ async fn main() {
         let random_vector: Vec<f32> = (0..n).map(|_| rng.gen()).collect();
 
         println!("Sending request for {:?}", &model.name());
+        let input = Tensor {
+            float_data: random_vector,
+            shape: model.input_shape().iter().map(|x| *x as i32).collect(),
+            ..Default::default()
+        };
         let request = Request::new(InferenceRequest {
             model_name: model.name(),
-            data: random_vector,
-            shape: model.input_shape().iter().map(|x| *x as i32).collect(),
+            inputs: HashMap::from([(model.input_name(), input)]),
+            ..Default::default()
         });
 
         let mut client = client.clone();
@@ -58,10 +63,7 @@ async fn main() {
     let mut elapsed_times: HashMap<String, Vec<Duration>> = HashMap::new();
     for future in futures {
         let (model_name, duration) = future.await.unwrap();
-        elapsed_times
-            .entry(model_name)
-            .or_insert_with(Vec::new)
-            .push(duration);
+        elapsed_times.entry(model_name).or_default().push(duration);
     }
 
     analyze_results(elapsed_times);
@@ -1,13 +1,14 @@
-/// Onnx test script
-///
-/// This script is used to test running an onnx session with a variety of
-/// models.
-#[allow(dead_code, unused_variables)]
+//! Onnx test script
+//!
+//! This script is used to test running an onnx session with a variety of
+//! models.
+#![allow(dead_code)]
 use lazy_static::lazy_static;
 use ndarray::{Array, Dimension, IxDyn};
 use onnxruntime::tensor::OrtOwnedTensor;
 use onnxruntime::{environment::Environment, GraphOptimizationLevel, LoggingLevel};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
@@ -24,7 +25,7 @@ lazy_static! {
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Output<A> {
-    data: Array<A, IxDyn>,
+    outputs: HashMap<String, Array<A, IxDyn>>,
 }
 
 trait Model {
@@ -102,13 +103,25 @@ fn main() {
     println!("Session inputs: {:?}", session.inputs);
     println!("Session outputs: {:?}", session.outputs);
 
-    let input_tensor_values = vec![model.dummy_data().into()];
+    let input_tensor_values = vec![model.dummy_data()];
+    let output_names: Vec<String> = session
+        .outputs
+        .iter()
+        .map(|output| output.name.clone())
+        .collect();
 
     let outputs: Vec<OrtOwnedTensor<f32, _>> = session.run(input_tensor_values).unwrap();
 
-    let output_data =
-        Array::from_shape_vec(outputs[0].shape(), outputs[0].iter().cloned().collect()).unwrap();
-    let output = Output { data: output_data };
+    let outputs = output_names
+        .into_iter()
+        .zip(outputs.iter())
+        .map(|(name, tensor)| {
+            let data =
+                Array::from_shape_vec(tensor.shape(), tensor.iter().cloned().collect()).unwrap();
+            (name, data)
+        })
+        .collect();
+    let output = Output { outputs };
 
     println!("output={:?}", output);
 
@@ -0,0 +1,569 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{CanaryConfig, ModelConfig};
+use crate::error::Error;
+use crate::registry::DEFAULT_ALIAS;
+use crate::split::fnv1a;
+use crate::state::SharedState;
+
+/// Version the candidate is loaded under, next to the current version of the model
+pub const CANARY_VERSION: &str = "canary";
+
+/// Latencies kept per step for estimating the p99. Requests past this are still counted.
+const MAX_SAMPLES: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanaryStatus {
+    /// The candidate is being loaded
+    Loading,
+    /// The candidate is serving its share of traffic
+    Running,
+    /// The candidate passed every step and replaced the current version
+    Promoted,
+    /// The candidate breached a bound and was unloaded
+    RolledBack,
+    /// The candidate couldn't be loaded or promoted
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanaryAction {
+    Advance,
+    Promote,
+    RollBack,
+    Fail,
+}
+
+impl CanaryAction {
+    fn name(&self) -> &'static str {
+        match self {
+            CanaryAction::Advance => "advance",
+            CanaryAction::Promote => "promote",
+            CanaryAction::RollBack => "roll_back",
+            CanaryAction::Fail => "fail",
+        }
+    }
+}
+
+/// Requests served by one side of the canary during a step
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WindowStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub p99_seconds: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CanaryDecision {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub action: CanaryAction,
+    /// Candidate's share of traffic after the decision
+    pub share: f64,
+    pub reason: String,
+    pub baseline: Option<WindowStats>,
+    pub candidate: Option<WindowStats>,
+}
+
+/// State of a canary as reported by the admin endpoint
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CanaryReport {
+    pub model: String,
+    pub baseline_version: String,
+    pub candidate_path: String,
+    pub status: CanaryStatus,
+    /// Index into the configured steps
+    pub step: usize,
+    pub share: f64,
+    pub history: Vec<CanaryDecision>,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    requests: u64,
+    errors: u64,
+    latencies: Vec<f64>,
+}
+
+impl Window {
+    fn record(&mut self, ok: bool, duration: Duration) {
+        self.requests += 1;
+        self.errors += !ok as u64;
+        if self.latencies.len() < MAX_SAMPLES {
+            self.latencies.push(duration.as_secs_f64());
+        }
+    }
+
+    fn stats(&mut self) -> WindowStats {
+        self.latencies.sort_by(f64::total_cmp);
+        let p99 = match self.latencies.len() {
+            0 => 0.0,
+            len => self.latencies[(len * 99).div_ceil(100) - 1],
+        };
+
+        WindowStats {
+            requests: self.requests,
+            errors: self.errors,
+            error_rate: self.errors as f64 / self.requests.max(1) as f64,
+            p99_seconds: p99,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CanaryState {
+    status: CanaryStatus,
+    step: usize,
+    share: f64,
+    baseline: Window,
+    candidate: Window,
+    history: Vec<CanaryDecision>,
+}
+
+/// `Canary` rolls a candidate file out to a model step by step.
+///
+/// The candidate is loaded as the `canary` version of the model and requests without a
+/// version are split between it and the current version by the share of the current
+/// step. After each step the candidate is judged against the current version on the
+/// requests of that step: a breached bound rolls all traffic back and unloads the
+/// candidate, otherwise the share moves on to the next step. Passing the last step
+/// promotes the candidate by reloading the current version from the candidate's file.
+///
+#[derive(Debug)]
+pub struct Canary {
+    pub model: String,
+    pub baseline_version: String,
+    pub config: CanaryConfig,
+    state: Mutex<CanaryState>,
+}
+
+impl Canary {
+    fn new(model: String, baseline_version: String, config: CanaryConfig) -> Self {
+        Self {
+            model,
+            baseline_version,
+            config,
+            state: Mutex::new(CanaryState {
+                status: CanaryStatus::Loading,
+                step: 0,
+                share: 0.0,
+                baseline: Window::default(),
+                candidate: Window::default(),
+                history: Vec::new(),
+            }),
+        }
+    }
+
+    /// Pick the version for a request that didn't name one. Returns `None` unless the
+    /// canary is running. Requests with a routing key always get the same side for a given
+    /// share.
+    pub fn choose(&self, routing_key: Option<&str>) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        if state.status != CanaryStatus::Running {
+            return None;
+        }
+
+        let point = match routing_key {
+            Some(key) => (fnv1a(key.as_bytes()) % 10_000) as f64 / 10_000.0,
+            None => rand::thread_rng().gen::<f64>(),
+        };
+        let version = if point < state.share {
+            CANARY_VERSION
+        } else {
+            &self.baseline_version
+        };
+        Some(version.to_string())
+    }
+
+    /// Count a request routed by [`Canary::choose`] towards the current step
+    pub fn record(&self, version: &str, ok: bool, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.status != CanaryStatus::Running {
+            return;
+        }
+
+        if version == CANARY_VERSION {
+            state.candidate.record(ok, duration);
+        } else if version == self.baseline_version {
+            state.baseline.record(ok, duration);
+        }
+    }
+
+    pub fn report(&self) -> CanaryReport {
+        let state = self.state.lock().unwrap();
+        CanaryReport {
+            model: self.model.clone(),
+            baseline_version: self.baseline_version.clone(),
+            candidate_path: self.config.path.clone(),
+            status: state.status,
+            step: state.step,
+            share: state.share,
+            history: state.history.clone(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(
+            self.state.lock().unwrap().status,
+            CanaryStatus::Loading | CanaryStatus::Running
+        )
+    }
+
+    /// Load the candidate, then step it through the configured shares. Whatever the
+    /// outcome, the `default` alias is put back the way it was before the canary.
+    async fn run(self: Arc<Self>, shared_state: Arc<SharedState>, candidate: ModelConfig) {
+        let previous_default = shared_state.models.alias(&self.model, DEFAULT_ALIAS);
+
+        // Keep requests without a version on the current version, as the candidate would
+        // otherwise become the latest
+        let pinned = shared_state
+            .models
+            .default_version(&self.model)
+            .and_then(|version| {
+                shared_state
+                    .models
+                    .set_alias(&self.model, DEFAULT_ALIAS, &version)
+            });
+        match pinned {
+            Ok(_) => self.run_steps(&shared_state, candidate).await,
+            Err(err) => self.fail(format!("current version isn't loaded: {}", err)),
+        }
+
+        let restored = match previous_default {
+            Some(version) => shared_state
+                .models
+                .set_alias(&self.model, DEFAULT_ALIAS, &version),
+            None => shared_state.models.remove_alias(&self.model, DEFAULT_ALIAS),
+        };
+        if let Err(err) = restored {
+            tracing::warn!("{:?} failed to restore default alias: {}", &self.model, err);
+        }
+    }
+
+    async fn run_steps(&self, shared_state: &SharedState, candidate: ModelConfig) {
+        if let Err(err) = shared_state.models.load(candidate).await {
+            self.fail(format!("failed to load candidate: {}", err));
+            return;
+        }
+        self.set_step(0, CanaryStatus::Running);
+
+        let max_step_duration = Duration::from_secs(self.config.max_step_duration_secs());
+        let mut step_started = Instant::now();
+        loop {
+            tokio::time::sleep(Duration::from_secs(self.config.step_duration_secs)).await;
+
+            let (step, baseline, candidate) = {
+                let mut state = self.state.lock().unwrap();
+                (state.step, state.baseline.stats(), state.candidate.stats())
+            };
+
+            if candidate.requests < self.config.min_requests {
+                if step_started.elapsed() < max_step_duration {
+                    // Not enough traffic to judge the candidate yet, so extend the step
+                    continue;
+                }
+                // The candidate can't be judged, so it can't be trusted with more traffic
+                self.set_step(step, CanaryStatus::RolledBack);
+                let reason = format!(
+                    "only {} of {} requests after {}s",
+                    candidate.requests,
+                    self.config.min_requests,
+                    max_step_duration.as_secs()
+                );
+                self.decide(CanaryAction::RollBack, reason, baseline, candidate);
+                self.unload_candidate(shared_state).await;
+                return;
+            }
+            self.record_stats("baseline", &baseline);
+            self.record_stats("candidate", &candidate);
+
+            if let Some(reason) = self.breach(&baseline, &candidate) {
+                self.set_step(step, CanaryStatus::RolledBack);
+                self.decide(CanaryAction::RollBack, reason, baseline, candidate);
+                self.unload_candidate(shared_state).await;
+                return;
+            }
+
+            if step + 1 < self.config.steps.len() {
+                self.set_step(step + 1, CanaryStatus::Running);
+                step_started = Instant::now();
+                let reason = format!("step {} within bounds", step);
+                self.decide(CanaryAction::Advance, reason, baseline, candidate);
+                continue;
+            }
+
+            // The candidate keeps its share until the current version has been replaced
+            let promoted = shared_state
+                .models
+                .reload(
+                    &self.model,
+                    Some(&self.baseline_version),
+                    Some(self.config.path.clone()),
+                )
+                .await;
+            match promoted {
+                Ok(_) => {
+                    self.set_step(step, CanaryStatus::Promoted);
+                    let reason = "passed every step".to_string();
+                    self.decide(CanaryAction::Promote, reason, baseline, candidate);
+                }
+                Err(err) => self.fail(format!("failed to promote candidate: {}", err)),
+            }
+            self.unload_candidate(shared_state).await;
+            return;
+        }
+    }
+
+    /// Reason the candidate fails the step, if it does
+    fn breach(&self, baseline: &WindowStats, candidate: &WindowStats) -> Option<String> {
+        if candidate.error_rate - baseline.error_rate > self.config.max_error_rate_increase {
+            return Some(format!(
+                "error rate {:.4} against {:.4} for the baseline",
+                candidate.error_rate, baseline.error_rate
+            ));
+        }
+        // Without baseline traffic there's nothing to compare the latency against
+        if baseline.requests > 0
+            && candidate.p99_seconds > baseline.p99_seconds * self.config.max_latency_ratio
+        {
+            return Some(format!(
+                "p99 latency {:.4}s against {:.4}s for the baseline",
+                candidate.p99_seconds, baseline.p99_seconds
+            ));
+        }
+        None
+    }
+
+    /// Move to a step, starting a fresh window of requests. The share drops to zero once
+    /// the canary is over.
+    fn set_step(&self, step: usize, status: CanaryStatus) {
+        let mut state = self.state.lock().unwrap();
+        state.status = status;
+        state.step = step;
+        state.share = match status {
+            CanaryStatus::Running => self.config.steps[step],
+            _ => 0.0,
+        };
+        state.baseline = Window::default();
+        state.candidate = Window::default();
+
+        metrics::gauge!("proton_canary_traffic_share", state.share, "model" => self.model.clone());
+    }
+
+    fn decide(
+        &self,
+        action: CanaryAction,
+        reason: String,
+        baseline: WindowStats,
+        candidate: WindowStats,
+    ) {
+        self.push_decision(action, reason, Some(baseline), Some(candidate));
+    }
+
+    fn fail(&self, reason: String) {
+        tracing::error!("{:?} canary failed: {}", &self.model, &reason);
+        let step = self.state.lock().unwrap().step;
+        self.set_step(step, CanaryStatus::Failed);
+        self.push_decision(CanaryAction::Fail, reason, None, None);
+    }
+
+    fn push_decision(
+        &self,
+        action: CanaryAction,
+        reason: String,
+        baseline: Option<WindowStats>,
+        candidate: Option<WindowStats>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        tracing::info!(
+            "{:?} canary decided to {} at share {}: {}",
+            &self.model,
+            action.name(),
+            state.share,
+            &reason
+        );
+        metrics::increment_counter!(
+            "proton_canary_decisions_total",
+            "model" => self.model.clone(),
+            "action" => action.name()
+        );
+
+        let share = state.share;
+        state.history.push(CanaryDecision {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            action,
+            share,
+            reason,
+            baseline,
+            candidate,
+        });
+    }
+
+    fn record_stats(&self, role: &'static str, stats: &WindowStats) {
+        let labels = [("model", self.model.clone()), ("role", role.to_string())];
+        metrics::gauge!("proton_canary_error_rate", stats.error_rate, &labels);
+        metrics::gauge!("proton_canary_p99_seconds", stats.p99_seconds, &labels);
+    }
+
+    async fn unload_candidate(&self, shared_state: &SharedState) {
+        if let Err(err) = shared_state
+            .models
+            .unload(&self.model, Some(CANARY_VERSION))
+            .await
+        {
+            tracing::warn!("{:?} failed to unload canary: {}", &self.model, err);
+        }
+    }
+}
+
+/// Check that a canary can be started for a model, i.e. that none is in progress
+pub fn check_idle(shared_state: &SharedState, model_name: &str) -> Result<(), Error> {
+    let canaries = shared_state.canaries.read().unwrap();
+    match canaries.get(model_name) {
+        Some(canary) if canary.is_active() => Err(Error::AlreadyExists(format!(
+            "a canary is already in progress for model {}",
+            model_name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Start the canary configured for a model, if any, in the background
+pub fn start(shared_state: Arc<SharedState>, config: &ModelConfig) -> Result<(), Error> {
+    let Some(canary_config) = config.canary.clone() else {
+        return Ok(());
+    };
+    check_idle(&shared_state, &config.name)?;
+
+    let candidate = ModelConfig {
+        version: CANARY_VERSION.to_string(),
+        path: canary_config.path.clone(),
+        aliases: Vec::new(),
+        canary: None,
+        ..config.clone()
+    };
+    let canary = Arc::new(Canary::new(
+        config.name.clone(),
+        config.version.clone(),
+        canary_config,
+    ));
+    shared_state
+        .canaries
+        .write()
+        .unwrap()
+        .insert(config.name.clone(), Arc::clone(&canary));
+
+    tokio::spawn(canary.run(shared_state, candidate));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CanaryConfig {
+        CanaryConfig {
+            path: "squeezenet1.1-7.onnx".to_string(),
+            steps: vec![0.05, 0.5, 1.0],
+            step_duration_secs: 300,
+            min_requests: 100,
+            max_step_duration_secs: None,
+            max_error_rate_increase: 0.01,
+            max_latency_ratio: 1.2,
+        }
+    }
+
+    fn canary() -> Canary {
+        Canary::new("squeezenet".to_string(), "1".to_string(), config())
+    }
+
+    fn stats(requests: u64, errors: u64, p99_seconds: f64) -> WindowStats {
+        WindowStats {
+            requests,
+            errors,
+            error_rate: errors as f64 / requests as f64,
+            p99_seconds,
+        }
+    }
+
+    #[test]
+    fn candidate_within_bounds_passes() {
+        let baseline = stats(1000, 10, 0.100);
+        let candidate = stats(100, 1, 0.110);
+        assert_eq!(canary().breach(&baseline, &candidate), None);
+    }
+
+    #[test]
+    fn higher_error_rate_breaches() {
+        let baseline = stats(1000, 10, 0.100);
+        let candidate = stats(100, 3, 0.100);
+        let reason = canary().breach(&baseline, &candidate).unwrap();
+        assert!(reason.starts_with("error rate"), "{}", reason);
+    }
+
+    #[test]
+    fn slower_p99_breaches() {
+        let baseline = stats(1000, 0, 0.100);
+        let candidate = stats(100, 0, 0.130);
+        let reason = canary().breach(&baseline, &candidate).unwrap();
+        assert!(reason.starts_with("p99 latency"), "{}", reason);
+    }
+
+    #[test]
+    fn latency_needs_baseline_traffic() {
+        let baseline = WindowStats::default();
+        let candidate = stats(100, 0, 10.0);
+        assert_eq!(canary().breach(&baseline, &candidate), None);
+    }
+
+    #[test]
+    fn window_reports_the_p99() {
+        let mut window = Window::default();
+        for millis in 1..=100 {
+            window.record(millis != 1, Duration::from_millis(millis));
+        }
+        let stats = window.stats();
+        assert_eq!(stats.requests, 100);
+        assert_eq!(stats.errors, 1);
+        assert!((stats.p99_seconds - 0.099).abs() < 1e-9);
+    }
+
+    #[test]
+    fn choose_follows_the_share_once_running() {
+        let canary = canary();
+        assert_eq!(canary.choose(Some("user-1")), None);
+
+        canary.set_step(2, CanaryStatus::Running);
+        assert_eq!(canary.choose(None).as_deref(), Some(CANARY_VERSION));
+        canary.set_step(2, CanaryStatus::RolledBack);
+        assert_eq!(canary.choose(None), None);
+    }
+
+    #[test]
+    fn validate_rejects_bad_steps_and_paths() {
+        assert!(config().validate().is_ok());
+        for steps in [vec![], vec![0.5, f64::NAN], vec![0.0], vec![1.5]] {
+            let invalid = CanaryConfig { steps, ..config() };
+            assert!(invalid.validate().is_err());
+        }
+        let invalid = CanaryConfig {
+            path: String::new(),
+            ..config()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = CanaryConfig {
+            max_step_duration_secs: Some(10),
+            ..config()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
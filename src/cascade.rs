@@ -0,0 +1,313 @@
+use ndarray::{ArrayView1, Axis};
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::config::{CascadeConfig, CascadeStageConfig, ConfidenceConfig, ConfidenceMeasure};
+use crate::dispatch;
+use crate::error::Error;
+use crate::priority::Priority;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
+
+type Outputs = HashMap<String, Tensor>;
+
+/// Outputs of the stage of a cascade that answered
+#[derive(Debug)]
+pub struct CascadeAnswer<'a> {
+    pub stage: &'a CascadeStageConfig,
+    pub model_version: String,
+    pub outputs: Outputs,
+}
+
+/// `Cascade` tries its stages in order, usually from the cheapest model to the most
+/// expensive, and returns the outputs of the first stage confident enough to answer.
+///
+/// Each stage that answers counts towards `proton_cascade_answers_total` and each stage that
+/// escalates towards `proton_cascade_escalations_total`, so the hit rate of a stage is its
+/// share of answers. `proton_cascade_confidence` records how confident each stage was, for
+/// tuning the thresholds.
+///
+#[derive(Clone, Debug)]
+pub struct Cascade {
+    pub config: CascadeConfig,
+}
+
+impl Cascade {
+    pub fn new(config: CascadeConfig) -> Self {
+        Self { config }
+    }
+
+    pub async fn run(
+        &self,
+        shared_state: &SharedState,
+        prediction_id: Uuid,
+        inputs: Outputs,
+        deadline: Option<Instant>,
+        priority: Priority,
+    ) -> Result<CascadeAnswer<'_>, Error> {
+        let (last, escalating) = self.config.stages.split_last().unwrap();
+
+        for stage in escalating {
+            let (model_version, outputs) = run_stage(
+                shared_state,
+                prediction_id,
+                stage,
+                inputs.clone(),
+                deadline,
+                priority,
+            )
+            .await?;
+
+            let config = stage.confidence.as_ref().unwrap();
+            let confidence = self.confidence(config, &outputs)?;
+            let labels = self.labels(stage);
+            if let Some(confidence) = confidence {
+                metrics::histogram!("proton_cascade_confidence", confidence, &labels);
+            }
+
+            if confidence.is_some_and(|confidence| confidence >= config.threshold) {
+                metrics::increment_counter!("proton_cascade_answers_total", &labels);
+                return Ok(CascadeAnswer {
+                    stage,
+                    model_version,
+                    outputs,
+                });
+            }
+            metrics::increment_counter!("proton_cascade_escalations_total", &labels);
+            tracing::info!(
+                "cascade {} escalated prediction_id={:?} past stage {} with confidence {:?}",
+                &self.config.name,
+                prediction_id,
+                &stage.name,
+                confidence
+            );
+        }
+
+        let (model_version, outputs) = run_stage(
+            shared_state,
+            prediction_id,
+            last,
+            inputs,
+            deadline,
+            priority,
+        )
+        .await?;
+        metrics::increment_counter!("proton_cascade_answers_total", &self.labels(last));
+
+        Ok(CascadeAnswer {
+            stage: last,
+            model_version,
+            outputs,
+        })
+    }
+
+    /// Confidence of the least confident row of the output. Returns `None`, so the stage
+    /// escalates, when the output has no rows or a row's confidence isn't a number, e.g.
+    /// because the model produced NaN scores.
+    fn confidence(
+        &self,
+        config: &ConfidenceConfig,
+        outputs: &Outputs,
+    ) -> Result<Option<f64>, Error> {
+        let scores = outputs
+            .get(&config.output)
+            .and_then(Tensor::to_f64)
+            .ok_or_else(|| {
+                Error::Internal(format!(
+                    "cascade {} found no numeric output {}",
+                    &self.config.name, &config.output
+                ))
+            })?;
+        let axis = scores.ndim().checked_sub(1).map(Axis).ok_or_else(|| {
+            Error::Internal(format!(
+                "cascade {} can't measure the confidence of scalar output {}",
+                &self.config.name, &config.output
+            ))
+        })?;
+
+        let confidence = scores
+            .lanes(axis)
+            .into_iter()
+            .map(|row| {
+                Some(row_confidence(config, row)).filter(|confidence| confidence.is_finite())
+            })
+            .reduce(|a, b| Some(a?.min(b?)))
+            .flatten();
+        Ok(confidence)
+    }
+
+    fn labels(&self, stage: &CascadeStageConfig) -> [(&'static str, String); 2] {
+        [
+            ("cascade", self.config.name.clone()),
+            ("stage", stage.name.clone()),
+        ]
+    }
+}
+
+async fn run_stage(
+    shared_state: &SharedState,
+    prediction_id: Uuid,
+    stage: &CascadeStageConfig,
+    inputs: Outputs,
+    deadline: Option<Instant>,
+    priority: Priority,
+) -> Result<(String, Outputs), Error> {
+    let queue = shared_state
+        .models
+        .queue(&stage.model, stage.version.as_deref())?;
+    let outputs = dispatch::send(
+        &queue,
+        prediction_id,
+        &stage.model,
+        inputs,
+        deadline,
+        priority,
+    )
+    .await?;
+
+    Ok((queue.version, outputs))
+}
+
+fn row_confidence(config: &ConfidenceConfig, row: ArrayView1<f64>) -> f64 {
+    let mut probabilities = row.to_vec();
+    if config.softmax {
+        let max = probabilities
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        probabilities.iter_mut().for_each(|p| *p = (*p - max).exp());
+        let sum: f64 = probabilities.iter().sum();
+        probabilities.iter_mut().for_each(|p| *p /= sum);
+    }
+    probabilities.sort_by(|a, b| b.total_cmp(a));
+
+    match config.measure {
+        ConfidenceMeasure::MaxSoftmax => probabilities.first().copied().unwrap_or_default(),
+        ConfidenceMeasure::Margin => match probabilities[..] {
+            [first, second, ..] => first - second,
+            [first] => first,
+            [] => 0.0,
+        },
+        ConfidenceMeasure::Entropy => {
+            if probabilities.len() < 2 {
+                return 1.0;
+            }
+            let entropy: f64 = probabilities
+                .iter()
+                .filter(|&&p| p > 0.0)
+                .map(|p| -p * p.ln())
+                .sum();
+            1.0 - entropy / (probabilities.len() as f64).ln()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr1, arr2};
+
+    fn confidence(measure: ConfidenceMeasure, threshold: f64, softmax: bool) -> ConfidenceConfig {
+        ConfidenceConfig {
+            output: "scores".to_string(),
+            measure,
+            threshold,
+            softmax,
+        }
+    }
+
+    fn cascade() -> Cascade {
+        Cascade::new(CascadeConfig {
+            name: "classifier".to_string(),
+            stages: Vec::new(),
+        })
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn measures_scale_higher_for_more_confident_rows() {
+        let confident = arr1(&[0.9, 0.05, 0.05]);
+        let unsure = arr1(&[0.4, 0.35, 0.25]);
+
+        for measure in [
+            ConfidenceMeasure::MaxSoftmax,
+            ConfidenceMeasure::Margin,
+            ConfidenceMeasure::Entropy,
+        ] {
+            let config = confidence(measure, 0.5, false);
+            assert!(
+                row_confidence(&config, confident.view()) > row_confidence(&config, unsure.view()),
+                "{:?}",
+                measure
+            );
+        }
+    }
+
+    #[test]
+    fn measures_match_their_definitions() {
+        let row = arr1(&[0.7, 0.2, 0.1]);
+        let max = confidence(ConfidenceMeasure::MaxSoftmax, 0.5, false);
+        let margin = confidence(ConfidenceMeasure::Margin, 0.5, false);
+        let entropy = confidence(ConfidenceMeasure::Entropy, 0.5, false);
+
+        assert_close(row_confidence(&max, row.view()), 0.7);
+        assert_close(row_confidence(&margin, row.view()), 0.5);
+        // A uniform row is as unsure as it gets, a one-hot row as sure
+        assert_close(row_confidence(&entropy, arr1(&[0.5, 0.5]).view()), 0.0);
+        assert_close(row_confidence(&entropy, arr1(&[1.0, 0.0]).view()), 1.0);
+    }
+
+    #[test]
+    fn softmax_turns_logits_into_probabilities() {
+        let config = confidence(ConfidenceMeasure::MaxSoftmax, 0.5, true);
+        let logits = arr1(&[2.0_f64.ln(), 0.0]);
+        assert_close(row_confidence(&config, logits.view()), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn least_confident_row_decides() {
+        let config = confidence(ConfidenceMeasure::MaxSoftmax, 0.8, false);
+        let scores = arr2(&[[0.95_f32, 0.05], [0.6, 0.4]]).into_dyn();
+        let outputs = HashMap::from([("scores".to_string(), Tensor::Float32(scores))]);
+
+        let confidence = cascade().confidence(&config, &outputs).unwrap().unwrap();
+        assert!((confidence - 0.6).abs() < 1e-6);
+        // Below the threshold, so the cascade escalates
+        assert!(confidence < config.threshold);
+    }
+
+    #[test]
+    fn missing_or_scalar_scores_are_errors() {
+        let config = confidence(ConfidenceMeasure::MaxSoftmax, 0.8, false);
+        assert!(cascade().confidence(&config, &HashMap::new()).is_err());
+
+        let scalar = ndarray::ArrayD::from_elem(vec![], 0.9_f32);
+        let outputs = HashMap::from([("scores".to_string(), Tensor::Float32(scalar))]);
+        assert!(cascade().confidence(&config, &outputs).is_err());
+    }
+
+    #[test]
+    fn empty_or_non_finite_scores_escalate() {
+        let config = confidence(ConfidenceMeasure::MaxSoftmax, 0.5, false);
+        let confidence_of = |scores: ndarray::ArrayD<f64>| {
+            let outputs = HashMap::from([("scores".to_string(), Tensor::Float64(scores))]);
+            cascade().confidence(&config, &outputs).unwrap()
+        };
+
+        assert_eq!(confidence_of(ndarray::ArrayD::zeros(vec![0, 3])), None);
+        let nan = arr2(&[[0.9, 0.1], [f64::NAN, 0.2]]).into_dyn();
+        assert_eq!(confidence_of(nan), None);
+        let infinite = arr2(&[[f64::INFINITY, 0.1]]).into_dyn();
+        assert_eq!(confidence_of(infinite), None);
+        assert_eq!(confidence_of(arr2(&[[0.9, 0.1]]).into_dyn()), Some(0.9));
+    }
+}
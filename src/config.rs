@@ -1,33 +1,962 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::pipeline::Pipeline;
+
+/// Read at startup, and again when reloading routing rules
+pub const CONFIG_PATH: &str = "config.yaml";
+
+/// Graph optimizations applied by onnxruntime when creating a session
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizationLevel {
+    DisableAll,
+    #[default]
+    Basic,
+    Extended,
+    All,
+}
+
+/// onnxruntime session settings. Settings left unset on a model fall back to the
+/// server-wide defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SessionConfig {
+    /// Number of threads used to parallelize execution within operators
+    pub intra_op_threads: Option<i16>,
+    pub optimization_level: Option<OptimizationLevel>,
+    /// Let the CPU memory arena cache allocations across runs
+    pub memory_arena: Option<bool>,
+    /// Plan memory from the shapes seen in earlier runs, which helps models with fixed shapes
+    pub memory_pattern: Option<bool>,
+}
+
+impl SessionConfig {
+    /// Fill in any settings that aren't set with those from `defaults`
+    pub fn or(&self, defaults: &SessionConfig) -> SessionConfig {
+        SessionConfig {
+            intra_op_threads: self.intra_op_threads.or(defaults.intra_op_threads),
+            optimization_level: self.optimization_level.or(defaults.optimization_level),
+            memory_arena: self.memory_arena.or(defaults.memory_arena),
+            memory_pattern: self.memory_pattern.or(defaults.memory_pattern),
+        }
+    }
+}
+
+/// Dynamic batching settings for a model
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchingConfig {
+    /// Maximum number of items, summed over the first axis of each request, in a batch
+    pub max_batch_size: usize,
+    /// How long to wait for more requests after the first one arrives
+    pub max_queue_delay_ms: u64,
+}
+
+impl BatchingConfig {
+    /// Longest `max_queue_delay_ms` accepted. Batching trades milliseconds of latency for
+    /// throughput, so anything longer is almost certainly a typo.
+    const MAX_QUEUE_DELAY_MS: u64 = 10_000;
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_batch_size == 0 {
+            return Err("needs a max_batch_size of at least 1".to_string());
+        }
+        if self.max_queue_delay_ms > Self::MAX_QUEUE_DELAY_MS {
+            return Err(format!(
+                "can't have a max_queue_delay_ms above {}",
+                Self::MAX_QUEUE_DELAY_MS
+            ));
+        }
+        Ok(())
+    }
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ModelConfig {
     pub name: String,
+    /// Several versions can be loaded under the same name
+    #[serde(default = "default_version")]
+    pub version: String,
+    pub path: String,
+    /// Aliases, e.g. `production`, pointing at this version once it is loaded. The alias
+    /// `default` picks the version serving requests that don't name one.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Losslessly convert inputs sent with a compatible dtype, e.g. int32 to int64
+    #[serde(default)]
+    pub cast_inputs: bool,
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// Stack concurrent requests into a single run. Disabled when unset.
+    pub batching: Option<BatchingConfig>,
+    /// Number of workers, each with its own session, consuming from the model's queue
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+    /// Restart a worker with a fresh session after it has handled this many requests, to
+    /// contain memory growth. Workers run indefinitely when unset.
+    pub max_requests_per_worker: Option<usize>,
+    /// Deadline of requests that don't set one. Requests wait indefinitely when unset.
+    pub timeout_ms: Option<u64>,
+    /// What to do with requests when the model's queue is full
+    #[serde(default)]
+    pub overload: OverloadPolicy,
+    /// Admit only as many requests at once as the model keeps up with, see
+    /// [`ConcurrencyConfig`]
+    pub concurrency: Option<ConcurrencyConfig>,
+    /// How waiting requests are ordered by priority, see [`PriorityConfig`]
+    #[serde(default)]
+    pub priority: PriorityConfig,
+    /// Share of the shared thread pool the model gets, see [`SchedulingConfig`]
+    #[serde(default)]
+    pub scheduling: SchedulingConfig,
+    /// Roll out a candidate file for this model gradually, see [`CanaryConfig`]
+    pub canary: Option<CanaryConfig>,
+}
+
+/// A candidate file loaded next to the current version of a model. The candidate's share
+/// of traffic steps up while its error rate and p99 latency stay within bounds relative
+/// to the current version, and drops back to zero as soon as a bound is breached.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CanaryConfig {
     pub path: String,
+    /// Share of traffic sent to the candidate at each step, e.g. `[0.05, 0.25, 0.5, 1.0]`.
+    /// The candidate is promoted after passing the last step.
+    pub steps: Vec<f64>,
+    /// How long each step runs before it is judged
+    pub step_duration_secs: u64,
+    /// Requests the candidate must serve in a step before it can be judged. Steps are
+    /// extended until then.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    /// Roll back when a step still hasn't seen `min_requests` after this long. Defaults to
+    /// ten times `step_duration_secs`.
+    pub max_step_duration_secs: Option<u64>,
+    /// Highest allowed difference between the candidate's and the baseline's error rates
+    #[serde(default)]
+    pub max_error_rate_increase: f64,
+    /// Highest allowed ratio of the candidate's p99 latency to the baseline's
+    #[serde(default = "default_max_latency_ratio")]
+    pub max_latency_ratio: f64,
+}
+
+fn default_min_requests() -> u64 {
+    100
+}
+
+fn default_max_latency_ratio() -> f64 {
+    1.2
+}
+
+impl CanaryConfig {
+    pub fn max_step_duration_secs(&self) -> u64 {
+        self.max_step_duration_secs
+            .unwrap_or(self.step_duration_secs.saturating_mul(10))
+    }
+
+    /// Check the settings serde can't
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.is_empty() {
+            return Err("needs a canary path".to_string());
+        }
+        if self.steps.is_empty() {
+            return Err("needs at least one canary step".to_string());
+        }
+        if !self.steps.iter().all(|&step| step > 0.0 && step <= 1.0) {
+            return Err("needs canary steps between 0 and 1".to_string());
+        }
+        if self.step_duration_secs == 0 {
+            return Err("needs a positive canary step_duration_secs".to_string());
+        }
+        if self.max_step_duration_secs() < self.step_duration_secs {
+            return Err(
+                "can't have a canary max_step_duration_secs below step_duration_secs".to_string(),
+            );
+        }
+        if !(self.max_error_rate_increase.is_finite() && self.max_error_rate_increase >= 0.0) {
+            return Err("needs a non-negative canary max_error_rate_increase".to_string());
+        }
+        if !(self.max_latency_ratio.is_finite() && self.max_latency_ratio > 0.0) {
+            return Err("needs a positive canary max_latency_ratio".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl ModelConfig {
+    /// Check the settings serde can't
+    pub fn validate(&self) -> Result<(), String> {
+        if self.replicas == 0 {
+            return Err("needs at least one replica".to_string());
+        }
+        if let Some(batching) = self.batching.as_ref() {
+            batching.validate()?;
+        }
+        if let Some(concurrency) = self.concurrency.as_ref() {
+            concurrency.validate()?;
+        }
+        if !(self.scheduling.weight.is_finite() && self.scheduling.weight > 0.0) {
+            return Err("needs a positive scheduling weight".to_string());
+        }
+        if self.scheduling.min_threads > self.replicas {
+            return Err("can't have more min_threads than replicas".to_string());
+        }
+        if let Some(canary) = self.canary.as_ref() {
+            canary.validate()?;
+        }
+        Ok(())
+    }
+}
+
+fn default_version() -> String {
+    "1".to_string()
+}
+
+fn default_replicas() -> usize {
+    1
+}
+
+/// What happens to a request that finds its model's queue full, which holds
+/// `buffer_size` requests
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum OverloadPolicy {
+    /// Fail with `RESOURCE_EXHAUSTED` straight away
+    Reject,
+    /// Wait for room in the queue for up to `max_wait_ms`, or indefinitely when unset, then
+    /// fail with `RESOURCE_EXHAUSTED`
+    Wait { max_wait_ms: Option<u64> },
+    /// Make room by failing the oldest queued request with `RESOURCE_EXHAUSTED`, or fail the
+    /// new request if no room can be made
+    DropOldest,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Wait { max_wait_ms: None }
+    }
+}
+
+/// How a model competes for threads when models share a thread pool. Models with waiting
+/// requests get thread time in proportion to their `weight`, and a model running on fewer
+/// than `min_threads` threads gets the next free thread it is placed on before the others.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SchedulingConfig {
+    #[serde(default = "default_scheduling_weight")]
+    pub weight: f64,
+    #[serde(default)]
+    pub min_threads: usize,
+}
+
+impl Default for SchedulingConfig {
+    fn default() -> Self {
+        Self {
+            weight: default_scheduling_weight(),
+            min_threads: 0,
+        }
+    }
+}
+
+fn default_scheduling_weight() -> f64 {
+    1.0
+}
+
+/// How a model's queue orders requests of different priorities. Higher classes always go
+/// first, except for requests that have waited `promote_after_ms`, which go before anything
+/// else so lower classes aren't starved.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PriorityConfig {
+    /// Within a class, serve the request with the earliest deadline first rather than the
+    /// oldest
+    #[serde(default)]
+    pub earliest_deadline_first: bool,
+    #[serde(default = "default_promote_after_ms")]
+    pub promote_after_ms: u64,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            earliest_deadline_first: false,
+            promote_after_ms: default_promote_after_ms(),
+        }
+    }
+}
+
+fn default_promote_after_ms() -> u64 {
+    1000
+}
+
+/// How a concurrency limiter adjusts its limit from the latency of each request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum LimitAlgorithm {
+    /// Add one to the limit while requests finish within `latency_threshold_ms` and the
+    /// limit is in use, and multiply it by `backoff_ratio` when one doesn't
+    Aimd {
+        latency_threshold_ms: u64,
+        #[serde(default = "default_backoff_ratio")]
+        backoff_ratio: f64,
+    },
+    /// Scale the limit by how far latency has drifted above its long-term average, leaving
+    /// headroom of the square root of the limit. `smoothing` weighs each new limit against
+    /// the current one.
+    Gradient {
+        #[serde(default = "default_smoothing")]
+        smoothing: f64,
+    },
+}
+
+fn default_backoff_ratio() -> f64 {
+    0.9
+}
+
+fn default_smoothing() -> f64 {
+    0.2
+}
+
+/// Limits the requests a model has in flight, queued or running, adapting the limit to
+/// the latency the model is delivering. Requests over the limit fail with
+/// `RESOURCE_EXHAUSTED` before they reach the queue.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConcurrencyConfig {
+    #[serde(flatten)]
+    pub algorithm: LimitAlgorithm,
+    #[serde(default = "default_initial_limit")]
+    pub initial_limit: usize,
+    #[serde(default = "default_min_limit")]
+    pub min_limit: usize,
+    #[serde(default = "default_max_limit")]
+    pub max_limit: usize,
+}
+
+impl ConcurrencyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_limit == 0 || self.min_limit > self.max_limit {
+            return Err("concurrency limits need 1 <= min_limit <= max_limit".to_string());
+        }
+        match self.algorithm {
+            LimitAlgorithm::Aimd { backoff_ratio, .. }
+                if !(backoff_ratio > 0.0 && backoff_ratio < 1.0) =>
+            {
+                Err("backoff_ratio must be between 0 and 1".to_string())
+            }
+            LimitAlgorithm::Gradient { smoothing } if !(smoothing > 0.0 && smoothing <= 1.0) => {
+                Err("smoothing must be between 0 and 1".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_initial_limit() -> usize {
+    10
+}
+
+fn default_min_limit() -> usize {
+    1
+}
+
+fn default_max_limit() -> usize {
+    200
+}
+
+impl OverloadPolicy {
+    /// Name used in metric labels
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverloadPolicy::Reject => "reject",
+            OverloadPolicy::Wait { .. } => "wait",
+            OverloadPolicy::DropOldest => "drop_oldest",
+        }
+    }
+}
+
+/// One of the models or versions a traffic split sends requests to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VariantConfig {
+    /// Reported in responses and metrics, e.g. `control` or `treatment`
+    pub name: String,
+    pub model: String,
+    /// A version or alias of the model. Uses the default version when unset.
+    pub version: Option<String>,
+    /// Share of requests relative to the other variants
+    pub weight: u32,
+}
+
+/// Splits requests for a logical model name across several models or versions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SplitConfig {
+    pub name: String,
+    pub variants: Vec<VariantConfig>,
+}
+
+/// How a bandit picks an arm for each request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BanditPolicy {
+    /// Explore a random arm with probability `epsilon`, otherwise pick the arm with the
+    /// highest mean reward
+    EpsilonGreedy { epsilon: f64 },
+    /// Sample each arm's mean reward from a Beta posterior and pick the highest sample.
+    /// Rewards must be between 0 and 1.
+    ThompsonSampling,
+}
+
+/// A model or version a bandit routes to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArmConfig {
+    /// Reported in responses and metrics, and used to key the saved statistics
+    pub name: String,
+    pub model: String,
+    /// A version or alias of the model. Uses the default version when unset.
+    pub version: Option<String>,
+}
+
+/// Routes requests for a logical model name between arms, learning from rewards that
+/// clients post back against the `prediction_id` of each response
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BanditConfig {
+    pub name: String,
+    pub policy: BanditPolicy,
+    pub arms: Vec<ArmConfig>,
+    /// File the arm statistics are saved to after each reward and loaded from at startup
+    pub state_path: Option<String>,
+    /// How long after a prediction a reward is accepted for it
+    #[serde(default = "default_feedback_ttl_secs")]
+    pub feedback_ttl_secs: u64,
+    /// Most predictions kept waiting for a reward. The oldest are forgotten beyond this.
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+}
+
+fn default_feedback_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_max_pending() -> usize {
+    100_000
+}
+
+impl BanditConfig {
+    /// Check the settings serde can't
+    pub fn validate(&self) -> Result<(), String> {
+        if self.arms.is_empty() {
+            return Err("has no arms".to_string());
+        }
+        if let BanditPolicy::EpsilonGreedy { epsilon } = self.policy {
+            if !(0.0..=1.0).contains(&epsilon) {
+                return Err("needs an epsilon between 0 and 1".to_string());
+            }
+        }
+        if self.max_pending == 0 {
+            return Err("needs a positive max_pending".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Predictions submitted through `/jobs`, which run in the background while clients poll
+/// for the result
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobsConfig {
+    /// How long a finished job is kept before it is forgotten
+    #[serde(default = "default_job_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Most jobs pending at once. Submitting more fails with `RESOURCE_EXHAUSTED`.
+    #[serde(default = "default_max_pending_jobs")]
+    pub max_pending: usize,
+    /// Directory jobs are saved to, a file each, whenever one changes and loaded from at
+    /// startup
+    pub store_dir: Option<String>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: default_job_ttl_secs(),
+            max_pending: default_max_pending_jobs(),
+            store_dir: None,
+        }
+    }
+}
+
+impl JobsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ttl_secs == 0 {
+            return Err("needs a positive ttl_secs".to_string());
+        }
+        if self.max_pending == 0 {
+            return Err("needs a max_pending of at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn default_job_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_max_pending_jobs() -> usize {
+    1000
+}
+
+/// A model run as one step of a pipeline
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepConfig {
+    /// Later steps refer to this step's outputs as `<name>.<output>`
+    pub name: String,
+    pub model: String,
+    /// A version or alias of the model. Uses the default version when unset.
+    pub version: Option<String>,
+    /// Where each input of the model comes from: `input.<name>` for an input of the
+    /// pipeline request, or `<step>.<output>` for an output of another step
+    pub inputs: HashMap<String, String>,
+}
+
+/// Runs other models as the steps of a DAG, wiring outputs of some steps into the inputs of
+/// others, and serves the result under its own name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    pub name: String,
+    /// Steps can be listed in any order. Steps that don't depend on each other run
+    /// concurrently.
+    pub steps: Vec<StepConfig>,
+    /// Where each output of the pipeline comes from, as `<step>.<output>`
+    pub outputs: HashMap<String, String>,
+}
+
+/// How an ensemble combines the outputs of its members
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Mean,
+    /// Mean weighted by the `weight` of each member
+    WeightedMean,
+    /// Each member votes, with its `weight`, for the highest scoring class along the last
+    /// axis. Returns the share of the votes each class got.
+    MajorityVote,
+    /// Element-wise maximum
+    Max,
+}
+
+/// A model or version an ensemble sends requests to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberConfig {
+    pub model: String,
+    /// A version or alias of the model. Uses the default version when unset.
+    pub version: Option<String>,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// Sends each request to every member in parallel and combines their outputs, serving the
+/// result under its own name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnsembleConfig {
+    pub name: String,
+    pub members: Vec<MemberConfig>,
+    pub aggregation: Aggregation,
+}
+
+/// How a cascade measures the confidence of a stage in each row of an output. Every
+/// measure is scaled so that higher means more confident.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfidenceMeasure {
+    /// Highest probability
+    MaxSoftmax,
+    /// Highest probability minus the second highest
+    Margin,
+    /// One minus the entropy of the probabilities, normalized by the highest possible
+    /// entropy
+    Entropy,
+}
+
+/// When a stage of a cascade is confident enough to answer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConfidenceConfig {
+    /// Output holding the class scores, along its last axis
+    pub output: String,
+    pub measure: ConfidenceMeasure,
+    /// The stage answers when every row of the output is at least this confident
+    pub threshold: f64,
+    /// Apply softmax to the scores first, for models returning logits
+    #[serde(default)]
+    pub softmax: bool,
+}
+
+/// A model or version tried by a cascade
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CascadeStageConfig {
+    /// Reported in responses and metrics
+    pub name: String,
+    pub model: String,
+    /// A version or alias of the model. Uses the default version when unset.
+    pub version: Option<String>,
+    /// Required for every stage but the last, which always answers
+    pub confidence: Option<ConfidenceConfig>,
+}
+
+/// Tries cheap models first and escalates to the next stage only when a stage isn't
+/// confident, serving the result under its own name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CascadeConfig {
+    pub name: String,
+    pub stages: Vec<CascadeStageConfig>,
+}
+
+/// Bounds on the number of elements in an input tensor
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ShapeCondition {
+    pub min_elements: Option<usize>,
+    pub max_elements: Option<usize>,
+}
+
+/// Request properties a routing rule matches on. A rule matches when all of them do.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RuleConditions {
+    /// HTTP headers or gRPC metadata, with names matched case-insensitively
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Shapes of named inputs. The inputs must be present.
+    #[serde(default)]
+    pub inputs: HashMap<String, ShapeCondition>,
+}
+
+/// Where a routing rule sends the requests it matches
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleTarget {
+    /// A model or the name of a traffic split, bandit, pipeline, ensemble or cascade
+    pub model: String,
+    /// A version or alias of the model. Uses the default version when unset.
+    pub version: Option<String>,
+}
+
+/// Sends requests for a model name to another target when they match the conditions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleConfig {
+    /// Reported in responses and metrics
+    pub name: String,
+    /// Requested model name the rule applies to
+    pub model: String,
+    #[serde(default)]
+    pub when: RuleConditions,
+    pub route_to: RuleTarget,
+}
+
+impl RuleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.model.is_empty() || self.route_to.model.is_empty() {
+            return Err("needs a model and a model to route to".to_string());
+        }
+        for (input, condition) in self.when.inputs.iter() {
+            if let (Some(min), Some(max)) = (condition.min_elements, condition.max_elements) {
+                if min > max {
+                    return Err(format!(
+                        "can't have min_elements above max_elements for input {}",
+                        input
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The part of the config file `Config::load_rules` reads
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+/// Mirrors a fraction of the requests for a model to a shadow model
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShadowConfig {
+    /// Model whose requests are mirrored
+    pub model: String,
+    /// Model receiving the copies
+    pub shadow_model: String,
+    /// A version or alias of the shadow model. Uses the default version when unset.
+    pub shadow_version: Option<String>,
+    /// Fraction of requests to mirror, between 0 and 1
+    pub fraction: f64,
+}
+
+/// How model replicas are mapped onto inference threads
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionMode {
+    /// Every replica of every model runs on a thread of its own
+    #[default]
+    Dedicated,
+    /// A fixed pool of `num_threads` threads runs every model
+    SharedPool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ServerConfig {
+    /// Default number of intra-op threads for models that don't set `intra_op_threads`, or
+    /// the size of the thread pool in `shared_pool` mode
     pub num_threads: i16,
     pub buffer_size: usize,
     pub port: u16,
     pub grpc_port: u16,
+    /// Session settings shared by every model
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub execution: ExecutionMode,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub batch_requests: BatchRequestsConfig,
+}
+
+/// Limits on batch prediction requests, which send many items in one call
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchRequestsConfig {
+    /// Batches with more items fail with `INVALID_ARGUMENT`
+    #[serde(default = "default_max_items")]
+    pub max_items: usize,
+    /// Items of a batch running at once
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+}
+
+impl BatchRequestsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_items == 0 || self.max_concurrency == 0 {
+            return Err("max_items and max_concurrency must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for BatchRequestsConfig {
+    fn default() -> Self {
+        Self {
+            max_items: default_max_items(),
+            max_concurrency: default_max_concurrency(),
+        }
+    }
+}
+
+fn default_max_items() -> usize {
+    1000
+}
+
+fn default_max_concurrency() -> usize {
+    64
+}
+
+/// Access to the admin endpoints of both servers
+#[derive(Clone, Debug, Deserialize)]
+pub struct AdminConfig {
+    /// Bearer token admin requests must send in their `authorization` header. The admin
+    /// endpoints are disabled when unset.
+    pub token: Option<String>,
+    /// Models loaded or reloaded through the admin endpoints must live in this directory.
+    /// Relative paths are resolved against it.
+    #[serde(default = "default_model_dir")]
+    pub model_dir: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            model_dir: default_model_dir(),
+        }
+    }
+}
+
+fn default_model_dir() -> String {
+    ".".to_string()
+}
+
+impl ServerConfig {
+    /// Resolve the session settings for a model against the server-wide defaults
+    pub fn session_config(&self, model_config: &ModelConfig) -> SessionConfig {
+        let mut session = model_config.session.or(&self.session);
+        // Pool threads already run models in parallel, so their sessions default to one
+        // thread each
+        let intra_op_threads = match self.execution {
+            ExecutionMode::Dedicated => self.num_threads,
+            ExecutionMode::SharedPool => 1,
+        };
+        session.intra_op_threads = session.intra_op_threads.or(Some(intra_op_threads));
+        session
+    }
+
+    /// Number of threads in the shared pool
+    pub fn pool_size(&self) -> usize {
+        self.num_threads.max(1) as usize
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub log_level: String,
     pub models: Vec<ModelConfig>,
+    /// Traffic splits, taking precedence over models with the same name
+    #[serde(default)]
+    pub splits: Vec<SplitConfig>,
+    /// Shadow models receiving copies of live requests, at most one per model
+    #[serde(default)]
+    pub shadows: Vec<ShadowConfig>,
+    /// Bandits, taking precedence over models with the same name
+    #[serde(default)]
+    pub bandits: Vec<BanditConfig>,
+    /// Pipelines, taking precedence over models with the same name
+    #[serde(default)]
+    pub pipelines: Vec<PipelineConfig>,
+    /// Ensembles, taking precedence over models with the same name
+    #[serde(default)]
+    pub ensembles: Vec<EnsembleConfig>,
+    /// Cascades, taking precedence over models with the same name
+    #[serde(default)]
+    pub cascades: Vec<CascadeConfig>,
+    /// Routing rules, tried in order before any other routing
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    #[serde(default)]
+    pub jobs: JobsConfig,
     pub server: ServerConfig,
 }
 
 impl Config {
     pub fn load(path: &str) -> Result<Config, Box<dyn Error>> {
         let config_data = read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&config_data)?;
+        let mut config: Config = serde_yaml::from_str(&config_data)?;
+        config
+            .server
+            .batch_requests
+            .validate()
+            .map_err(|err| format!("batch_requests {}", err))?;
+        config
+            .jobs
+            .validate()
+            .map_err(|err| format!("jobs {}", err))?;
+
+        for model_config in config.models.iter_mut() {
+            model_config.session = config.server.session_config(model_config);
+            model_config
+                .validate()
+                .map_err(|err| format!("model {} {}", model_config.name, err))?;
+            if let Some(canary) = model_config.canary.as_ref() {
+                if !Path::new(&canary.path).is_file() {
+                    return Err(format!(
+                        "model {} canary path {} isn't a file",
+                        model_config.name, canary.path
+                    )
+                    .into());
+                }
+            }
+        }
+
+        let min_threads: usize = config
+            .models
+            .iter()
+            .map(|model| model.scheduling.min_threads)
+            .sum();
+        if config.server.execution == ExecutionMode::SharedPool
+            && min_threads > config.server.pool_size()
+        {
+            return Err(format!(
+                "models need {} min_threads but the pool has {} threads",
+                min_threads,
+                config.server.pool_size()
+            )
+            .into());
+        }
+
+        for bandit in config.bandits.iter() {
+            bandit
+                .validate()
+                .map_err(|err| format!("bandit {} {}", bandit.name, err))?;
+        }
+
+        for pipeline in config.pipelines.iter() {
+            Pipeline::new(pipeline.clone())
+                .map_err(|err| format!("pipeline {} {}", pipeline.name, err))?;
+        }
+
+        for ensemble in config.ensembles.iter() {
+            if ensemble.members.is_empty() {
+                return Err(format!("ensemble {} has no members", ensemble.name).into());
+            }
+            let weights = ensemble.members.iter().map(|member| member.weight);
+            if weights
+                .clone()
+                .any(|weight| !weight.is_finite() || weight < 0.0)
+                || weights.sum::<f64>() <= 0.0
+            {
+                return Err(
+                    format!("ensemble {} has invalid member weights", ensemble.name).into(),
+                );
+            }
+        }
+
+        for cascade in config.cascades.iter() {
+            let Some((_, escalating)) = cascade.stages.split_last() else {
+                return Err(format!("cascade {} has no stages", cascade.name).into());
+            };
+            if let Some(stage) = escalating.iter().find(|stage| stage.confidence.is_none()) {
+                return Err(format!(
+                    "stage {} of cascade {} needs a confidence threshold",
+                    stage.name, cascade.name
+                )
+                .into());
+            }
+        }
+
+        for shadow in config.shadows.iter() {
+            if !(0.0..=1.0).contains(&shadow.fraction) {
+                return Err(format!(
+                    "shadow of model {} needs a fraction between 0 and 1",
+                    shadow.model
+                )
+                .into());
+            }
+        }
+
+        for split in config.splits.iter() {
+            if split.variants.iter().all(|variant| variant.weight == 0) {
+                return Err(
+                    format!("traffic split {} has no weighted variants", split.name).into(),
+                );
+            }
+        }
+
+        validate_rules(&config.rules)?;
 
         Ok(config)
     }
+
+    /// Read only the routing rules from the config file, so rules can be reloaded without
+    /// the rest of the file having to be valid for a fresh start
+    pub fn load_rules(path: &str) -> Result<Vec<RuleConfig>, Box<dyn Error>> {
+        let config_data = read_to_string(path)?;
+        let file: RulesFile = serde_yaml::from_str(&config_data)?;
+        validate_rules(&file.rules)?;
+        Ok(file.rules)
+    }
+}
+
+fn validate_rules(rules: &[RuleConfig]) -> Result<(), String> {
+    for rule in rules.iter() {
+        rule.validate()
+            .map_err(|err| format!("rule {} {}", rule.name, err))?;
+    }
+    Ok(())
 }
@@ -0,0 +1,641 @@
+use futures::{stream, Future, StreamExt};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::config::{BatchRequestsConfig, OverloadPolicy};
+use crate::ensemble::{Disagreement, MemberReport};
+use crate::error::Error;
+use crate::pipeline::StepReport;
+use crate::priority::Priority;
+use crate::registry::Queue;
+use crate::shadow;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
+use crate::worker::Message;
+
+/// Times a request tries to make room in a full queue under `drop_oldest` before it is
+/// turned away itself
+const DROP_OLDEST_ATTEMPTS: usize = 3;
+
+/// Where the client wants a request to go
+#[derive(Clone, Debug, Default)]
+pub struct Target {
+    /// A model or the name of a traffic split, bandit, pipeline, ensemble or cascade
+    pub model_name: String,
+    /// A version or alias of the model. Requests naming a version bypass traffic splits and
+    /// bandits.
+    pub model_version: Option<String>,
+    /// Key for sticky assignment to a variant of a traffic split, e.g. a hash of the user ID
+    pub routing_key: Option<String>,
+    /// Return the outputs of every step of a pipeline, or of every member of an ensemble
+    /// along with how much they disagree
+    pub include_intermediate: bool,
+    /// Tenant the request is made for, which routing rules can match on
+    pub tenant: Option<String>,
+    /// Free-form labels routing rules can match on
+    pub labels: HashMap<String, String>,
+    /// HTTP headers or gRPC metadata with lowercase names, which routing rules can match on
+    pub headers: HashMap<String, String>,
+    /// Give up on the request once this has passed. Models with `timeout_ms` set impose
+    /// their own deadline as well.
+    pub deadline: Option<Instant>,
+    /// Class the request is queued in at each model it goes through
+    pub priority: Priority,
+}
+
+/// Outputs of a model along with the model and version that produced them
+#[derive(Debug)]
+pub struct Prediction {
+    pub model_name: String,
+    pub model_version: String,
+    /// Variant of the traffic split, arm of the bandit or stage of the cascade that served
+    /// the request
+    pub variant: Option<String>,
+    pub outputs: HashMap<String, Tensor>,
+    /// Steps run by a pipeline, empty for other models
+    pub steps: Vec<StepReport>,
+    /// Members of an ensemble, only returned when asking for intermediate outputs
+    pub members: Vec<MemberReport>,
+    /// Disagreement between the members of an ensemble on each output, only returned when
+    /// asking for intermediate outputs
+    pub disagreement: HashMap<String, Disagreement>,
+    /// Routing rule that sent the request to this model, if one matched
+    pub rule: Option<String>,
+}
+
+/// Route the request to a model, send the inputs to its queue and wait for a worker to
+/// respond. Requests without a version first go through the routing rules, then through the
+/// pipeline, ensemble, cascade, traffic split or bandit for the model name if there is one,
+/// and otherwise to the model's default version.
+pub async fn predict(
+    shared_state: &SharedState,
+    prediction_id: Uuid,
+    target: &Target,
+    inputs: HashMap<String, Tensor>,
+) -> Result<Prediction, Error> {
+    let rule = shared_state
+        .rules
+        .read()
+        .unwrap()
+        .matching(target, &inputs)
+        .cloned();
+    let Some(rule) = rule else {
+        return route(shared_state, prediction_id, target, inputs).await;
+    };
+
+    metrics::increment_counter!("proton_rule_matches_total", "rule" => rule.name.clone());
+    tracing::info!(
+        "rule {} sent prediction_id={:?} to model={}",
+        &rule.name,
+        prediction_id,
+        &rule.route_to.model
+    );
+    let target = Target {
+        model_name: rule.route_to.model,
+        model_version: rule.route_to.version,
+        ..target.clone()
+    };
+
+    let prediction = route(shared_state, prediction_id, &target, inputs).await?;
+    Ok(Prediction {
+        rule: Some(rule.name),
+        ..prediction
+    })
+}
+
+async fn route(
+    shared_state: &SharedState,
+    prediction_id: Uuid,
+    target: &Target,
+    inputs: HashMap<String, Tensor>,
+) -> Result<Prediction, Error> {
+    let pipeline = shared_state
+        .pipelines
+        .get(&target.model_name)
+        .filter(|_| target.model_version.is_none());
+    if let Some(pipeline) = pipeline {
+        let (outputs, steps) = pipeline
+            .run(
+                shared_state,
+                prediction_id,
+                inputs,
+                target.include_intermediate,
+                target.deadline,
+                target.priority,
+            )
+            .await?;
+
+        // Pipelines aren't versioned
+        return Ok(Prediction {
+            model_name: target.model_name.clone(),
+            model_version: String::new(),
+            variant: None,
+            outputs,
+            steps,
+            members: Vec::new(),
+            disagreement: HashMap::new(),
+            rule: None,
+        });
+    }
+
+    let ensemble = shared_state
+        .ensembles
+        .get(&target.model_name)
+        .filter(|_| target.model_version.is_none());
+    if let Some(ensemble) = ensemble {
+        let prediction = ensemble
+            .run(
+                shared_state,
+                prediction_id,
+                inputs,
+                target.deadline,
+                target.priority,
+            )
+            .await?;
+        let (members, disagreement) = match target.include_intermediate {
+            true => (prediction.members, prediction.disagreement),
+            false => (Vec::new(), HashMap::new()),
+        };
+
+        return Ok(Prediction {
+            model_name: target.model_name.clone(),
+            model_version: String::new(),
+            variant: None,
+            outputs: prediction.outputs,
+            steps: Vec::new(),
+            members,
+            disagreement,
+            rule: None,
+        });
+    }
+
+    let cascade = shared_state
+        .cascades
+        .get(&target.model_name)
+        .filter(|_| target.model_version.is_none());
+    if let Some(cascade) = cascade {
+        let answer = cascade
+            .run(
+                shared_state,
+                prediction_id,
+                inputs,
+                target.deadline,
+                target.priority,
+            )
+            .await?;
+
+        return Ok(Prediction {
+            model_name: answer.stage.model.clone(),
+            model_version: answer.model_version,
+            variant: Some(answer.stage.name.clone()),
+            outputs: answer.outputs,
+            steps: Vec::new(),
+            members: Vec::new(),
+            disagreement: HashMap::new(),
+            rule: None,
+        });
+    }
+
+    let split = shared_state
+        .splits
+        .get(&target.model_name)
+        .filter(|_| target.model_version.is_none());
+    let bandit = shared_state
+        .bandits
+        .get(&target.model_name)
+        .filter(|_| target.model_version.is_none() && split.is_none());
+    let arm = bandit.map(|bandit| bandit.choose());
+
+    let (model_name, model_version, variant) = match (split, bandit.zip(arm)) {
+        (Some(split), _) => {
+            let variant = split.choose(target.routing_key.as_deref());
+            metrics::increment_counter!(
+                "proton_split_requests_total",
+                "split" => split.config.name.clone(),
+                "variant" => variant.name.clone()
+            );
+            tracing::info!(
+                "split {} sent prediction_id={:?} to variant {}",
+                &split.config.name,
+                prediction_id,
+                &variant.name
+            );
+            (
+                variant.model.as_str(),
+                variant.version.as_deref(),
+                Some(variant.name.clone()),
+            )
+        }
+        (None, Some((bandit, arm))) => {
+            let arm = bandit.arm(arm);
+            tracing::info!(
+                "bandit {} sent prediction_id={:?} to arm {}",
+                &bandit.config.name,
+                prediction_id,
+                &arm.name
+            );
+            (
+                arm.model.as_str(),
+                arm.version.as_deref(),
+                Some(arm.name.clone()),
+            )
+        }
+        (None, None) => (
+            target.model_name.as_str(),
+            target.model_version.as_deref(),
+            None,
+        ),
+    };
+
+    // A running canary splits requests that don't name a version between the current
+    // version and the candidate
+    let canary = shared_state
+        .canaries
+        .read()
+        .unwrap()
+        .get(model_name)
+        .cloned()
+        .filter(|_| model_version.is_none());
+    let canary_version = canary
+        .as_ref()
+        .and_then(|canary| canary.choose(target.routing_key.as_deref()));
+    let model_version = canary_version.as_deref().or(model_version);
+
+    let queue = shared_state.models.queue(model_name, model_version)?;
+    let model_version = queue.version.clone();
+
+    let shadow = shadow::mirror(shared_state, prediction_id, model_name, &inputs);
+    let started = Instant::now();
+    let result = send(
+        &queue,
+        prediction_id,
+        model_name,
+        inputs,
+        target.deadline,
+        target.priority,
+    )
+    .await;
+    if let Some(shadow) = shadow {
+        shadow.compare(result.as_ref().ok(), started.elapsed());
+    }
+    if let (Some(canary), Some(_)) = (canary, canary_version) {
+        canary.record(&model_version, result.is_ok(), started.elapsed());
+    }
+    if let (Some(bandit), Some(arm), Ok(_)) = (bandit, arm, &result) {
+        bandit.record_pull(prediction_id, arm);
+    }
+
+    Ok(Prediction {
+        model_name: model_name.to_string(),
+        model_version,
+        variant,
+        outputs: result?,
+        steps: Vec::new(),
+        members: Vec::new(),
+        disagreement: HashMap::new(),
+        rule: None,
+    })
+}
+
+/// Run the items of a batch request, `max_concurrency` at a time, returning their results
+/// in the order of the items. Fails with `INVALID_ARGUMENT` when there are more than
+/// `max_items`.
+pub async fn run_batch<I, T, F, Fut>(
+    config: &BatchRequestsConfig,
+    items: Vec<I>,
+    mut run: F,
+) -> Result<Vec<T>, Error>
+where
+    F: FnMut(I) -> Fut,
+    Fut: Future<Output = T>,
+{
+    if items.len() > config.max_items {
+        return Err(Error::InvalidArgument(format!(
+            "batch has {} items, more than the limit of {}",
+            items.len(),
+            config.max_items
+        )));
+    }
+
+    let runs = items.into_iter().enumerate().map(|(index, item)| {
+        let run = run(item);
+        async move { (index, run.await) }
+    });
+    let mut results: Vec<(usize, T)> = stream::iter(runs)
+        .buffer_unordered(config.max_concurrency)
+        .collect()
+        .await;
+    results.sort_unstable_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Send the inputs to a model's queue and wait for a worker to respond, giving up once the
+/// deadline, or the model's own timeout, passes
+pub async fn send(
+    queue: &Queue,
+    prediction_id: Uuid,
+    model_name: &str,
+    inputs: HashMap<String, Tensor>,
+    deadline: Option<Instant>,
+    priority: Priority,
+) -> Result<HashMap<String, Tensor>, Error> {
+    // A timeout too far out to represent is as good as none
+    let timeout = queue
+        .timeout
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let deadline = match (deadline, timeout) {
+        (Some(deadline), Some(timeout)) => Some(deadline.min(timeout)),
+        (deadline, timeout) => deadline.or(timeout),
+    };
+    let deadline_exceeded = || {
+        Error::DeadlineExceeded(format!(
+            "model {} didn't respond before the deadline",
+            model_name
+        ))
+    };
+    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+        return Err(deadline_exceeded());
+    }
+    let permit = match &queue.limiter {
+        Some(limiter) => Some(limiter.acquire().ok_or_else(|| {
+            let labels = [
+                ("model", model_name.to_string()),
+                ("version", queue.version.clone()),
+                ("policy", "concurrency_limit".to_string()),
+            ];
+            metrics::increment_counter!("proton_requests_rejected_total", &labels);
+            Error::ResourceExhausted(format!("model {} is at its concurrency limit", model_name))
+        })?),
+        None => None,
+    };
+
+    // Create a channel to receive the inference result
+    let (response_tx, response_rx) = oneshot::channel();
+
+    let message = Message {
+        prediction_id,
+        model_name: model_name.to_string(),
+        inputs,
+        deadline,
+        priority,
+        enqueued_at: Instant::now(),
+        response_tx,
+    };
+
+    let response = async {
+        enqueue(queue, model_name, message).await?;
+        record_depth(queue, model_name);
+
+        // The worker drops the sender without responding if it dies mid request
+        let response = response_rx.await.map_err(|_| {
+            Error::Unavailable(format!(
+                "worker for model {} stopped before responding",
+                model_name
+            ))
+        });
+        record_depth(queue, model_name);
+        response?
+    };
+
+    // Dropping the receiver on timeout lets the worker skip the request if it hasn't
+    // started on it yet
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), response)
+            .await
+            .unwrap_or_else(|_| Err(deadline_exceeded())),
+        None => response.await,
+    };
+    if let Some(permit) = permit {
+        let overloaded = matches!(
+            result,
+            Err(Error::DeadlineExceeded(_) | Error::ResourceExhausted(_))
+        );
+        permit.finish(overloaded);
+    }
+    result
+}
+
+/// Put a message on the model's queue, following the model's overload policy if the queue
+/// is full
+async fn enqueue(queue: &Queue, model_name: &str, message: Message) -> Result<(), Error> {
+    let stopped = || Error::Unavailable(format!("workers for model {} have stopped", model_name));
+    let rejected = || {
+        let labels = [
+            ("model", model_name.to_string()),
+            ("version", queue.version.clone()),
+            ("policy", queue.overload.name().to_string()),
+        ];
+        metrics::increment_counter!("proton_requests_rejected_total", &labels);
+        Error::ResourceExhausted(format!("queue of model {} is full", model_name))
+    };
+
+    let mut message = match queue.try_send(message) {
+        Ok(()) => return Ok(()),
+        Err(TrySendError::Closed(_)) => return Err(stopped()),
+        Err(TrySendError::Full(message)) => message,
+    };
+
+    match queue.overload {
+        OverloadPolicy::Reject => Err(rejected()),
+        OverloadPolicy::Wait { max_wait_ms: None } => {
+            queue.send(message).await.map_err(|_| stopped())
+        }
+        OverloadPolicy::Wait {
+            max_wait_ms: Some(max_wait_ms),
+        } => {
+            let max_wait = Duration::from_millis(max_wait_ms);
+            match tokio::time::timeout(max_wait, queue.send(message)).await {
+                Ok(sent) => sent.map_err(|_| stopped()),
+                Err(_) => Err(rejected()),
+            }
+        }
+        OverloadPolicy::DropOldest => {
+            // The room made by dropping a request can be taken by another sender, and a
+            // worker collecting a batch keeps requests from being dropped, so try a few
+            // times before turning this one away instead
+            for _ in 0..DROP_OLDEST_ATTEMPTS {
+                match queue.pop_oldest() {
+                    Some(oldest) => {
+                        let _ = oldest.response_tx.send(Err(rejected()));
+                    }
+                    None => tokio::task::yield_now().await,
+                }
+                message = match queue.try_send(message) {
+                    Ok(()) => return Ok(()),
+                    Err(TrySendError::Closed(_)) => return Err(stopped()),
+                    Err(TrySendError::Full(message)) => message,
+                };
+            }
+            Err(rejected())
+        }
+    }
+}
+
+fn record_depth(queue: &Queue, model_name: &str) {
+    metrics::gauge!(
+        "proton_queue_depth",
+        queue.depth() as f64,
+        "model" => model_name.to_string(),
+        "version" => queue.version.clone()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModelConfig, ServerConfig};
+    use crate::registry::ModelRegistry;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A registry serving a model that can't load, so its queue is never drained
+    fn stalled(overload: &str) -> ModelRegistry {
+        let server: ServerConfig =
+            serde_yaml::from_str("{num_threads: 1, buffer_size: 2, port: 0, grpc_port: 0}")
+                .unwrap();
+        let model: ModelConfig = serde_yaml::from_str(&format!(
+            "{{name: stalled, path: missing.onnx, overload: {}}}",
+            overload
+        ))
+        .unwrap();
+        let registry = ModelRegistry::new(server);
+        registry.start(model).unwrap();
+        registry
+    }
+
+    type Response = oneshot::Receiver<Result<HashMap<String, Tensor>, Error>>;
+
+    fn message() -> (Message, Response) {
+        let (response_tx, response_rx) = oneshot::channel();
+        let message = Message {
+            prediction_id: Uuid::new_v4(),
+            model_name: "stalled".to_string(),
+            inputs: HashMap::new(),
+            deadline: None,
+            priority: Priority::Normal,
+            enqueued_at: Instant::now(),
+            response_tx,
+        };
+        (message, response_rx)
+    }
+
+    #[tokio::test]
+    async fn queue_holds_buffer_size_requests() {
+        let registry = stalled("{policy: reject}");
+        let queue = registry.queue("stalled", None).unwrap();
+        for _ in 0..2 {
+            enqueue(&queue, "stalled", message().0).await.unwrap();
+        }
+        // Requests the priority queue has taken off the channel still count
+        let oldest = queue.pop_oldest().unwrap();
+        queue.try_send(oldest).unwrap();
+
+        let err = enqueue(&queue, "stalled", message().0).await.unwrap_err();
+        assert!(matches!(err, Error::ResourceExhausted(_)), "{:?}", err);
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_fails_the_oldest_request() {
+        let registry = stalled("{policy: drop_oldest}");
+        let queue = registry.queue("stalled", None).unwrap();
+        let (first, mut first_rx) = message();
+        enqueue(&queue, "stalled", first).await.unwrap();
+        let (second, mut second_rx) = message();
+        enqueue(&queue, "stalled", second).await.unwrap();
+
+        let (third, mut third_rx) = message();
+        enqueue(&queue, "stalled", third).await.unwrap();
+        let err = first_rx.try_recv().unwrap().unwrap_err();
+        assert!(matches!(err, Error::ResourceExhausted(_)), "{:?}", err);
+        assert!(second_rx.try_recv().is_err());
+        assert!(third_rx.try_recv().is_err());
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[tokio::test]
+    async fn requests_dropped_by_a_crashed_worker_are_unavailable() {
+        let registry = stalled("{policy: reject}");
+        let queue = registry.queue("stalled", None).unwrap();
+        let request = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let id = Uuid::new_v4();
+                send(
+                    &queue,
+                    id,
+                    "stalled",
+                    HashMap::new(),
+                    None,
+                    Priority::Normal,
+                )
+                .await
+            }
+        });
+
+        // Take the request as a worker would and drop it, as a panic does
+        let message = loop {
+            match queue.pop_oldest() {
+                Some(message) => break message,
+                None => tokio::task::yield_now().await,
+            }
+        };
+        drop(message);
+
+        let err = request.await.unwrap().unwrap_err();
+        assert!(matches!(err, Error::Unavailable(_)), "{:?}", err);
+    }
+
+    fn batch_config(max_items: usize, max_concurrency: usize) -> BatchRequestsConfig {
+        BatchRequestsConfig {
+            max_items,
+            max_concurrency,
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_results_keep_the_order_of_the_items() {
+        let config = batch_config(10, 4);
+        let items: Vec<u64> = (0..10).collect();
+        // Later items finish first
+        let results = run_batch(&config, items, |item| async move {
+            tokio::time::sleep(Duration::from_millis(20 - 2 * item)).await;
+            item
+        })
+        .await
+        .unwrap();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn batch_runs_max_concurrency_items_at_once() {
+        let config = batch_config(20, 3);
+        let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        run_batch(&config, vec![(); 20], |_| async {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+        })
+        .await
+        .unwrap();
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn batch_over_max_items_is_invalid() {
+        let config = batch_config(2, 1);
+        let err = run_batch(&config, vec![1, 2, 3], |item| async move { item })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+        assert_eq!(
+            run_batch(&config, vec![1, 2], |item| async move { item })
+                .await
+                .unwrap(),
+            [1, 2]
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use futures::future;
+use ndarray::{ArrayD, Axis};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::config::{Aggregation, EnsembleConfig, MemberConfig};
+use crate::dispatch;
+use crate::error::Error;
+use crate::priority::Priority;
+use crate::shadow::argmax;
+use crate::state::SharedState;
+use crate::tensor::{DataType, Tensor};
+
+type Outputs = HashMap<String, Tensor>;
+
+/// Outputs of one member of an ensemble
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemberReport {
+    pub model_name: String,
+    pub model_version: String,
+    pub duration_seconds: f64,
+    pub outputs: Outputs,
+}
+
+/// How much the members of an ensemble disagree on an output
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Disagreement {
+    /// Variance across members, averaged over the elements of the output
+    pub variance: f64,
+    /// Share of the members voting for the most voted class along the last axis, averaged
+    /// over rows. Unset for scalar outputs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vote_agreement: Option<f64>,
+}
+
+/// Combined outputs of an ensemble along with what each member returned
+#[derive(Debug)]
+pub struct EnsemblePrediction {
+    pub outputs: Outputs,
+    pub members: Vec<MemberReport>,
+    pub disagreement: HashMap<String, Disagreement>,
+}
+
+/// `Ensemble` sends each request to all of its members at once, through their queues, and
+/// combines the outputs of the same name.
+///
+/// Outputs are combined as f64 and returned as float32 when every member returned float32,
+/// otherwise as float64. Every member has to return the same outputs with the same shapes.
+///
+#[derive(Clone, Debug)]
+pub struct Ensemble {
+    pub config: EnsembleConfig,
+}
+
+impl Ensemble {
+    pub fn new(config: EnsembleConfig) -> Self {
+        Self { config }
+    }
+
+    pub async fn run(
+        &self,
+        shared_state: &SharedState,
+        prediction_id: Uuid,
+        inputs: Outputs,
+        deadline: Option<Instant>,
+        priority: Priority,
+    ) -> Result<EnsemblePrediction, Error> {
+        let runs = self.config.members.iter().map(|member| {
+            run_member(
+                shared_state,
+                prediction_id,
+                member,
+                inputs.clone(),
+                deadline,
+                priority,
+            )
+        });
+        let members = future::try_join_all(runs).await?;
+
+        let mut outputs = HashMap::new();
+        let mut disagreement = HashMap::new();
+        for (name, tensor) in members[0].outputs.iter() {
+            let arrays = members
+                .iter()
+                .map(|member| self.array(member, name))
+                .collect::<Result<Vec<ArrayD<f64>>, Error>>()?;
+            if arrays.iter().any(|array| array.shape() != tensor.shape()) {
+                return Err(Error::Internal(format!(
+                    "members of ensemble {} returned output {} with different shapes",
+                    &self.config.name, name
+                )));
+            }
+
+            let combined = self.aggregate(&arrays).ok_or_else(|| {
+                Error::Internal(format!(
+                    "ensemble {} can't vote on scalar output {}",
+                    &self.config.name, name
+                ))
+            })?;
+            let all_float32 = members
+                .iter()
+                .all(|member| member.outputs[name].dtype() == DataType::Float32);
+            let combined = match all_float32 {
+                true => Tensor::Float32(combined.mapv(|value| value as f32)),
+                false => Tensor::Float64(combined),
+            };
+
+            let stats = disagreement_of(&arrays);
+            metrics::histogram!(
+                "proton_ensemble_variance",
+                stats.variance,
+                "ensemble" => self.config.name.clone(),
+                "output" => name.clone()
+            );
+            outputs.insert(name.clone(), combined);
+            disagreement.insert(name.clone(), stats);
+        }
+
+        Ok(EnsemblePrediction {
+            outputs,
+            members,
+            disagreement,
+        })
+    }
+
+    fn array(&self, member: &MemberReport, name: &str) -> Result<ArrayD<f64>, Error> {
+        let tensor = member.outputs.get(name).ok_or_else(|| {
+            Error::Internal(format!(
+                "member {} of ensemble {} returned no output {}",
+                &member.model_name, &self.config.name, name
+            ))
+        })?;
+
+        tensor.to_f64().ok_or_else(|| {
+            Error::Internal(format!(
+                "ensemble {} can't combine {} output {}",
+                &self.config.name,
+                tensor.dtype(),
+                name
+            ))
+        })
+    }
+
+    /// Combine the same output of every member. Returns `None` when voting on a scalar.
+    fn aggregate(&self, arrays: &[ArrayD<f64>]) -> Option<ArrayD<f64>> {
+        let members = &self.config.members;
+        let total_weight: f64 = members.iter().map(|member| member.weight).sum();
+        let mut combined = ArrayD::zeros(arrays[0].shape());
+
+        match self.config.aggregation {
+            Aggregation::Mean => {
+                for array in arrays {
+                    combined += array;
+                }
+                combined /= arrays.len() as f64;
+            }
+            Aggregation::WeightedMean => {
+                for (array, member) in arrays.iter().zip(members) {
+                    combined.scaled_add(member.weight / total_weight, array);
+                }
+            }
+            Aggregation::MajorityVote => {
+                let axis = Axis(combined.ndim().checked_sub(1)?);
+                for (array, member) in arrays.iter().zip(members) {
+                    let votes = combined.lanes_mut(axis).into_iter().zip(array.lanes(axis));
+                    for (mut votes, row) in votes {
+                        if let Some(class) = argmax(row) {
+                            votes[class] += member.weight / total_weight;
+                        }
+                    }
+                }
+            }
+            Aggregation::Max => {
+                combined.fill(f64::NEG_INFINITY);
+                for array in arrays {
+                    combined.zip_mut_with(array, |a, &b| *a = a.max(b));
+                }
+            }
+        }
+
+        Some(combined)
+    }
+}
+
+async fn run_member(
+    shared_state: &SharedState,
+    prediction_id: Uuid,
+    member: &MemberConfig,
+    inputs: Outputs,
+    deadline: Option<Instant>,
+    priority: Priority,
+) -> Result<MemberReport, Error> {
+    let queue = shared_state
+        .models
+        .queue(&member.model, member.version.as_deref())?;
+
+    let started = Instant::now();
+    let outputs = dispatch::send(
+        &queue,
+        prediction_id,
+        &member.model,
+        inputs,
+        deadline,
+        priority,
+    )
+    .await?;
+
+    Ok(MemberReport {
+        model_name: member.model.clone(),
+        model_version: queue.version,
+        duration_seconds: started.elapsed().as_secs_f64(),
+        outputs,
+    })
+}
+
+fn disagreement_of(arrays: &[ArrayD<f64>]) -> Disagreement {
+    let count = arrays.len() as f64;
+    let mut mean = ArrayD::zeros(arrays[0].shape());
+    for array in arrays {
+        mean += array;
+    }
+    mean /= count;
+
+    let mut variance = ArrayD::zeros(arrays[0].shape());
+    for array in arrays {
+        variance += &(array - &mean).mapv(|diff| diff * diff);
+    }
+    variance /= count;
+
+    Disagreement {
+        variance: variance.mean().unwrap_or_default(),
+        vote_agreement: vote_agreement(arrays),
+    }
+}
+
+fn vote_agreement(arrays: &[ArrayD<f64>]) -> Option<f64> {
+    let axis = Axis(arrays[0].ndim().checked_sub(1)?);
+    let votes: Vec<Vec<Option<usize>>> = arrays
+        .iter()
+        .map(|array| array.lanes(axis).into_iter().map(argmax).collect())
+        .collect();
+
+    let rows = votes[0].len();
+    let agreement: f64 = (0..rows)
+        .map(|row| {
+            let mut counts: HashMap<Option<usize>, usize> = HashMap::new();
+            for member in votes.iter() {
+                *counts.entry(member[row]).or_default() += 1;
+            }
+            counts.into_values().max().unwrap_or_default() as f64 / arrays.len() as f64
+        })
+        .sum();
+
+    (rows > 0).then(|| agreement / rows as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::arr2;
+
+    fn ensemble(aggregation: Aggregation, weights: &[f64]) -> Ensemble {
+        let members = weights
+            .iter()
+            .enumerate()
+            .map(|(index, &weight)| MemberConfig {
+                model: format!("member-{}", index),
+                version: None,
+                weight,
+            })
+            .collect();
+
+        Ensemble::new(EnsembleConfig {
+            name: "classifiers".to_string(),
+            members,
+            aggregation,
+        })
+    }
+
+    fn arrays() -> Vec<ArrayD<f64>> {
+        vec![
+            arr2(&[[0.8, 0.2], [0.4, 0.6]]).into_dyn(),
+            arr2(&[[0.6, 0.4], [0.2, 0.8]]).into_dyn(),
+            arr2(&[[0.1, 0.9], [0.3, 0.7]]).into_dyn(),
+        ]
+    }
+
+    fn assert_close(actual: &ArrayD<f64>, expected: ArrayD<f64>) {
+        assert_eq!(actual.shape(), expected.shape());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-9, "{} != {}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn mean_averages_members() {
+        let combined = ensemble(Aggregation::Mean, &[1.0, 1.0, 1.0])
+            .aggregate(&arrays())
+            .unwrap();
+        assert_close(&combined, arr2(&[[0.5, 0.5], [0.3, 0.7]]).into_dyn());
+    }
+
+    #[test]
+    fn weighted_mean_follows_the_weights() {
+        let combined = ensemble(Aggregation::WeightedMean, &[2.0, 1.0, 1.0])
+            .aggregate(&arrays())
+            .unwrap();
+        assert_close(
+            &combined,
+            arr2(&[[0.575, 0.425], [0.325, 0.675]]).into_dyn(),
+        );
+    }
+
+    #[test]
+    fn majority_vote_counts_weighted_votes_per_row() {
+        let combined = ensemble(Aggregation::MajorityVote, &[1.0, 1.0, 2.0])
+            .aggregate(&arrays())
+            .unwrap();
+        // The heavier third member outvotes the other two on the first row
+        assert_close(&combined, arr2(&[[0.5, 0.5], [0.0, 1.0]]).into_dyn());
+    }
+
+    #[test]
+    fn majority_vote_needs_a_class_axis() {
+        let scalars = vec![ArrayD::from_elem(vec![], 1.0); 2];
+        assert!(ensemble(Aggregation::MajorityVote, &[1.0, 1.0])
+            .aggregate(&scalars)
+            .is_none());
+    }
+
+    #[test]
+    fn max_takes_the_highest_score() {
+        let combined = ensemble(Aggregation::Max, &[1.0, 1.0, 1.0])
+            .aggregate(&arrays())
+            .unwrap();
+        assert_close(&combined, arr2(&[[0.8, 0.9], [0.4, 0.8]]).into_dyn());
+    }
+
+    #[test]
+    fn disagreement_reports_variance_and_vote_agreement() {
+        let agreeing = vec![arr2(&[[0.9, 0.1]]).into_dyn(); 3];
+        let stats = disagreement_of(&agreeing);
+        assert!(stats.variance < 1e-12);
+        assert_eq!(stats.vote_agreement, Some(1.0));
+
+        let stats = disagreement_of(&arrays());
+        assert!(stats.variance > 0.0);
+        // Two of three members agree on the first row and all three on the second
+        let expected = (2.0 / 3.0 + 1.0) / 2.0;
+        assert!((stats.vote_agreement.unwrap() - expected).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,243 @@
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tonic::{Code, Status};
+
+use crate::model::PredictError;
+
+/// Seconds clients are asked to wait before retrying when a queue is full
+const RETRY_AFTER_SECS: &str = "1";
+
+/// Errors surfaced to clients by both the REST and gRPC servers
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// No model is registered under the requested name
+    ModelNotFound(String),
+    /// A model is already registered under the requested name
+    AlreadyExists(String),
+    /// The request or one of its tensors is malformed or doesn't fit the model
+    InvalidArgument(String),
+    /// The model can't take requests right now, e.g. its worker has stopped
+    Unavailable(String),
+    /// The model's queue is full, so the client should back off and retry
+    ResourceExhausted(String),
+    /// The request lacks valid credentials for an admin endpoint
+    Unauthenticated(String),
+    /// The request's deadline passed before the model responded
+    DeadlineExceeded(String),
+    /// Inference failed for a reason that isn't the client's fault
+    Internal(String),
+}
+
+impl Error {
+    pub fn code(&self) -> Code {
+        match self {
+            Error::ModelNotFound(_) => Code::NotFound,
+            Error::AlreadyExists(_) => Code::AlreadyExists,
+            Error::InvalidArgument(_) => Code::InvalidArgument,
+            Error::Unavailable(_) => Code::Unavailable,
+            Error::ResourceExhausted(_) => Code::ResourceExhausted,
+            Error::Unauthenticated(_) => Code::Unauthenticated,
+            Error::DeadlineExceeded(_) => Code::DeadlineExceeded,
+            Error::Internal(_) => Code::Internal,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            Error::AlreadyExists(_) => StatusCode::CONFLICT,
+            Error::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+            Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::ResourceExhausted(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Error::DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Name of the gRPC status code, used as the error code in REST responses
+    pub fn code_name(&self) -> &'static str {
+        match self {
+            Error::ModelNotFound(_) => "NOT_FOUND",
+            Error::AlreadyExists(_) => "ALREADY_EXISTS",
+            Error::InvalidArgument(_) => "INVALID_ARGUMENT",
+            Error::Unavailable(_) => "UNAVAILABLE",
+            Error::ResourceExhausted(_) => "RESOURCE_EXHAUSTED",
+            Error::Unauthenticated(_) => "UNAUTHENTICATED",
+            Error::DeadlineExceeded(_) => "DEADLINE_EXCEEDED",
+            Error::Internal(_) => "INTERNAL",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Error::ModelNotFound(message)
+            | Error::AlreadyExists(message)
+            | Error::InvalidArgument(message)
+            | Error::Unavailable(message)
+            | Error::ResourceExhausted(message)
+            | Error::Unauthenticated(message)
+            | Error::DeadlineExceeded(message)
+            | Error::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<PredictError> for Error {
+    fn from(err: PredictError) -> Self {
+        match err {
+            PredictError::MissingInput(_)
+            | PredictError::UnexpectedInput(_)
+            | PredictError::DataType { .. }
+            | PredictError::Shape { .. } => Error::InvalidArgument(err.to_string()),
+            PredictError::Unsupported(_) | PredictError::Runtime(_) => {
+                Error::Internal(err.to_string())
+            }
+        }
+    }
+}
+
+/// JSON body returned by the REST server for every error
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// The gRPC status code name, e.g. `NOT_FOUND`, so both servers report the same codes
+    pub code: String,
+    pub message: String,
+}
+
+impl From<&Error> for ErrorBody {
+    fn from(err: &Error) -> Self {
+        Self {
+            code: err.code_name().to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: ErrorBody::from(&self),
+        };
+
+        let mut response = (self.status_code(), Json(body)).into_response();
+        if let Error::ResourceExhausted(_) = self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                header::HeaderValue::from_static(RETRY_AFTER_SECS),
+            );
+        }
+        response
+    }
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        Status::new(err.code(), err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn maps_every_variant_to_the_same_code_on_both_servers() {
+        let table = [
+            (
+                Error::ModelNotFound("m".into()),
+                StatusCode::NOT_FOUND,
+                Code::NotFound,
+                "NOT_FOUND",
+            ),
+            (
+                Error::AlreadyExists("m".into()),
+                StatusCode::CONFLICT,
+                Code::AlreadyExists,
+                "ALREADY_EXISTS",
+            ),
+            (
+                Error::InvalidArgument("m".into()),
+                StatusCode::BAD_REQUEST,
+                Code::InvalidArgument,
+                "INVALID_ARGUMENT",
+            ),
+            (
+                Error::Unavailable("m".into()),
+                StatusCode::SERVICE_UNAVAILABLE,
+                Code::Unavailable,
+                "UNAVAILABLE",
+            ),
+            (
+                Error::ResourceExhausted("m".into()),
+                StatusCode::TOO_MANY_REQUESTS,
+                Code::ResourceExhausted,
+                "RESOURCE_EXHAUSTED",
+            ),
+            (
+                Error::Unauthenticated("m".into()),
+                StatusCode::UNAUTHORIZED,
+                Code::Unauthenticated,
+                "UNAUTHENTICATED",
+            ),
+            (
+                Error::DeadlineExceeded("m".into()),
+                StatusCode::GATEWAY_TIMEOUT,
+                Code::DeadlineExceeded,
+                "DEADLINE_EXCEEDED",
+            ),
+            (
+                Error::Internal("m".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Code::Internal,
+                "INTERNAL",
+            ),
+        ];
+
+        for (err, status_code, code, code_name) in table {
+            let status = Status::from(err.clone());
+            assert_eq!(status.code(), code, "{}", code_name);
+            assert_eq!(status.message(), "m");
+
+            let response = err.into_response();
+            assert_eq!(response.status(), status_code, "{}", code_name);
+            let retry_after = response.headers().get(header::RETRY_AFTER);
+            match code {
+                Code::ResourceExhausted => assert_eq!(retry_after.unwrap(), RETRY_AFTER_SECS),
+                _ => assert!(retry_after.is_none(), "{}", code_name),
+            }
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                body,
+                serde_json::json!({"error": {"code": code_name, "message": "m"}})
+            );
+        }
+    }
+
+    #[test]
+    fn predict_errors_blame_the_client_only_for_bad_inputs() {
+        let err = Error::from(PredictError::MissingInput("data_0".into()));
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let err = Error::from(PredictError::Runtime("out of memory".into()));
+        assert_eq!(err.code(), Code::Internal);
+    }
+}
@@ -0,0 +1,493 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::{self, AbortHandle};
+use uuid::Uuid;
+
+use crate::config::JobsConfig;
+use crate::error::{Error, ErrorBody};
+use crate::routes::predict::InferenceResponse;
+
+/// How often finished jobs past their `ttl_secs` are forgotten
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// The prediction is queued or running
+    Pending,
+    Succeeded,
+    Failed,
+    /// The client cancelled the job before it finished
+    Cancelled,
+}
+
+impl JobStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// A prediction run in the background, with either `response` or `error` set once it
+/// succeeds or fails
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub prediction_id: Uuid,
+    pub status: JobStatus,
+    /// Seconds since the unix epoch
+    pub submitted_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<InferenceResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    /// Task running the prediction, while it is pending
+    task: Option<AbortHandle>,
+}
+
+/// A change to the saved jobs, applied in order by the writer task
+#[derive(Debug)]
+enum Write {
+    Save(Box<Job>),
+    Remove(Uuid),
+}
+
+/// `JobStore` keeps track of predictions submitted through `/jobs`.
+///
+/// Each job runs as its own task, the same way a `/predict` request would, and its result
+/// is kept for `ttl_secs` after it finishes. Cancelling a pending job aborts the task, so
+/// workers drop the request if it is still queued. With `store_dir` set, each job is saved
+/// to its own file whenever it changes, by a background task so requests don't wait on the
+/// disk, and loaded at startup. Jobs that were pending when the server stopped can't be
+/// resumed and are loaded as failed.
+///
+#[derive(Debug)]
+pub struct JobStore {
+    config: JobsConfig,
+    entries: Arc<Mutex<HashMap<Uuid, Entry>>>,
+    /// Changes to save, when `store_dir` is set
+    writes: Option<UnboundedSender<Write>>,
+}
+
+impl JobStore {
+    /// Must be called from within the tokio runtime, which runs the writer and expiry tasks
+    pub fn new(config: JobsConfig) -> Self {
+        let writes = config.store_dir.as_ref().map(|store_dir| {
+            let (writes_tx, writes_rx) = unbounded_channel();
+            tokio::spawn(write_jobs(PathBuf::from(store_dir), writes_rx));
+            writes_tx
+        });
+
+        let mut jobs = load_jobs(&config);
+        // Jobs that were still pending lost their task with the server, so they fail as if
+        // their model had become unavailable
+        for job in jobs.iter_mut() {
+            if job.status != JobStatus::Pending {
+                continue;
+            }
+            let err = Error::Unavailable("server restarted before the job finished".into());
+            job.status = JobStatus::Failed;
+            job.finished_at = Some(now());
+            job.error = Some(ErrorBody::from(&err));
+            if let Some(writes) = &writes {
+                let _ = writes.send(Write::Save(Box::new(job.clone())));
+            }
+        }
+        let entries = jobs
+            .into_iter()
+            .map(|job| (job.prediction_id, Entry { job, task: None }))
+            .collect();
+        let entries = Arc::new(Mutex::new(entries));
+        tokio::spawn(expire_jobs(
+            Arc::downgrade(&entries),
+            config.ttl_secs,
+            writes.clone(),
+        ));
+
+        Self {
+            config,
+            entries,
+            writes,
+        }
+    }
+
+    /// Record a pending job and spawn the task running it. The task must call `finish`
+    /// once the prediction is done. Fails with `RESOURCE_EXHAUSTED` if `max_pending` jobs
+    /// are pending already.
+    pub fn submit<F>(&self, prediction_id: Uuid, run: F) -> Result<Job, Error>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let job = Job {
+            prediction_id,
+            status: JobStatus::Pending,
+            submitted_at: now(),
+            finished_at: None,
+            response: None,
+            error: None,
+        };
+
+        // Spawned under the lock so the task can't finish before it is recorded
+        let mut entries = self.entries.lock().unwrap();
+        let pending = entries
+            .values()
+            .filter(|entry| entry.task.is_some())
+            .count();
+        if pending >= self.config.max_pending {
+            metrics::increment_counter!("proton_jobs_rejected_total");
+            return Err(Error::ResourceExhausted(format!(
+                "{} jobs are pending already",
+                pending
+            )));
+        }
+        let task = tokio::spawn(run).abort_handle();
+        entries.insert(
+            prediction_id,
+            Entry {
+                job: job.clone(),
+                task: Some(task),
+            },
+        );
+        metrics::increment_counter!("proton_jobs_submitted_total");
+
+        self.save(&job);
+        Ok(job)
+    }
+
+    /// Store the outcome of a job, unless it was cancelled meanwhile
+    pub fn finish(&self, prediction_id: &Uuid, result: Result<InferenceResponse, Error>) {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(prediction_id) else {
+            return;
+        };
+        if entry.job.status != JobStatus::Pending {
+            return;
+        }
+
+        entry.task = None;
+        entry.job.finished_at = Some(now());
+        match result {
+            Ok(response) => {
+                entry.job.status = JobStatus::Succeeded;
+                entry.job.response = Some(response);
+            }
+            Err(err) => {
+                entry.job.status = JobStatus::Failed;
+                entry.job.error = Some(ErrorBody::from(&err));
+            }
+        }
+        metrics::increment_counter!(
+            "proton_jobs_finished_total",
+            "status" => entry.job.status.name()
+        );
+
+        self.save(&entry.job);
+    }
+
+    pub fn get(&self, prediction_id: &Uuid) -> Result<Job, Error> {
+        let entries = self.entries.lock().unwrap();
+        self.live(&entries, prediction_id)
+            .map(|entry| entry.job.clone())
+            .ok_or_else(|| unknown(prediction_id))
+    }
+
+    /// Abort a pending job. Jobs that already finished are left as they are.
+    pub fn cancel(&self, prediction_id: &Uuid) -> Result<Job, Error> {
+        let mut entries = self.entries.lock().unwrap();
+        self.live(&entries, prediction_id)
+            .ok_or_else(|| unknown(prediction_id))?;
+
+        let entry = entries.get_mut(prediction_id).unwrap();
+        if entry.job.status != JobStatus::Pending {
+            return Ok(entry.job.clone());
+        }
+
+        if let Some(task) = entry.task.take() {
+            task.abort();
+        }
+        entry.job.status = JobStatus::Cancelled;
+        entry.job.finished_at = Some(now());
+        metrics::increment_counter!(
+            "proton_jobs_finished_total",
+            "status" => entry.job.status.name()
+        );
+        let job = entry.job.clone();
+
+        self.save(&job);
+        Ok(job)
+    }
+
+    /// A job that hasn't expired, even if the expiry task hasn't got to it yet
+    fn live<'a>(
+        &self,
+        entries: &'a HashMap<Uuid, Entry>,
+        prediction_id: &Uuid,
+    ) -> Option<&'a Entry> {
+        let now = now();
+        entries
+            .get(prediction_id)
+            .filter(|entry| !expired(&entry.job, self.config.ttl_secs, now))
+    }
+
+    /// Queue the job to be saved. Called under the lock so writes are queued in the order
+    /// the job changed.
+    fn save(&self, job: &Job) {
+        if let Some(writes) = &self.writes {
+            let _ = writes.send(Write::Save(Box::new(job.clone())));
+        }
+    }
+}
+
+impl Write {
+    /// Apply the change to the job's file, writing through a temporary file so a crash mid
+    /// write doesn't lose the previous state
+    fn apply(&self, store_dir: &Path) -> io::Result<()> {
+        match self {
+            Write::Save(job) => {
+                let path = job_path(store_dir, &job.prediction_id);
+                let tmp_path = path.with_extension("json.tmp");
+                fs::write(&tmp_path, serde_json::to_vec(job)?)?;
+                fs::rename(&tmp_path, &path)
+            }
+            Write::Remove(prediction_id) => {
+                match fs::remove_file(job_path(store_dir, prediction_id)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+fn job_path(store_dir: &Path, prediction_id: &Uuid) -> PathBuf {
+    store_dir.join(format!("{}.json", prediction_id))
+}
+
+/// Apply changes to the saved jobs one at a time, off the runtime's threads
+async fn write_jobs(store_dir: PathBuf, mut writes: UnboundedReceiver<Write>) {
+    if let Err(err) = fs::create_dir_all(&store_dir) {
+        tracing::error!("failed to create {:?}: {}", store_dir, err);
+    }
+    while let Some(write) = writes.recv().await {
+        let store_dir = store_dir.clone();
+        let written = task::spawn_blocking(move || write.apply(&store_dir)).await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("failed to save job: {}", err),
+            Err(err) => tracing::error!("failed to save job: {}", err),
+        }
+    }
+}
+
+/// Forget jobs that finished more than `ttl_secs` ago, until the store is dropped
+async fn expire_jobs(
+    entries: Weak<Mutex<HashMap<Uuid, Entry>>>,
+    ttl_secs: u64,
+    writes: Option<UnboundedSender<Write>>,
+) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(entries) = entries.upgrade() else {
+            return;
+        };
+
+        let now = now();
+        entries.lock().unwrap().retain(|prediction_id, entry| {
+            let expired = expired(&entry.job, ttl_secs, now);
+            if let (true, Some(writes)) = (expired, &writes) {
+                let _ = writes.send(Write::Remove(*prediction_id));
+            }
+            !expired
+        });
+    }
+}
+
+fn expired(job: &Job, ttl_secs: u64, now: u64) -> bool {
+    job.finished_at
+        .is_some_and(|finished_at| now.saturating_sub(finished_at) >= ttl_secs)
+}
+
+/// Read the saved jobs
+fn load_jobs(config: &JobsConfig) -> Vec<Job> {
+    let Some(store_dir) = &config.store_dir else {
+        return Vec::new();
+    };
+    let Ok(files) = fs::read_dir(store_dir) else {
+        return Vec::new();
+    };
+
+    files
+        .filter_map(|file| {
+            let path = file.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            let data = fs::read(&path).ok()?;
+            match serde_json::from_slice::<Job>(&data) {
+                Ok(job) => Some(job),
+                Err(err) => {
+                    tracing::warn!("ignoring saved job {:?}: {}", path, err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn unknown(prediction_id: &Uuid) -> Error {
+    Error::ModelNotFound(format!("job {} is unknown or has expired", prediction_id))
+}
+
+/// Seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let store_dir =
+            env::temp_dir().join(format!("proton-jobs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&store_dir);
+        store_dir
+    }
+
+    fn store(store_dir: &Path, ttl_secs: u64) -> JobStore {
+        JobStore::new(JobsConfig {
+            ttl_secs,
+            max_pending: 10,
+            store_dir: Some(store_dir.to_string_lossy().into_owned()),
+        })
+    }
+
+    fn saved(store_dir: &Path, prediction_id: &Uuid) -> Option<Job> {
+        let data = fs::read(job_path(store_dir, prediction_id)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Wait for the writer task to catch up
+    async fn eventually(condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met");
+    }
+
+    #[tokio::test]
+    async fn jobs_are_saved_to_a_file_each_and_reloaded() {
+        let store_dir = store_dir("reload");
+        let jobs = store(&store_dir, 3600);
+        let pending = Uuid::new_v4();
+        jobs.submit(pending, std::future::pending()).unwrap();
+        let failed = Uuid::new_v4();
+        jobs.submit(failed, std::future::pending()).unwrap();
+        jobs.finish(&failed, Err(Error::Internal("boom".to_string())));
+
+        let is_failed = |job: Option<Job>| job.is_some_and(|job| job.status == JobStatus::Failed);
+        eventually(|| {
+            saved(&store_dir, &pending).is_some() && is_failed(saved(&store_dir, &failed))
+        })
+        .await;
+        assert_eq!(
+            saved(&store_dir, &pending).unwrap().status,
+            JobStatus::Pending
+        );
+
+        // A pending job can't be resumed after a restart
+        let jobs = store(&store_dir, 3600);
+        let job = jobs.get(&pending).unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error.unwrap().code, "UNAVAILABLE");
+        assert_eq!(jobs.get(&failed).unwrap().status, JobStatus::Failed);
+        eventually(|| is_failed(saved(&store_dir, &pending))).await;
+
+        fs::remove_dir_all(&store_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_jobs_are_forgotten_with_their_files() {
+        let store_dir = store_dir("expire");
+        fs::create_dir_all(&store_dir).unwrap();
+        let job = Job {
+            prediction_id: Uuid::new_v4(),
+            status: JobStatus::Cancelled,
+            submitted_at: now() - 7200,
+            finished_at: Some(now() - 7200),
+            response: None,
+            error: None,
+        };
+        Write::Save(Box::new(job.clone()))
+            .apply(&store_dir)
+            .unwrap();
+
+        let jobs = store(&store_dir, 3600);
+        assert!(jobs.get(&job.prediction_id).is_err());
+        eventually(|| !job_path(&store_dir, &job.prediction_id).exists()).await;
+        assert!(jobs.entries.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(&store_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn submitting_beyond_max_pending_is_rejected() {
+        let jobs = JobStore::new(JobsConfig {
+            max_pending: 2,
+            ..JobsConfig::default()
+        });
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        jobs.submit(ids[0], std::future::pending()).unwrap();
+        jobs.submit(ids[1], std::future::pending()).unwrap();
+
+        let err = jobs.submit(ids[2], std::future::pending()).unwrap_err();
+        assert!(matches!(err, Error::ResourceExhausted(_)), "{:?}", err);
+        assert!(jobs.get(&ids[2]).is_err());
+
+        // Finished and cancelled jobs make room
+        jobs.finish(&ids[0], Err(Error::Internal("boom".to_string())));
+        jobs.submit(ids[2], std::future::pending()).unwrap();
+        jobs.cancel(&ids[1]).unwrap();
+        jobs.submit(Uuid::new_v4(), std::future::pending()).unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_zero_ttl_and_max_pending() {
+        assert!(JobsConfig::default().validate().is_ok());
+        let config = JobsConfig {
+            ttl_secs: 0,
+            ..JobsConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = JobsConfig {
+            max_pending: 0,
+            ..JobsConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
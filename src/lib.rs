@@ -1,15 +1,39 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod bandit;
+pub mod batch;
+pub mod canary;
+pub mod cascade;
 pub mod config;
+pub mod dispatch;
+pub mod ensemble;
+pub mod error;
+pub mod jobs;
+pub mod limiter;
 pub mod logging;
+pub mod metadata;
 pub mod model;
+pub mod pipeline;
+pub mod pool;
+pub mod priority;
+pub mod registry;
 pub mod routes;
+pub mod rules;
 pub mod server;
+pub mod session;
+pub mod shadow;
+pub mod split;
 pub mod state;
+pub mod supervisor;
+pub mod tensor;
 pub mod utils;
 pub mod worker;
 
 pub mod predictor {
     tonic::include_proto!("predict");
 }
+
+pub mod onnx {
+    tonic::include_proto!("onnx");
+}
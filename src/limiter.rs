@@ -0,0 +1,302 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{ConcurrencyConfig, LimitAlgorithm};
+
+/// Number of samples the gradient algorithm averages latency over
+const LONG_WINDOW: f64 = 600.0;
+
+/// Lowest ratio of long-term to current latency the gradient algorithm scales the limit by,
+/// so a single slow request can't more than halve it
+const MIN_GRADIENT: f64 = 0.5;
+
+#[derive(Debug)]
+struct LimiterState {
+    limit: f64,
+    in_flight: usize,
+    /// Moving average of latency in seconds, for the gradient algorithm
+    long_latency: Option<f64>,
+}
+
+/// `ConcurrencyLimiter` caps the requests a model version has in flight, from the moment
+/// they are admitted until a worker responds, and adjusts the cap from the latency of each
+/// request.
+///
+/// Requests over the limit are turned away before they reach the queue, so a model that
+/// slows down sheds load early instead of building up a backlog every request then waits
+/// behind. The current limit is reported as `proton_concurrency_limit`.
+///
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    config: ConcurrencyConfig,
+    labels: [(&'static str, String); 2],
+    state: Mutex<LimiterState>,
+}
+
+/// A request admitted by a limiter. Dropping the permit releases the slot, adjusting the
+/// limit if the request finished.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<ConcurrencyLimiter>,
+    started: Instant,
+    /// Requests in flight when this one was admitted, including it
+    in_flight: usize,
+    /// Whether the request timed out or was turned away by the queue, once it finished
+    overloaded: Option<bool>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(model_name: &str, version: &str, config: ConcurrencyConfig) -> Self {
+        let limit = config
+            .initial_limit
+            .clamp(config.min_limit, config.max_limit) as f64;
+        let limiter = Self {
+            config,
+            labels: [
+                ("model", model_name.to_string()),
+                ("version", version.to_string()),
+            ],
+            state: Mutex::new(LimiterState {
+                limit,
+                in_flight: 0,
+                long_latency: None,
+            }),
+        };
+        limiter.record_limit(limit);
+        limiter
+    }
+
+    /// Admit a request if the model has room for it
+    pub fn acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit as usize {
+            return None;
+        }
+        state.in_flight += 1;
+
+        Some(Permit {
+            limiter: Arc::clone(self),
+            started: Instant::now(),
+            in_flight: state.in_flight,
+            overloaded: None,
+        })
+    }
+
+    fn release(&self, latency: Duration, in_flight: usize, overloaded: Option<bool>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        // Requests abandoned by the client say nothing about the model
+        let Some(overloaded) = overloaded else {
+            return;
+        };
+
+        let limit = match self.config.algorithm {
+            LimitAlgorithm::Aimd {
+                latency_threshold_ms,
+                backoff_ratio,
+            } => {
+                let threshold = Duration::from_millis(latency_threshold_ms);
+                if overloaded || latency > threshold {
+                    state.limit * backoff_ratio
+                } else if in_flight * 2 >= state.limit as usize {
+                    // Only grow a limit that is actually being used
+                    state.limit + 1.0
+                } else {
+                    state.limit
+                }
+            }
+            LimitAlgorithm::Gradient { smoothing } => {
+                let latency = latency.as_secs_f64();
+                let long = state.long_latency.unwrap_or(latency);
+                let mut long = long + (latency - long) * 2.0 / (LONG_WINDOW + 1.0);
+                // Let the average catch up quickly when latency recovers, so the limit
+                // doesn't stay pinned to an old slowdown
+                if long > latency * 2.0 {
+                    long *= 0.95;
+                }
+                state.long_latency = Some(long);
+
+                let gradient = match overloaded {
+                    true => MIN_GRADIENT,
+                    false => (long / latency.max(f64::EPSILON)).clamp(MIN_GRADIENT, 1.0),
+                };
+                let target = state.limit * gradient + state.limit.sqrt();
+                state.limit * (1.0 - smoothing) + target * smoothing
+            }
+        };
+
+        let limit = limit.clamp(self.config.min_limit as f64, self.config.max_limit as f64);
+        if limit as usize != state.limit as usize {
+            self.record_limit(limit);
+        }
+        state.limit = limit;
+    }
+
+    fn record_limit(&self, limit: f64) {
+        metrics::gauge!("proton_concurrency_limit", limit.floor(), &self.labels);
+    }
+}
+
+impl Permit {
+    /// Mark the request as finished so its latency counts towards the limit
+    pub fn finish(mut self, overloaded: bool) {
+        self.overloaded = Some(overloaded);
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter
+            .release(self.started.elapsed(), self.in_flight, self.overloaded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(algorithm: LimitAlgorithm, initial_limit: usize) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(
+            "squeezenet",
+            "1",
+            ConcurrencyConfig {
+                algorithm,
+                initial_limit,
+                min_limit: 2,
+                max_limit: 20,
+            },
+        ))
+    }
+
+    fn aimd(initial_limit: usize) -> Arc<ConcurrencyLimiter> {
+        let algorithm = LimitAlgorithm::Aimd {
+            latency_threshold_ms: 100,
+            backoff_ratio: 0.5,
+        };
+        limiter(algorithm, initial_limit)
+    }
+
+    fn gradient(initial_limit: usize) -> Arc<ConcurrencyLimiter> {
+        limiter(LimitAlgorithm::Gradient { smoothing: 1.0 }, initial_limit)
+    }
+
+    /// Finish a request that took `latency_ms` with `in_flight` requests admitted
+    fn complete(limiter: &ConcurrencyLimiter, latency_ms: u64, in_flight: usize, overloaded: bool) {
+        limiter.state.lock().unwrap().in_flight += 1;
+        limiter.release(
+            Duration::from_millis(latency_ms),
+            in_flight,
+            Some(overloaded),
+        );
+    }
+
+    fn limit(limiter: &ConcurrencyLimiter) -> f64 {
+        limiter.state.lock().unwrap().limit
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_turned_away() {
+        let limiter = aimd(2);
+        let first = limiter.acquire().unwrap();
+        let _second = limiter.acquire().unwrap();
+        assert!(limiter.acquire().is_none());
+
+        drop(first);
+        assert!(limiter.acquire().is_some());
+    }
+
+    #[test]
+    fn initial_limit_is_clamped() {
+        assert_eq!(limit(&aimd(0)), 2.0);
+        assert_eq!(limit(&aimd(100)), 20.0);
+    }
+
+    #[test]
+    fn abandoned_requests_leave_the_limit_alone() {
+        let limiter = aimd(4);
+        for _ in 0..4 {
+            drop(limiter.acquire().unwrap());
+        }
+        assert_eq!(limit(&limiter), 4.0);
+        assert_eq!(limiter.state.lock().unwrap().in_flight, 0);
+    }
+
+    #[test]
+    fn aimd_grows_a_limit_in_use() {
+        let limiter = aimd(4);
+        complete(&limiter, 10, 2, false);
+        assert_eq!(limit(&limiter), 5.0);
+
+        // A mostly idle limit says nothing about how much the model can take
+        complete(&limiter, 10, 1, false);
+        assert_eq!(limit(&limiter), 5.0);
+    }
+
+    #[test]
+    fn aimd_backs_off_when_slow_or_overloaded() {
+        let limiter = aimd(16);
+        complete(&limiter, 200, 16, false);
+        assert_eq!(limit(&limiter), 8.0);
+
+        complete(&limiter, 10, 8, true);
+        assert_eq!(limit(&limiter), 4.0);
+    }
+
+    #[test]
+    fn aimd_stays_within_bounds() {
+        let limiter = aimd(20);
+        complete(&limiter, 10, 20, false);
+        assert_eq!(limit(&limiter), 20.0);
+
+        for _ in 0..10 {
+            complete(&limiter, 200, 1, false);
+        }
+        assert_eq!(limit(&limiter), 2.0);
+    }
+
+    #[test]
+    fn gradient_grows_while_latency_is_steady() {
+        let limiter = gradient(4);
+        complete(&limiter, 10, 4, false);
+        // No change in latency, so the limit grows by its square root
+        assert_eq!(limit(&limiter), 6.0);
+
+        for _ in 0..100 {
+            complete(&limiter, 10, 1, false);
+        }
+        assert_eq!(limit(&limiter), 20.0);
+    }
+
+    #[test]
+    fn gradient_shrinks_when_latency_rises() {
+        let limiter = gradient(16);
+        for _ in 0..10 {
+            complete(&limiter, 10, 1, false);
+        }
+        let steady = limit(&limiter);
+        assert_eq!(steady, 20.0);
+
+        // Latency well above the long-term average scales the limit by the lowest gradient
+        complete(&limiter, 1000, 1, false);
+        let slow = limit(&limiter);
+        assert!(
+            slow < steady * MIN_GRADIENT + steady.sqrt() + 1e-9,
+            "{}",
+            slow
+        );
+        assert!(slow < steady);
+    }
+
+    #[test]
+    fn gradient_backs_off_when_overloaded() {
+        let limiter = gradient(16);
+        complete(&limiter, 10, 16, true);
+        assert_eq!(limit(&limiter), 16.0 * MIN_GRADIENT + 4.0);
+
+        // Halving and adding the square root settles at 4
+        for _ in 0..50 {
+            complete(&limiter, 10, 1, true);
+        }
+        assert!((limit(&limiter) - 4.0).abs() < 1e-3, "{}", limit(&limiter));
+    }
+}
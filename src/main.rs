@@ -1,17 +1,14 @@
-use proton::config::Config;
-use std::collections::HashMap;
+use proton::config::{Config, CONFIG_PATH};
 use std::sync::Arc;
-use std::thread;
-use tokio::sync::mpsc::{channel, Sender};
 
+use proton::canary;
 use proton::logging;
 use proton::server::{axum, grpc};
 use proton::state::SharedState;
-use proton::worker::{InferenceWorker, Message};
 
 #[tokio::main]
 async fn main() {
-    let config = match Config::load("config.yaml") {
+    let config = match Config::load(CONFIG_PATH) {
         Ok(config) => config,
         Err(err) => panic!("Failed to load config {:?}", err),
     };
@@ -24,31 +21,22 @@ async fn main() {
     // store config and model readiness state
     let shared_state = Arc::new(SharedState::new(config.clone()));
 
-    // Spawn threads to run our workers in the background. We communicate with the threads using
-    // the channels we created earlier. Each thread gets the receiving end and the sender sides
-    // are provided to the inference handlers
-    let mut queues_tx: HashMap<String, Sender<Message>> = HashMap::new();
+    // Start a supervised worker thread for each replica of each model, or place the
+    // replicas on the shared thread pool. Requests reach the workers through per model
+    // queues held by the model registry, which the admin endpoints update while the server
+    // is running
     for model_config in config.models.iter() {
-        // Create a separate queue for each worker so that worker can process messages at
-        // different rates
-        let (requests_tx, requests_rx) = channel::<Message>(config.server.buffer_size);
-        queues_tx.insert(model_config.name.clone(), requests_tx);
-
-        let mut worker = InferenceWorker::new(model_config.clone(), shared_state.clone());
-
-        thread::spawn(move || {
-            worker.run(requests_rx);
-        });
+        shared_state.models.start(model_config.clone()).unwrap();
+        canary::start(Arc::clone(&shared_state), model_config).unwrap();
     }
-    let queues_tx = Arc::new(queues_tx);
 
     // Run both servers concurrently
     tokio::select! {
-        grpc_result = axum::build(config.server.port, Arc::clone(&queues_tx), shared_state) => {
+        grpc_result = axum::build(config.server.port, Arc::clone(&shared_state)) => {
             tracing::info!("gRPC server exited");
             grpc_result.unwrap();
         }
-        axum_result = grpc::build(config.server.grpc_port, queues_tx) => {
+        axum_result = grpc::build(config.server.grpc_port, shared_state) => {
             tracing::info!("Axum server exited");
             axum_result.unwrap();
         }
@@ -0,0 +1,114 @@
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use crate::config::ModelConfig;
+use crate::onnx::ModelProto;
+use crate::session::{Session, TensorSpec};
+
+/// Name, element type and shape of a model input or output
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dtype: String,
+    /// Dimensions of the tensor, dynamic dimensions are `None`
+    pub shape: Vec<Option<usize>>,
+}
+
+impl From<&TensorSpec> for TensorInfo {
+    fn from(spec: &TensorSpec) -> Self {
+        // Element types we can't serve are still reported using the onnx name
+        let dtype = match spec.dtype {
+            Some(dtype) => dtype.to_string(),
+            None => spec.onnx_type.clone(),
+        };
+
+        Self {
+            name: spec.name.clone(),
+            dtype,
+            shape: spec.dimensions.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OperatorSet {
+    pub domain: String,
+    pub version: i64,
+}
+
+/// Signature of a loaded model along with the metadata stored in its ONNX file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    pub name: String,
+    /// Version of the model being served, as opposed to the `model_version` in the file
+    pub version: String,
+    pub inputs: Vec<TensorInfo>,
+    pub outputs: Vec<TensorInfo>,
+    pub ir_version: i64,
+    pub producer_name: String,
+    pub producer_version: String,
+    pub domain: String,
+    pub model_version: i64,
+    pub description: String,
+    pub opset_import: Vec<OperatorSet>,
+    pub metadata_props: HashMap<String, String>,
+}
+
+impl ModelMetadata {
+    /// Collect the signature from the session and the model level fields from the ONNX file.
+    /// onnxruntime doesn't expose the latter so we decode them from the file directly. A
+    /// file we can't decode still yields the session signature.
+    pub fn new(config: &ModelConfig, session: &Session) -> Self {
+        let mut metadata = match read_model_proto(&config.path) {
+            Ok(model_proto) => Self::from(model_proto),
+            Err(err) => {
+                tracing::warn!("{:?} failed to read onnx metadata: {}", config.name, err);
+                Self::default()
+            }
+        };
+
+        metadata.name = config.name.clone();
+        metadata.version = config.version.clone();
+        metadata.inputs = session.inputs.iter().map(TensorInfo::from).collect();
+        metadata.outputs = session.outputs.iter().map(TensorInfo::from).collect();
+
+        metadata
+    }
+}
+
+impl From<ModelProto> for ModelMetadata {
+    fn from(model_proto: ModelProto) -> Self {
+        Self {
+            ir_version: model_proto.ir_version,
+            producer_name: model_proto.producer_name,
+            producer_version: model_proto.producer_version,
+            domain: model_proto.domain,
+            model_version: model_proto.model_version,
+            description: model_proto.doc_string,
+            opset_import: model_proto
+                .opset_import
+                .into_iter()
+                .map(|opset| OperatorSet {
+                    domain: opset.domain,
+                    version: opset.version,
+                })
+                .collect(),
+            metadata_props: model_proto
+                .metadata_props
+                .into_iter()
+                .map(|entry| (entry.key, entry.value))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+fn read_model_proto(path: &str) -> Result<ModelProto, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    let model_proto = ModelProto::decode(&bytes[..])?;
+
+    Ok(model_proto)
+}
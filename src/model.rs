@@ -1,53 +1,205 @@
-use ndarray::{Array, IxDyn};
-use onnxruntime::environment::Environment;
-use onnxruntime::session::Session;
-use onnxruntime::{GraphOptimizationLevel, LoggingLevel};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fmt;
 
+use crate::batch;
 use crate::config::ModelConfig;
+use crate::metadata::ModelMetadata;
+use crate::session::Session;
+use crate::tensor::{DataType, Tensor};
 
-lazy_static! {
-    pub static ref ENVIRONMENT: Arc<Environment> = Arc::new(
-        Environment::builder()
-            .with_name("onnx_environment")
-            .with_log_level(LoggingLevel::Warning)
-            .build()
-            .unwrap()
-    );
+#[derive(Debug)]
+pub enum PredictError {
+    MissingInput(String),
+    UnexpectedInput(String),
+    DataType {
+        input: String,
+        expected: DataType,
+        actual: DataType,
+    },
+    Shape {
+        input: String,
+        expected: Vec<Option<usize>>,
+        actual: Vec<usize>,
+    },
+    Unsupported(String),
+    Runtime(String),
 }
 
-pub struct Model<'a> {
+impl fmt::Display for PredictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PredictError::MissingInput(name) => write!(f, "missing input {:?}", name),
+            PredictError::UnexpectedInput(name) => write!(f, "unexpected input {:?}", name),
+            PredictError::DataType {
+                input,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "input {:?} expects dtype {} but got {}",
+                input, expected, actual
+            ),
+            PredictError::Shape {
+                input,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "input {:?} expects shape {:?} but got {:?}",
+                input, expected, actual
+            ),
+            PredictError::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            PredictError::Runtime(err) => write!(f, "onnxruntime error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PredictError {}
+
+pub struct Model {
     pub config: ModelConfig,
-    session: Session<'a>,
+    pub metadata: ModelMetadata,
+    /// Whether requests can be stacked into one run, see [`batch::supports_batching`]
+    pub batchable: bool,
+    session: Session,
 }
 
-impl Model<'_> {
+impl Model {
     pub fn new(config: &ModelConfig) -> Self {
-        // Load the onnx model and create a session. For now, we'll just
-        // load the first model in the config but in the future, we'll want
-        // the ability to load multiple models.
-        let session = ENVIRONMENT
-            .new_session_builder()
-            .unwrap()
-            .with_optimization_level(GraphOptimizationLevel::Basic)
-            .unwrap()
-            .with_number_threads(1)
-            .unwrap()
-            .with_model_from_file(config.path.clone())
-            .unwrap();
+        // Load the onnx model and create a session using the configured settings
+        let session_config = &config.session;
+        let session = Session::new(&config.path, session_config)
+            .unwrap_or_else(|err| panic!("{:?} failed to create a session: {}", config.name, err));
 
+        tracing::info!("{:?} session: {:?}", config.name, session_config);
         tracing::info!("{:?} inputs: {:?}", config.name, session.inputs);
         tracing::info!("{:?} outputs: {:?}", config.name, session.outputs);
 
+        let metadata = ModelMetadata::new(config, &session);
+        let batchable = batch::supports_batching(&metadata.inputs);
+        if !batchable
+            && config
+                .batching
+                .as_ref()
+                .is_some_and(|b| b.max_batch_size > 1)
+        {
+            tracing::warn!(
+                "{:?} inputs have a fixed first dimension, running requests unbatched",
+                config.name
+            );
+        }
+
         Self {
             config: config.clone(),
+            metadata,
+            batchable,
             session,
         }
     }
 
-    pub fn predict(&mut self, inputs: Vec<Array<f32, IxDyn>>) -> Array<f32, IxDyn> {
-        let outputs = self.session.run(inputs).unwrap();
+    /// Run the session on a set of named inputs and return every named output.
+    ///
+    /// onnxruntime takes its inputs positionally, so the named inputs are arranged in
+    /// the order the session declares them before running. Each input is checked against
+    /// the dtype and shape the session expects and, if `cast_inputs` is enabled for the
+    /// model, losslessly converted when the client sent a compatible type. Each output
+    /// comes back with its own dtype.
+    pub fn predict(
+        &mut self,
+        mut inputs: HashMap<String, Tensor>,
+    ) -> Result<HashMap<String, Tensor>, PredictError> {
+        let mut input_tensors = Vec::with_capacity(self.session.inputs.len());
+        for input in self.session.inputs.iter() {
+            let expected = input.dtype.ok_or_else(|| {
+                PredictError::Unsupported(format!(
+                    "{:?} has element type {}",
+                    input.name, input.onnx_type
+                ))
+            })?;
+            let tensor = inputs
+                .remove(&input.name)
+                .ok_or_else(|| PredictError::MissingInput(input.name.clone()))?;
+
+            let actual = tensor.dtype();
+            let tensor = if actual == expected {
+                tensor
+            } else if self.config.cast_inputs && actual.can_cast_to(expected) {
+                tensor.cast(expected).unwrap()
+            } else {
+                return Err(PredictError::DataType {
+                    input: input.name.clone(),
+                    expected,
+                    actual,
+                });
+            };
+
+            // Dynamic dimensions, reported as `None`, accept any size
+            let expected_shape = &input.dimensions;
+            let shape_matches = tensor.shape().len() == expected_shape.len()
+                && tensor
+                    .shape()
+                    .iter()
+                    .zip(expected_shape.iter())
+                    .all(|(&actual, &expected)| expected.is_none() || expected == Some(actual));
+            if !shape_matches {
+                return Err(PredictError::Shape {
+                    input: input.name.clone(),
+                    expected: expected_shape.clone(),
+                    actual: tensor.shape().to_vec(),
+                });
+            }
+
+            input_tensors.push(tensor);
+        }
 
-        Array::from_shape_vec(outputs[0].shape(), outputs[0].iter().cloned().collect()).unwrap()
+        if let Some(name) = inputs.into_keys().next() {
+            return Err(PredictError::UnexpectedInput(name));
+        }
+
+        let outputs = self
+            .session
+            .run(input_tensors)
+            .map_err(PredictError::Runtime)?;
+
+        Ok(self
+            .session
+            .outputs
+            .iter()
+            .map(|output| output.name.clone())
+            .zip(outputs)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use ndarray::ArrayD;
+
+    #[test]
+    #[ignore = "needs libonnxruntime and MaskRCNN-10.onnx"]
+    fn maskrcnn_returns_every_output_with_its_dtype() {
+        let config = Config::load("config.yaml").unwrap();
+        let model_config = config
+            .models
+            .iter()
+            .find(|model_config| model_config.name == "maskrcnn")
+            .unwrap();
+        let mut model = Model::new(model_config);
+
+        let image = ArrayD::<f32>::zeros(vec![3, 224, 224]);
+        let inputs = HashMap::from([("image".to_string(), Tensor::Float32(image))]);
+        let outputs = model.predict(inputs).unwrap();
+
+        assert_eq!(outputs.len(), model.metadata.outputs.len());
+        for output in model.metadata.outputs.iter() {
+            let tensor = &outputs[&output.name];
+            assert_eq!(tensor.dtype().to_string(), output.dtype);
+        }
+        // Boxes, scores and masks are float while labels are integers
+        let dtypes: Vec<DataType> = outputs.values().map(Tensor::dtype).collect();
+        assert!(dtypes.contains(&DataType::Float32));
+        assert!(dtypes.contains(&DataType::Int64));
     }
 }
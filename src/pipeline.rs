@@ -0,0 +1,368 @@
+use futures::future;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::config::{PipelineConfig, StepConfig};
+use crate::dispatch;
+use crate::error::Error;
+use crate::priority::Priority;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
+
+/// Prefix of the sources that refer to inputs of the pipeline request
+const INPUT: &str = "input";
+
+/// How a step of a pipeline went
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepReport {
+    pub name: String,
+    pub model_name: String,
+    pub model_version: String,
+    /// Time from sending the step's inputs to its model until it responded
+    pub duration_seconds: f64,
+    /// Outputs of the step, only returned when the request asks for intermediate outputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<HashMap<String, Tensor>>,
+}
+
+/// `Pipeline` runs other models as the steps of a DAG.
+///
+/// The steps are grouped into stages so that each step only depends on inputs of the
+/// request and on steps in earlier stages. Stages run one after the other, and the steps
+/// within a stage run concurrently, each going through the queue of its model like any
+/// other request.
+///
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    pub config: PipelineConfig,
+    stages: Vec<Vec<usize>>,
+}
+
+impl Pipeline {
+    /// Check that every source refers to an input or another step and that the steps don't
+    /// form a cycle
+    pub fn new(config: PipelineConfig) -> Result<Self, String> {
+        if config.steps.is_empty() {
+            return Err("has no steps".to_string());
+        }
+
+        let mut names = HashSet::new();
+        for step in config.steps.iter() {
+            if step.name == INPUT || step.name.contains('.') {
+                return Err(format!("has a step with the invalid name {}", step.name));
+            }
+            if !names.insert(step.name.as_str()) {
+                return Err(format!("has more than one step named {}", step.name));
+            }
+        }
+
+        let sources = config
+            .steps
+            .iter()
+            .flat_map(|step| step.inputs.values())
+            .chain(config.outputs.values());
+        for source in sources {
+            match parse_source(source) {
+                Some((step, _)) if step == INPUT || names.contains(step) => {}
+                _ => return Err(format!("refers to unknown source {}", source)),
+            }
+        }
+
+        let stages = stages(&config.steps)?;
+
+        Ok(Self { config, stages })
+    }
+
+    /// Run the steps, stage by stage, and collect the outputs of the pipeline along with
+    /// a report of each step in the order they ran
+    pub async fn run(
+        &self,
+        shared_state: &SharedState,
+        prediction_id: Uuid,
+        inputs: HashMap<String, Tensor>,
+        include_intermediate: bool,
+        deadline: Option<Instant>,
+        priority: Priority,
+    ) -> Result<(HashMap<String, Tensor>, Vec<StepReport>), Error> {
+        // Every tensor produced so far, keyed by source
+        let mut values: HashMap<String, Tensor> = inputs
+            .into_iter()
+            .map(|(name, tensor)| (format!("{}.{}", INPUT, name), tensor))
+            .collect();
+        let mut reports = Vec::with_capacity(self.config.steps.len());
+
+        for stage in self.stages.iter() {
+            let runs = stage.iter().map(|&index| {
+                self.run_step(
+                    shared_state,
+                    prediction_id,
+                    &self.config.steps[index],
+                    &values,
+                    deadline,
+                    priority,
+                )
+            });
+            for (step, outputs, report) in future::try_join_all(runs).await? {
+                values.extend(
+                    outputs
+                        .iter()
+                        .map(|(name, tensor)| (format!("{}.{}", step, name), tensor.clone())),
+                );
+                reports.push(StepReport {
+                    outputs: include_intermediate.then_some(outputs),
+                    ..report
+                });
+            }
+        }
+
+        let outputs = self
+            .config
+            .outputs
+            .iter()
+            .map(|(name, source)| Ok((name.clone(), self.value(&values, source)?)))
+            .collect::<Result<HashMap<String, Tensor>, Error>>()?;
+
+        Ok((outputs, reports))
+    }
+
+    async fn run_step(
+        &self,
+        shared_state: &SharedState,
+        prediction_id: Uuid,
+        step: &StepConfig,
+        values: &HashMap<String, Tensor>,
+        deadline: Option<Instant>,
+        priority: Priority,
+    ) -> Result<(String, HashMap<String, Tensor>, StepReport), Error> {
+        let inputs = step
+            .inputs
+            .iter()
+            .map(|(name, source)| Ok((name.clone(), self.value(values, source)?)))
+            .collect::<Result<HashMap<String, Tensor>, Error>>()?;
+        let queue = shared_state
+            .models
+            .queue(&step.model, step.version.as_deref())?;
+
+        let started = Instant::now();
+        let outputs = dispatch::send(
+            &queue,
+            prediction_id,
+            &step.model,
+            inputs,
+            deadline,
+            priority,
+        )
+        .await
+        .map_err(|err| {
+            tracing::warn!(
+                "pipeline {} step {} failed prediction_id={:?}: {}",
+                &self.config.name,
+                &step.name,
+                prediction_id,
+                err
+            );
+            err
+        })?;
+        let duration = started.elapsed();
+        metrics::histogram!(
+            "proton_pipeline_step_duration_seconds",
+            duration.as_secs_f64(),
+            "pipeline" => self.config.name.clone(),
+            "step" => step.name.clone()
+        );
+
+        let report = StepReport {
+            name: step.name.clone(),
+            model_name: step.model.clone(),
+            model_version: queue.version,
+            duration_seconds: duration.as_secs_f64(),
+            outputs: None,
+        };
+        Ok((step.name.clone(), outputs, report))
+    }
+
+    fn value(&self, values: &HashMap<String, Tensor>, source: &str) -> Result<Tensor, Error> {
+        values
+            .get(source)
+            .cloned()
+            .ok_or_else(|| match parse_source(source) {
+                Some((INPUT, name)) => Error::InvalidArgument(format!(
+                    "pipeline {} is missing input {}",
+                    &self.config.name, name
+                )),
+                _ => Error::Internal(format!(
+                    "pipeline {} has no output {}",
+                    &self.config.name, source
+                )),
+            })
+    }
+}
+
+/// Split `<step>.<output>` at the first dot, since output names may contain dots
+fn parse_source(source: &str) -> Option<(&str, &str)> {
+    source.split_once('.')
+}
+
+/// Group the steps into stages, each depending only on the stages before it
+fn stages(steps: &[StepConfig]) -> Result<Vec<Vec<usize>>, String> {
+    let dependencies: Vec<HashSet<&str>> = steps
+        .iter()
+        .map(|step| {
+            step.inputs
+                .values()
+                .filter_map(|source| parse_source(source))
+                .map(|(step, _)| step)
+                .filter(|&step| step != INPUT)
+                .collect()
+        })
+        .collect();
+
+    let mut done: HashSet<&str> = HashSet::new();
+    let mut stages = Vec::new();
+    while done.len() < steps.len() {
+        let stage: Vec<usize> = (0..steps.len())
+            .filter(|&index| !done.contains(steps[index].name.as_str()))
+            .filter(|&index| dependencies[index].is_subset(&done))
+            .collect();
+        if stage.is_empty() {
+            return Err("has steps that depend on each other in a cycle".to_string());
+        }
+
+        done.extend(stage.iter().map(|&index| steps[index].name.as_str()));
+        stages.push(stage);
+    }
+
+    Ok(stages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(name: &str, inputs: &[(&str, &str)]) -> StepConfig {
+        StepConfig {
+            name: name.to_string(),
+            model: format!("{}-model", name),
+            version: None,
+            inputs: inputs
+                .iter()
+                .map(|(input, source)| (input.to_string(), source.to_string()))
+                .collect(),
+        }
+    }
+
+    fn pipeline(steps: Vec<StepConfig>, outputs: &[(&str, &str)]) -> Result<Pipeline, String> {
+        Pipeline::new(PipelineConfig {
+            name: "detect-classify".to_string(),
+            steps,
+            outputs: outputs
+                .iter()
+                .map(|(output, source)| (output.to_string(), source.to_string()))
+                .collect(),
+        })
+    }
+
+    /// Names of the steps in each stage
+    fn stage_names(pipeline: &Pipeline) -> Vec<Vec<&str>> {
+        pipeline
+            .stages
+            .iter()
+            .map(|stage| {
+                stage
+                    .iter()
+                    .map(|&index| pipeline.config.steps[index].name.as_str())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn independent_steps_share_a_stage() {
+        // Listed out of order: the join depends on both branches, which only need the input
+        let pipeline = pipeline(
+            vec![
+                step("join", &[("a", "left.scores"), ("b", "right.scores")]),
+                step("left", &[("image", "input.image")]),
+                step("right", &[("image", "input.image")]),
+            ],
+            &[("scores", "join.scores")],
+        )
+        .unwrap();
+
+        assert_eq!(
+            stage_names(&pipeline),
+            vec![vec!["left", "right"], vec!["join"]]
+        );
+    }
+
+    #[test]
+    fn output_names_may_contain_dots() {
+        assert_eq!(
+            parse_source("detector.boxes.0"),
+            Some(("detector", "boxes.0"))
+        );
+        assert_eq!(parse_source("image"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_wiring() {
+        let unknown = pipeline(
+            vec![step("classifier", &[("data_0", "detector.crops")])],
+            &[],
+        );
+        assert_eq!(
+            unknown.unwrap_err(),
+            "refers to unknown source detector.crops"
+        );
+
+        let unknown_output = pipeline(
+            vec![step("classifier", &[("data_0", "input.image")])],
+            &[("scores", "classifier")],
+        );
+        assert!(unknown_output.is_err());
+
+        let cycle = pipeline(
+            vec![step("a", &[("x", "b.y")]), step("b", &[("x", "a.y")])],
+            &[],
+        );
+        assert_eq!(
+            cycle.unwrap_err(),
+            "has steps that depend on each other in a cycle"
+        );
+
+        let duplicate = pipeline(
+            vec![
+                step("a", &[("x", "input.x")]),
+                step("a", &[("x", "input.x")]),
+            ],
+            &[],
+        );
+        assert!(duplicate.is_err());
+
+        for name in [INPUT, "a.b"] {
+            assert!(pipeline(vec![step(name, &[])], &[]).is_err());
+        }
+        assert!(pipeline(vec![], &[]).is_err());
+    }
+
+    #[test]
+    fn missing_request_input_is_the_clients_fault() {
+        let pipeline = pipeline(
+            vec![step("classifier", &[("data_0", "input.image")])],
+            &[("scores", "classifier.scores")],
+        )
+        .unwrap();
+        let values = HashMap::new();
+
+        assert!(matches!(
+            pipeline.value(&values, "input.image"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            pipeline.value(&values, "classifier.scores"),
+            Err(Error::Internal(_))
+        ));
+    }
+}
@@ -0,0 +1,509 @@
+use std::cmp;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+use crate::model::Model;
+use crate::priority::PriorityQueue;
+use crate::registry::ModelState;
+use crate::worker::{InferenceWorker, Message};
+
+/// Delay before recreating a session after a crash, doubled on each crash after that
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long an idle thread sleeps before looking for work again, in case it missed a wakeup
+/// or a session is due to be recreated
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A model version served by the pool, shared by the threads its replicas are placed on
+#[derive(Debug)]
+struct PoolModel {
+    state: Arc<ModelState>,
+    requests_rx: Arc<Mutex<PriorityQueue>>,
+    /// Thread time the model has been charged, divided by its weight
+    pass: Mutex<f64>,
+    /// Number of pool threads running the model right now
+    running: AtomicUsize,
+}
+
+impl PoolModel {
+    /// Whether the model is running on fewer threads than it is guaranteed
+    fn guaranteed(&self) -> bool {
+        self.running.load(Ordering::Relaxed) < self.state.config.scheduling.min_threads
+    }
+
+    /// The model's pass, not letting a model that has been idle claim the time it didn't use
+    fn pass(&self, virtual_time: f64) -> f64 {
+        self.pass.lock().unwrap().max(virtual_time)
+    }
+
+    /// Which of two models goes first: guaranteed models, then the one charged least
+    fn cmp(&self, other: &PoolModel, virtual_time: f64) -> cmp::Ordering {
+        other
+            .guaranteed()
+            .cmp(&self.guaranteed())
+            .then(self.pass(virtual_time).total_cmp(&other.pass(virtual_time)))
+    }
+
+    /// Charge the model for thread time used since its pass was `start_pass`
+    fn charge(&self, start_pass: f64, elapsed: Duration) {
+        let cost = elapsed.as_secs_f64() / self.state.config.scheduling.weight;
+        let mut pass = self.pass.lock().unwrap();
+        *pass = pass.max(start_pass) + cost;
+    }
+}
+
+/// A replica of a model handed to a pool thread
+#[derive(Debug)]
+struct Assignment {
+    model: Arc<PoolModel>,
+    replica: usize,
+    /// Dropped once the thread lets go of the replica
+    done: mpsc::Sender<()>,
+}
+
+/// A replica of a model as held by the thread it runs on. Sessions never leave the thread
+/// that created them.
+struct Placement {
+    model: Arc<PoolModel>,
+    worker: InferenceWorker,
+    session: Option<Model>,
+    /// Requests handled by the current session
+    handled: usize,
+    /// When the current session was created
+    started: Instant,
+    /// When to create the session again after a crash
+    retry_at: Instant,
+    backoff: Duration,
+    /// Set once the thread should let go of the replica
+    closed: bool,
+    _done: mpsc::Sender<()>,
+}
+
+impl Placement {
+    fn new(assignment: Assignment, runtime: &Handle) -> Self {
+        let worker = InferenceWorker::new(
+            Arc::clone(&assignment.model.state),
+            assignment.replica,
+            runtime.clone(),
+        );
+
+        Self {
+            model: assignment.model,
+            worker,
+            session: None,
+            handled: 0,
+            started: Instant::now(),
+            retry_at: Instant::now(),
+            backoff: INITIAL_BACKOFF,
+            closed: false,
+            _done: assignment.done,
+        }
+    }
+
+    /// Create the session if the replica doesn't have one and isn't backing off
+    fn start(&mut self) {
+        if self.session.is_some() || self.closed || self.retry_at > Instant::now() {
+            return;
+        }
+
+        let worker = &self.worker;
+        match panic::catch_unwind(AssertUnwindSafe(|| worker.load())) {
+            Ok(session) => {
+                self.session = Some(session);
+                self.handled = 0;
+                self.started = Instant::now();
+            }
+            Err(_) => self.crashed(),
+        }
+    }
+
+    fn labels(&self) -> [(&'static str, String); 3] {
+        let config = &self.model.state.config;
+        [
+            ("model", config.name.clone()),
+            ("version", config.version.clone()),
+            ("replica", self.worker.replica.to_string()),
+        ]
+    }
+
+    fn set_ready(&self, ready: bool) {
+        self.model.state.ready[self.worker.replica].store(ready, Ordering::Relaxed);
+        metrics::gauge!(
+            "proton_worker_ready",
+            if ready { 1.0 } else { 0.0 },
+            &self.labels()
+        );
+    }
+
+    /// Throw away the session after a panic and schedule a new one after a backoff, unless
+    /// the model is being unloaded
+    fn crashed(&mut self) {
+        self.session = None;
+        self.set_ready(false);
+        self.model.state.crashes.fetch_add(1, Ordering::Relaxed);
+        metrics::increment_counter!("proton_worker_crashes_total", &self.labels());
+        if self.model.state.stopping.load(Ordering::Relaxed) {
+            self.closed = true;
+            return;
+        }
+
+        // A session that stayed up for a while is treated as a fresh start
+        if self.started.elapsed() > MAX_BACKOFF {
+            self.backoff = INITIAL_BACKOFF;
+        }
+        tracing::error!(
+            "{:?} replica {} crashed on the pool, restarting in {:?}",
+            &self.model.state.config.name,
+            self.worker.replica,
+            self.backoff
+        );
+
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// Replicas waiting to be picked up by each thread
+    inboxes: Vec<Mutex<Vec<Assignment>>>,
+    /// Total weight of the replicas placed on each thread
+    load: Mutex<Vec<f64>>,
+    /// Pass of the model most recently picked, which idle models catch up to
+    virtual_time: Mutex<f64>,
+    /// Bumped whenever there may be new work, waking idle threads
+    generation: Mutex<u64>,
+    wakeup: Condvar,
+}
+
+impl Shared {
+    fn new(size: usize) -> Self {
+        Self {
+            inboxes: (0..size).map(|_| Mutex::new(Vec::new())).collect(),
+            load: Mutex::new(vec![0.0; size]),
+            virtual_time: Mutex::new(0.0),
+            generation: Mutex::new(0),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.wakeup.notify_all();
+    }
+}
+
+/// `WorkerPool` runs every model on a fixed set of `num_threads` threads, for servers with
+/// more models than cores.
+///
+/// Each replica of a model is placed on the thread carrying the least weight at the time,
+/// which creates the replica's session and keeps it for as long as the model is loaded.
+/// A thread with several models placed on it uses weighted fair scheduling to pick the
+/// next one to serve: every model is charged the thread time it uses divided by its
+/// `weight`, and the model with waiting requests that has been charged least goes next.
+/// Models running on fewer threads than their `min_threads` go before the others.
+///
+/// A panic only costs the session it happened in, which is recreated after a backoff, and
+/// the thread moves on to other models.
+///
+#[derive(Debug)]
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+}
+
+impl WorkerPool {
+    /// Must be called from within the tokio runtime, which is handed to each worker
+    pub fn new(size: usize) -> Self {
+        let shared = Arc::new(Shared::new(size));
+
+        for index in 0..size {
+            let shared = Arc::clone(&shared);
+            let runtime = Handle::current();
+            thread::Builder::new()
+                .name(format!("pool-{}", index))
+                .spawn(move || run(&shared, &runtime, index))
+                .unwrap();
+        }
+
+        Self { shared }
+    }
+
+    pub fn size(&self) -> usize {
+        self.shared.inboxes.len()
+    }
+
+    /// Place each replica of a model on a thread. The returned receiver disconnects once
+    /// every thread has drained the model's queue, after it is closed, and let go of it.
+    pub fn attach(
+        &self,
+        state: Arc<ModelState>,
+        requests_rx: Arc<Mutex<PriorityQueue>>,
+    ) -> mpsc::Receiver<()> {
+        let (done_tx, done_rx) = mpsc::channel();
+        let model = Arc::new(PoolModel {
+            state: Arc::clone(&state),
+            requests_rx,
+            pass: Mutex::new(*self.shared.virtual_time.lock().unwrap()),
+            running: AtomicUsize::new(0),
+        });
+
+        let mut load = self.shared.load.lock().unwrap();
+        let mut threads: Vec<usize> = (0..self.size()).collect();
+        threads.sort_by(|&a, &b| load[a].total_cmp(&load[b]));
+        for (replica, &index) in threads.iter().take(state.config.replicas).enumerate() {
+            load[index] += state.config.scheduling.weight;
+            self.shared.inboxes[index].lock().unwrap().push(Assignment {
+                model: Arc::clone(&model),
+                replica,
+                done: done_tx.clone(),
+            });
+            tracing::info!(
+                "{:?} replica {} placed on pool thread {}",
+                &state.config.name,
+                replica,
+                index
+            );
+        }
+        drop(load);
+
+        self.shared.notify();
+        done_rx
+    }
+
+    /// Wake idle threads after a request was put on a queue
+    pub fn notify(&self) {
+        self.shared.notify();
+    }
+}
+
+/// Loop of a pool thread, which runs as long as the server
+fn run(shared: &Shared, runtime: &Handle, index: usize) {
+    let mut placements: Vec<Placement> = Vec::new();
+
+    loop {
+        // Read before looking for work, so work arriving meanwhile cuts the wait short
+        let generation = *shared.generation.lock().unwrap();
+
+        let assignments: Vec<Assignment> =
+            shared.inboxes[index].lock().unwrap().drain(..).collect();
+        placements.extend(
+            assignments
+                .into_iter()
+                .map(|assignment| Placement::new(assignment, runtime)),
+        );
+        placements.iter_mut().for_each(Placement::start);
+
+        let served = serve_next(shared, &mut placements);
+
+        placements.retain(|placement| {
+            if placement.closed {
+                let config = &placement.model.state.config;
+                shared.load.lock().unwrap()[index] -= config.scheduling.weight;
+                tracing::info!(
+                    "{:?} replica {} left pool thread {}",
+                    &config.name,
+                    placement.worker.replica,
+                    index
+                );
+            }
+            !placement.closed
+        });
+
+        if !served {
+            let current = shared.generation.lock().unwrap();
+            if *current == generation {
+                let _ = shared
+                    .wakeup
+                    .wait_timeout(current, IDLE_POLL_INTERVAL)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+    }
+}
+
+/// Serve the next model in line that has requests waiting. Returns whether there was one.
+fn serve_next(shared: &Shared, placements: &mut [Placement]) -> bool {
+    let virtual_time = *shared.virtual_time.lock().unwrap();
+    let mut order: Vec<usize> = (0..placements.len())
+        .filter(|&index| placements[index].session.is_some())
+        .collect();
+    order.sort_by(|&a, &b| placements[a].model.cmp(&placements[b].model, virtual_time));
+
+    for index in order {
+        let placement = &mut placements[index];
+        let messages = {
+            let mut requests_rx = match placement.model.requests_rx.try_lock() {
+                Ok(requests_rx) => requests_rx,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                // Another replica is collecting requests from this queue
+                Err(TryLockError::WouldBlock) => continue,
+            };
+            placement.worker.try_collect(&mut requests_rx)
+        };
+
+        match messages {
+            None => placement.closed = true,
+            Some(messages) if messages.is_empty() => {}
+            Some(messages) => {
+                serve(shared, placement, messages, virtual_time);
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// Run the requests and charge the model for the thread time they took
+fn serve(shared: &Shared, placement: &mut Placement, messages: Vec<Message>, virtual_time: f64) {
+    let model = Arc::clone(&placement.model);
+    let start_pass = model.pass(virtual_time);
+    {
+        let mut virtual_time = shared.virtual_time.lock().unwrap();
+        *virtual_time = virtual_time.max(start_pass);
+    }
+
+    let count = messages.len();
+    let started = Instant::now();
+    model.running.fetch_add(1, Ordering::Relaxed);
+    let session = placement.session.as_mut().unwrap();
+    let worker = &placement.worker;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| worker.handle(session, messages)));
+    model.running.fetch_sub(1, Ordering::Relaxed);
+
+    model.charge(start_pass, started.elapsed());
+
+    if result.is_err() {
+        placement.crashed();
+        return;
+    }
+
+    placement.handled += count;
+    let max_requests = model.state.config.max_requests_per_worker;
+    if max_requests.is_some_and(|max_requests| placement.handled >= max_requests) {
+        tracing::info!(
+            "{:?} replica {} recycling session",
+            &model.state.config.name,
+            placement.worker.replica
+        );
+        metrics::increment_counter!("proton_worker_recycles_total", &placement.labels());
+        placement.session = None;
+        placement.set_ready(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModelConfig, SchedulingConfig};
+    use tokio::sync::mpsc::channel;
+
+    /// A pool without threads, so placements stay in the inboxes
+    fn pool(size: usize) -> WorkerPool {
+        WorkerPool {
+            shared: Arc::new(Shared::new(size)),
+        }
+    }
+
+    fn state(name: &str, replicas: usize, weight: f64, min_threads: usize) -> Arc<ModelState> {
+        let mut config: ModelConfig =
+            serde_yaml::from_str(&format!("{{name: {}, path: {}.onnx}}", name, name)).unwrap();
+        config.replicas = replicas;
+        config.scheduling = SchedulingConfig {
+            weight,
+            min_threads,
+        };
+        Arc::new(ModelState::new(config))
+    }
+
+    fn model(name: &str, weight: f64, min_threads: usize) -> PoolModel {
+        let (_, rx) = channel(1);
+        let state = state(name, 1, weight, min_threads);
+        let requests_rx = PriorityQueue::new(rx, state.config.priority.clone(), 1);
+        PoolModel {
+            state,
+            requests_rx: Arc::new(Mutex::new(requests_rx)),
+            pass: Mutex::new(0.0),
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    fn placed(pool: &WorkerPool) -> Vec<Vec<String>> {
+        pool.shared
+            .inboxes
+            .iter()
+            .map(|inbox| {
+                let inbox = inbox.lock().unwrap();
+                inbox
+                    .iter()
+                    .map(|assignment| assignment.model.state.config.name.clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replicas_go_to_the_least_loaded_threads() {
+        let pool = pool(3);
+        let queue = || {
+            let (_, rx) = channel(1);
+            Arc::new(Mutex::new(PriorityQueue::new(rx, Default::default(), 1)))
+        };
+
+        let _heavy = pool.attach(state("heavy", 2, 3.0, 0), queue());
+        let _light = pool.attach(state("light", 2, 1.0, 0), queue());
+
+        assert_eq!(*pool.shared.load.lock().unwrap(), [4.0, 3.0, 1.0]);
+        assert_eq!(
+            placed(&pool),
+            [vec!["heavy", "light"], vec!["heavy"], vec!["light"]]
+        );
+    }
+
+    #[test]
+    fn busy_models_share_time_by_weight() {
+        let models = [model("heavy", 2.0, 0), model("light", 1.0, 0)];
+        let mut served = [0, 0];
+        let mut virtual_time = 0.0;
+        for _ in 0..300 {
+            let next = (0..models.len())
+                .min_by(|&a, &b| models[a].cmp(&models[b], virtual_time))
+                .unwrap();
+            let start_pass = models[next].pass(virtual_time);
+            virtual_time = start_pass;
+            models[next].charge(start_pass, Duration::from_millis(10));
+            served[next] += 1;
+        }
+        assert_eq!(served, [200, 100]);
+    }
+
+    #[test]
+    fn idle_models_catch_up_to_the_virtual_time() {
+        let busy = model("busy", 1.0, 0);
+        busy.charge(0.0, Duration::from_secs(10));
+        let idle = model("idle", 1.0, 0);
+
+        // The idle model goes next but can't claim the 10 seconds it didn't use
+        assert_eq!(idle.cmp(&busy, 5.0), cmp::Ordering::Less);
+        assert_eq!(idle.pass(5.0), 5.0);
+        idle.charge(idle.pass(5.0), Duration::from_secs(1));
+        assert_eq!(*idle.pass.lock().unwrap(), 6.0);
+    }
+
+    #[test]
+    fn guaranteed_models_go_first() {
+        let cheap = model("cheap", 1.0, 0);
+        let guaranteed = model("guaranteed", 1.0, 1);
+        guaranteed.charge(0.0, Duration::from_secs(10));
+        assert_eq!(guaranteed.cmp(&cheap, 0.0), cmp::Ordering::Less);
+
+        // Until it runs on min_threads threads
+        guaranteed.running.store(1, Ordering::Relaxed);
+        assert_eq!(guaranteed.cmp(&cheap, 0.0), cmp::Ordering::Greater);
+    }
+}
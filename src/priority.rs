@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{Notify, Semaphore};
+
+use crate::config::PriorityConfig;
+use crate::worker::Message;
+
+/// Class of service of a request. Interactive traffic would usually be `high` and bulk
+/// backfills `low`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Classes from highest to lowest
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Priority::ALL
+            .into_iter()
+            .find(|priority| priority.name().eq_ignore_ascii_case(value))
+            .ok_or_else(|| format!("unknown priority {}", value))
+    }
+}
+
+/// `PriorityQueue` is the receiving end of a model's queue, shared by its replicas.
+///
+/// Requests arrive through the model's channel in the order they were sent. Senders take
+/// one of the queue's `capacity` slots first, which is given back once the request is
+/// handed out, so the channel and the queue together never hold more than `capacity`
+/// requests. The queue takes requests off the channel and hands them to workers by class,
+/// highest first, and within a class in arrival order or, with
+/// `earliest_deadline_first`, by deadline. Requests that have waited longer than
+/// `promote_after_ms` go before everything else, oldest first, so a steady stream of high
+/// priority requests can't starve the others.
+///
+#[derive(Debug)]
+pub struct PriorityQueue {
+    rx: Receiver<Message>,
+    config: PriorityConfig,
+    /// Requests taken off the channel, by class from highest to lowest
+    classes: [VecDeque<Message>; 3],
+    /// Room left for requests, in the channel or held by the queue
+    slots: Arc<Semaphore>,
+    /// Notified by senders after each request, so workers filling a batch can wait for
+    /// more without holding the queue
+    arrivals: Arc<Notify>,
+}
+
+impl PriorityQueue {
+    pub fn new(rx: Receiver<Message>, config: PriorityConfig, capacity: usize) -> Self {
+        Self {
+            rx,
+            config,
+            classes: Default::default(),
+            slots: Arc::new(Semaphore::new(capacity)),
+            arrivals: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Slots senders take before putting a request on the channel
+    pub fn slots(&self) -> Arc<Semaphore> {
+        Arc::clone(&self.slots)
+    }
+
+    /// Senders notify waiters on this after putting a request on the channel
+    pub fn arrivals(&self) -> Arc<Notify> {
+        Arc::clone(&self.arrivals)
+    }
+
+    /// Wait for the next request to serve. Returns `None` once the channel is closed and
+    /// every request has been handed out.
+    pub fn blocking_recv(&mut self) -> Option<Message> {
+        if let Some(message) = self.next() {
+            return Some(message);
+        }
+        let message = self.rx.blocking_recv()?;
+        self.hold(message);
+        self.next()
+    }
+
+    /// Async version of `blocking_recv`
+    pub async fn recv(&mut self) -> Option<Message> {
+        if let Some(message) = self.next() {
+            return Some(message);
+        }
+        let message = self.rx.recv().await?;
+        self.hold(message);
+        self.next()
+    }
+
+    /// The next request to serve if one is waiting. Fails with `Disconnected` once the
+    /// channel is closed and every request has been handed out.
+    pub fn try_recv(&mut self) -> Result<Message, TryRecvError> {
+        if let Some(message) = self.next() {
+            return Ok(message);
+        }
+        let message = self.rx.try_recv()?;
+        self.hold(message);
+        Ok(self.next().unwrap())
+    }
+
+    /// Take the oldest request of the lowest class off the queue, making room for another
+    pub fn pop_oldest(&mut self) -> Option<Message> {
+        self.fill();
+        let message = self
+            .classes
+            .iter_mut()
+            .rev()
+            .find_map(|class| class.pop_front());
+        if message.is_some() {
+            self.slots.add_permits(1);
+        }
+        message
+    }
+
+    /// The next request to serve without waiting for new ones
+    fn next(&mut self) -> Option<Message> {
+        self.fill();
+
+        let promote_after = Duration::from_millis(self.config.promote_after_ms);
+        let now = Instant::now();
+        // The front of each class is its oldest request
+        let starving = self
+            .classes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, class)| Some((index, class.front()?.enqueued_at)))
+            .filter(|(_, enqueued_at)| now.duration_since(*enqueued_at) >= promote_after)
+            .min_by_key(|(_, enqueued_at)| *enqueued_at);
+
+        let message = match starving {
+            Some((index, _)) => self.classes[index].pop_front(),
+            None => {
+                let class = self.classes.iter_mut().find(|class| !class.is_empty())?;
+                match self.config.earliest_deadline_first {
+                    // Requests without a deadline go last, in arrival order
+                    true => {
+                        let index = (0..class.len())
+                            .min_by_key(|&index| {
+                                (class[index].deadline.is_none(), class[index].deadline)
+                            })
+                            .unwrap();
+                        class.remove(index)
+                    }
+                    false => class.pop_front(),
+                }
+            }
+        };
+
+        if message.is_some() {
+            self.slots.add_permits(1);
+        }
+        message
+    }
+
+    /// Move every request waiting in the channel into its class
+    fn fill(&mut self) {
+        while let Ok(message) = self.rx.try_recv() {
+            self.hold(message);
+        }
+    }
+
+    fn hold(&mut self, message: Message) {
+        self.classes[message.priority as usize].push_back(message);
+    }
+}
+
+impl Drop for PriorityQueue {
+    /// Turn away senders still waiting for a slot, as the channel does once it is closed
+    fn drop(&mut self) {
+        self.slots.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc::{channel, Sender};
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    fn queue(earliest_deadline_first: bool) -> (Sender<Message>, PriorityQueue) {
+        let (tx, rx) = channel(16);
+        let config = PriorityConfig {
+            earliest_deadline_first,
+            promote_after_ms: 1000,
+        };
+        (tx, PriorityQueue::new(rx, config, 16))
+    }
+
+    /// A request named `name` that has waited `waited_ms`
+    fn message(
+        name: &str,
+        priority: Priority,
+        waited_ms: u64,
+        deadline_ms: Option<u64>,
+    ) -> Message {
+        let now = Instant::now();
+        Message {
+            prediction_id: Uuid::new_v4(),
+            model_name: name.to_string(),
+            inputs: HashMap::new(),
+            deadline: deadline_ms.map(|deadline_ms| now + Duration::from_millis(deadline_ms)),
+            priority,
+            enqueued_at: now - Duration::from_millis(waited_ms),
+            response_tx: oneshot::channel().0,
+        }
+    }
+
+    fn send(tx: &Sender<Message>, queue: &PriorityQueue, message: Message) {
+        queue.slots.try_acquire().unwrap().forget();
+        tx.try_send(message).unwrap();
+    }
+
+    fn drain(queue: &mut PriorityQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.try_recv().ok())
+            .map(|message| message.model_name)
+            .collect()
+    }
+
+    #[test]
+    fn priority_parses_case_insensitively() {
+        assert_eq!("HIGH".parse(), Ok(Priority::High));
+        assert_eq!("low".parse(), Ok(Priority::Low));
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn higher_classes_go_first() {
+        let (tx, mut queue) = queue(false);
+        send(&tx, &queue, message("low", Priority::Low, 0, None));
+        send(&tx, &queue, message("normal-1", Priority::Normal, 0, None));
+        send(&tx, &queue, message("high", Priority::High, 0, None));
+        send(&tx, &queue, message("normal-2", Priority::Normal, 0, None));
+
+        assert_eq!(drain(&mut queue), ["high", "normal-1", "normal-2", "low"]);
+    }
+
+    #[test]
+    fn earliest_deadline_goes_first_within_a_class() {
+        let (tx, mut queue) = queue(true);
+        send(&tx, &queue, message("none", Priority::Normal, 0, None));
+        send(&tx, &queue, message("late", Priority::Normal, 0, Some(500)));
+        send(&tx, &queue, message("soon", Priority::Normal, 0, Some(100)));
+        send(&tx, &queue, message("low", Priority::Low, 0, Some(10)));
+
+        assert_eq!(drain(&mut queue), ["soon", "late", "none", "low"]);
+    }
+
+    #[test]
+    fn starving_requests_are_promoted() {
+        let (tx, mut queue) = queue(false);
+        send(&tx, &queue, message("low-old", Priority::Low, 2000, None));
+        send(&tx, &queue, message("normal", Priority::Normal, 1500, None));
+        send(&tx, &queue, message("high", Priority::High, 0, None));
+        send(&tx, &queue, message("low-fresh", Priority::Low, 0, None));
+
+        // Oldest of the starving requests first, then by class
+        assert_eq!(
+            drain(&mut queue),
+            ["low-old", "normal", "high", "low-fresh"]
+        );
+    }
+
+    #[test]
+    fn pop_oldest_takes_from_the_lowest_class() {
+        let (tx, mut queue) = queue(false);
+        send(&tx, &queue, message("low", Priority::Low, 0, None));
+        send(&tx, &queue, message("high", Priority::High, 10, None));
+
+        assert_eq!(queue.pop_oldest().unwrap().model_name, "low");
+        assert_eq!(drain(&mut queue), ["high"]);
+    }
+
+    #[test]
+    fn handing_out_requests_frees_their_slots() {
+        let (tx, mut queue) = queue(false);
+        for index in 0..16 {
+            send(
+                &tx,
+                &queue,
+                message(&index.to_string(), Priority::Normal, 0, None),
+            );
+        }
+        // The requests the queue holds count against its capacity as well
+        queue.fill();
+        assert!(queue.slots.try_acquire().is_err());
+
+        queue.try_recv().unwrap();
+        queue.pop_oldest().unwrap();
+        assert_eq!(queue.slots.available_permits(), 2);
+
+        // Senders waiting for a slot are turned away once the queue is gone
+        let slots = queue.slots();
+        drop(queue);
+        assert!(slots.try_acquire().is_err());
+        assert!(tx.is_closed());
+    }
+}
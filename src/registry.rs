@@ -0,0 +1,825 @@
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+
+use crate::config::{ExecutionMode, ModelConfig, OverloadPolicy, ServerConfig};
+use crate::error::Error;
+use crate::limiter::ConcurrencyLimiter;
+use crate::metadata::ModelMetadata;
+use crate::pool::WorkerPool;
+use crate::priority::PriorityQueue;
+use crate::supervisor::Supervisor;
+use crate::worker::Message;
+
+/// Alias picking the version that serves requests which don't name one
+pub const DEFAULT_ALIAS: &str = "default";
+
+/// How often to check whether a newly started model has come online
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// State of a model shared between the registry and the model's workers
+#[derive(Debug)]
+pub struct ModelState {
+    pub config: ModelConfig,
+    /// Readiness of each replica
+    pub ready: Vec<AtomicBool>,
+    /// Signature and metadata, published once a worker has loaded the model
+    pub metadata: RwLock<Option<ModelMetadata>>,
+    /// Number of times a worker for this model has crashed
+    pub crashes: AtomicUsize,
+    /// Set when the model is being unloaded so supervisors stop restarting workers
+    pub stopping: AtomicBool,
+}
+
+impl ModelState {
+    pub(crate) fn new(config: ModelConfig) -> Self {
+        // Initialize replicas as not ready. InferenceWorker
+        // is responsible for updating these flags once the model comes online
+        let ready = (0..config.replicas)
+            .map(|_| AtomicBool::new(false))
+            .collect();
+
+        Self {
+            config,
+            ready,
+            metadata: RwLock::new(None),
+            crashes: AtomicUsize::new(0),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    pub fn readiness(&self) -> Vec<bool> {
+        self.ready
+            .iter()
+            .map(|ready| ready.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+/// What runs the replicas of a model
+#[derive(Debug)]
+enum Workers {
+    /// A supervised thread for each replica
+    Dedicated(Vec<JoinHandle<()>>),
+    /// Threads of the shared pool, which disconnect once they have let go of the model. The
+    /// mutex only makes the receiver `Sync`.
+    Pool(Mutex<mpsc::Receiver<()>>),
+}
+
+/// A running model: the sending end of its queue and the workers running its replicas
+#[derive(Debug)]
+struct ModelEntry {
+    state: Arc<ModelState>,
+    queue_tx: Sender<Message>,
+    requests_rx: Arc<Mutex<PriorityQueue>>,
+    /// Room left in the queue, see [`PriorityQueue`]
+    slots: Arc<Semaphore>,
+    arrivals: Arc<Notify>,
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+    workers: Workers,
+}
+
+/// Where requests for a version of a model go
+#[derive(Clone, Debug)]
+pub struct Queue {
+    pub version: String,
+    pub tx: Sender<Message>,
+    /// How long requests that don't set a deadline may take, from `timeout_ms`
+    pub timeout: Option<Duration>,
+    pub overload: OverloadPolicy,
+    /// Admits requests to the queue when the model has `concurrency` set
+    pub limiter: Option<Arc<ConcurrencyLimiter>>,
+    rx: Arc<Mutex<PriorityQueue>>,
+    slots: Arc<Semaphore>,
+    arrivals: Arc<Notify>,
+    pool: Option<Arc<WorkerPool>>,
+}
+
+impl Queue {
+    /// Put a message on the queue if it has room. Hands the message back otherwise, like
+    /// `Sender::try_send`.
+    #[allow(clippy::result_large_err)]
+    pub fn try_send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        match self.slots.try_acquire() {
+            Ok(slot) => slot.forget(),
+            Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(message)),
+            Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(message)),
+        }
+        // Holding a slot, the channel always has room
+        self.tx.try_send(message)?;
+        self.sent();
+        Ok(())
+    }
+
+    /// Put a message on the queue, waiting for room
+    pub async fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        match self.slots.acquire().await {
+            Ok(slot) => slot.forget(),
+            Err(_) => return Err(SendError(message)),
+        }
+        self.tx.send(message).await?;
+        self.sent();
+        Ok(())
+    }
+
+    /// Wake workers waiting for more requests to fill a batch, and the shared pool's idle
+    /// threads, which don't wait on any one queue
+    fn sent(&self) {
+        self.arrivals.notify_waiters();
+        if let Some(pool) = &self.pool {
+            pool.notify();
+        }
+    }
+
+    /// Take the oldest request of the lowest priority off the queue, unless a worker is
+    /// collecting requests from it right now, in which case room is about to open up anyway
+    pub fn pop_oldest(&self) -> Option<Message> {
+        let mut rx = match self.rx.try_lock() {
+            Ok(rx) => rx,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        rx.pop_oldest()
+    }
+
+    /// Number of requests waiting in the queue
+    pub fn depth(&self) -> usize {
+        self.tx.max_capacity() - self.slots.available_permits()
+    }
+}
+
+/// Summary of a model version returned by the admin endpoints
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelStatus {
+    pub name: String,
+    pub version: String,
+    pub path: String,
+    /// Readiness of each replica
+    pub ready: Vec<bool>,
+}
+
+impl From<&ModelState> for ModelStatus {
+    fn from(state: &ModelState) -> Self {
+        Self {
+            name: state.config.name.clone(),
+            version: state.config.version.clone(),
+            path: state.config.path.clone(),
+            ready: state.readiness(),
+        }
+    }
+}
+
+/// A model with the versions loaded under its name, listed by `/models`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelSummary {
+    pub name: String,
+    /// Version serving requests that don't name one
+    pub default_version: String,
+    /// Aliases and the versions they point at
+    pub aliases: HashMap<String, String>,
+    pub versions: Vec<ModelStatus>,
+}
+
+/// The versions loaded under a model name and the aliases pointing at them
+#[derive(Debug, Default)]
+struct ModelVersions {
+    versions: HashMap<String, ModelEntry>,
+    aliases: HashMap<String, String>,
+}
+
+impl ModelVersions {
+    /// Find the version a request is for. `version` can name a version or an alias and
+    /// requests without one go to the default version.
+    fn resolve(&self, version: Option<&str>) -> Option<&str> {
+        let Some(version) = version else {
+            return self.default_version();
+        };
+
+        match self.versions.get_key_value(version) {
+            Some((version, _)) => Some(version),
+            None => self.aliases.get(version).map(String::as_str),
+        }
+    }
+
+    /// The version the `default` alias points at, or else the latest version
+    fn default_version(&self) -> Option<&str> {
+        self.aliases
+            .get(DEFAULT_ALIAS)
+            .map(String::as_str)
+            .or_else(|| {
+                self.versions
+                    .keys()
+                    .max_by(|a, b| compare_versions(a, b))
+                    .map(String::as_str)
+            })
+    }
+
+    /// Point an alias at a version, moving it if it points elsewhere
+    fn set_alias(&mut self, alias: &str, version: &str) -> Result<(), Error> {
+        if self.versions.contains_key(alias) {
+            return Err(Error::InvalidArgument(format!(
+                "alias {} is already the name of a version",
+                alias
+            )));
+        }
+        self.aliases.insert(alias.to_string(), version.to_string());
+        Ok(())
+    }
+
+    fn summary(&self, name: &str) -> ModelSummary {
+        let mut versions: Vec<ModelStatus> = self
+            .versions
+            .values()
+            .map(|entry| ModelStatus::from(entry.state.as_ref()))
+            .collect();
+        versions.sort_by(|a, b| compare_versions(&a.version, &b.version));
+
+        ModelSummary {
+            name: name.to_string(),
+            default_version: self.default_version().unwrap_or_default().to_string(),
+            aliases: self.aliases.clone(),
+            versions,
+        }
+    }
+}
+
+/// `ModelRegistry` tracks the models being served and routes requests to their queues.
+///
+/// Several versions of a model can be loaded under the same name. Requests pick a version
+/// either by name or through an alias such as `production`, and go to the default version
+/// when they don't name one.
+///
+/// Models can be loaded, unloaded and reloaded while the server is running. Each change
+/// swaps a single entry in the registry, so requests for other models are unaffected and
+/// requests for the changed model see either the old or the new version, never a mix.
+///
+#[derive(Debug)]
+pub struct ModelRegistry {
+    server: ServerConfig,
+    models: RwLock<HashMap<String, ModelVersions>>,
+    /// Threads running every model in `shared_pool` mode
+    pool: Option<Arc<WorkerPool>>,
+}
+
+impl ModelRegistry {
+    /// Must be called from within the tokio runtime in `shared_pool` mode, which starts the
+    /// pool's threads
+    pub fn new(server: ServerConfig) -> Self {
+        let pool = (server.execution == ExecutionMode::SharedPool)
+            .then(|| Arc::new(WorkerPool::new(server.pool_size())));
+
+        Self {
+            server,
+            models: RwLock::new(HashMap::new()),
+            pool,
+        }
+    }
+
+    /// Start serving a model version without waiting for it to come online. Used at
+    /// startup, where readiness is reported through `/ready`.
+    pub fn start(&self, config: ModelConfig) -> Result<(), Error> {
+        let mut models = self.models.write().unwrap();
+        check_new_version(models.get(&config.name), &config)?;
+
+        let entry = self.spawn(config);
+        insert(&mut models, entry);
+        Ok(())
+    }
+
+    /// Load a new model version and start routing requests to it once all of its replicas
+    /// are ready. Aliases listed in the config move over to the new version.
+    pub async fn load(&self, config: ModelConfig) -> Result<ModelStatus, Error> {
+        check_new_version(self.models.read().unwrap().get(&config.name), &config)?;
+        check_path(&config)?;
+
+        let entry = self.spawn(config);
+        if let Err(err) = wait_ready(entry.state.as_ref()).await {
+            tokio::task::spawn_blocking(move || stop(entry));
+            return Err(err);
+        }
+
+        let status = ModelStatus::from(entry.state.as_ref());
+        let mut models = self.models.write().unwrap();
+        let config = &entry.state.config;
+        // Another version with the same name may have been loaded in the meantime
+        if let Err(err) = check_new_version(models.get(&config.name), config) {
+            drop(models);
+            tokio::task::spawn_blocking(move || stop(entry));
+            return Err(err);
+        }
+        insert(&mut models, entry);
+        tracing::info!("{:?} version {:?} loaded", status.name, status.version);
+
+        Ok(status)
+    }
+
+    /// Stop routing requests to a model version, or to every version of the model when no
+    /// version is given, then wait for their workers to drain their queues and exit
+    pub async fn unload(&self, name: &str, version: Option<&str>) -> Result<(), Error> {
+        let entries: Vec<ModelEntry> = {
+            let mut models = self.models.write().unwrap();
+            let model = models
+                .get_mut(name)
+                .ok_or_else(|| Error::ModelNotFound(format!("unknown model {}", name)))?;
+
+            match version {
+                Some(version) => {
+                    let version = model
+                        .resolve(Some(version))
+                        .ok_or_else(|| unknown_version(name, version))?
+                        .to_string();
+                    let entry = model.versions.remove(&version).unwrap();
+                    // Aliases pointing at the version go with it
+                    model.aliases.retain(|_, target| *target != version);
+                    if model.versions.is_empty() {
+                        models.remove(name);
+                    }
+                    vec![entry]
+                }
+                None => models
+                    .remove(name)
+                    .unwrap()
+                    .versions
+                    .into_values()
+                    .collect(),
+            }
+        };
+
+        tokio::task::spawn_blocking(move || entries.into_iter().for_each(stop))
+            .await
+            .unwrap();
+        tracing::info!("{:?} version {:?} unloaded", name, version);
+
+        Ok(())
+    }
+
+    /// Start a fresh copy of a model version, optionally from a new path, and swap it in
+    /// once it is ready. The old copy keeps serving until then and drains its queue
+    /// afterwards.
+    pub async fn reload(
+        &self,
+        name: &str,
+        version: Option<&str>,
+        path: Option<String>,
+    ) -> Result<ModelStatus, Error> {
+        let mut config = self.with_version(name, version, |entry| entry.state.config.clone())?;
+        if let Some(path) = path {
+            config.path = path;
+        }
+        check_path(&config)?;
+
+        let entry = self.spawn(config);
+        if let Err(err) = wait_ready(entry.state.as_ref()).await {
+            tokio::task::spawn_blocking(move || stop(entry));
+            return Err(err);
+        }
+
+        let status = ModelStatus::from(entry.state.as_ref());
+        let old = {
+            let mut models = self.models.write().unwrap();
+            let versions = models
+                .get_mut(name)
+                .map(|model| &mut model.versions)
+                .filter(|versions| versions.contains_key(&status.version));
+            // The version may have been unloaded while the new copy was starting
+            let Some(versions) = versions else {
+                drop(models);
+                tokio::task::spawn_blocking(move || stop(entry));
+                return Err(unknown_version(name, &status.version));
+            };
+            versions.insert(status.version.clone(), entry).unwrap()
+        };
+
+        tokio::task::spawn_blocking(move || stop(old))
+            .await
+            .unwrap();
+        tracing::info!("{:?} version {:?} reloaded", name, status.version);
+
+        Ok(status)
+    }
+
+    /// Point an alias at a loaded version of a model
+    pub fn set_alias(&self, name: &str, alias: &str, version: &str) -> Result<(), Error> {
+        let mut models = self.models.write().unwrap();
+        let model = models
+            .get_mut(name)
+            .ok_or_else(|| Error::ModelNotFound(format!("unknown model {}", name)))?;
+        let version = model
+            .resolve(Some(version))
+            .ok_or_else(|| unknown_version(name, version))?
+            .to_string();
+
+        model.set_alias(alias, &version)?;
+        tracing::info!(
+            "{:?} alias {:?} points at version {:?}",
+            name,
+            alias,
+            version
+        );
+        Ok(())
+    }
+
+    /// The version an alias points at, if the model has the alias
+    pub fn alias(&self, name: &str, alias: &str) -> Option<String> {
+        let models = self.models.read().unwrap();
+        models.get(name)?.aliases.get(alias).cloned()
+    }
+
+    pub fn remove_alias(&self, name: &str, alias: &str) -> Result<(), Error> {
+        let mut models = self.models.write().unwrap();
+        let model = models
+            .get_mut(name)
+            .ok_or_else(|| Error::ModelNotFound(format!("unknown model {}", name)))?;
+
+        model.aliases.remove(alias).ok_or_else(|| {
+            Error::ModelNotFound(format!("unknown alias {} of model {}", alias, name))
+        })?;
+        Ok(())
+    }
+
+    /// The version a request is routed to and the queue for that version
+    pub fn queue(&self, name: &str, version: Option<&str>) -> Result<Queue, Error> {
+        self.with_version(name, version, |entry| Queue {
+            version: entry.state.config.version.clone(),
+            tx: entry.queue_tx.clone(),
+            timeout: entry.state.config.timeout_ms.map(Duration::from_millis),
+            overload: entry.state.config.overload.clone(),
+            limiter: entry.limiter.clone(),
+            rx: Arc::clone(&entry.requests_rx),
+            slots: Arc::clone(&entry.slots),
+            arrivals: Arc::clone(&entry.arrivals),
+            pool: self.pool.clone(),
+        })
+    }
+
+    /// The version requests without one currently go to
+    pub fn default_version(&self, name: &str) -> Result<String, Error> {
+        self.with_version(name, None, |entry| entry.state.config.version.clone())
+    }
+
+    pub fn list(&self) -> Vec<ModelSummary> {
+        let mut models: Vec<ModelSummary> = self
+            .models
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, model)| model.summary(name))
+            .collect();
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        models
+    }
+
+    /// Readiness of each replica of each version of each model
+    pub fn readiness(&self) -> HashMap<String, HashMap<String, Vec<bool>>> {
+        self.models
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, model)| {
+                let versions = model
+                    .versions
+                    .iter()
+                    .map(|(version, entry)| (version.clone(), entry.state.readiness()))
+                    .collect();
+                (name.clone(), versions)
+            })
+            .collect()
+    }
+
+    /// Look up the signature and metadata of a model version
+    pub fn metadata(&self, name: &str, version: Option<&str>) -> Result<ModelMetadata, Error> {
+        let metadata = self.with_version(name, version, |entry| {
+            entry.state.metadata.read().unwrap().clone()
+        })?;
+
+        // Metadata is published once a worker has loaded the model
+        metadata.ok_or_else(|| Error::Unavailable(format!("model {} is not loaded yet", name)))
+    }
+
+    /// Run `f` on the entry for a version, or alias, of a model
+    fn with_version<T>(
+        &self,
+        name: &str,
+        version: Option<&str>,
+        f: impl FnOnce(&ModelEntry) -> T,
+    ) -> Result<T, Error> {
+        let models = self.models.read().unwrap();
+        let model = models
+            .get(name)
+            .ok_or_else(|| Error::ModelNotFound(format!("unknown model {}", name)))?;
+        let entry = model
+            .resolve(version)
+            .and_then(|version| model.versions.get(version))
+            .ok_or_else(|| unknown_version(name, version.unwrap_or(DEFAULT_ALIAS)))?;
+
+        Ok(f(entry))
+    }
+
+    /// Create a queue for the model and start a supervised worker for each replica, or
+    /// place the replicas on the shared pool
+    fn spawn(&self, mut config: ModelConfig) -> ModelEntry {
+        config.session = self.server.session_config(&config);
+        if let Some(pool) = &self.pool {
+            // Replicas go on different threads
+            config.replicas = config.replicas.min(pool.size());
+        }
+        let state = Arc::new(ModelState::new(config));
+
+        // Replicas share the receiving end and take turns pulling work off the queue
+        let (queue_tx, requests_rx) = channel::<Message>(self.server.buffer_size);
+        let requests_rx = PriorityQueue::new(
+            requests_rx,
+            state.config.priority.clone(),
+            self.server.buffer_size,
+        );
+        let slots = requests_rx.slots();
+        let arrivals = requests_rx.arrivals();
+        let requests_rx = Arc::new(Mutex::new(requests_rx));
+        let limiter = state.config.concurrency.clone().map(|concurrency| {
+            let config = &state.config;
+            Arc::new(ConcurrencyLimiter::new(
+                &config.name,
+                &config.version,
+                concurrency,
+            ))
+        });
+
+        let workers = match &self.pool {
+            Some(pool) => {
+                let done = pool.attach(Arc::clone(&state), Arc::clone(&requests_rx));
+                Workers::Pool(Mutex::new(done))
+            }
+            None => Workers::Dedicated(
+                (0..state.config.replicas)
+                    .map(|replica| {
+                        // Each replica's worker runs under a supervisor that restarts it if
+                        // it crashes
+                        let supervisor = Supervisor::new(Arc::clone(&state), replica);
+                        let requests_rx = Arc::clone(&requests_rx);
+                        thread::spawn(move || supervisor.run(requests_rx))
+                    })
+                    .collect(),
+            ),
+        };
+
+        ModelEntry {
+            state,
+            queue_tx,
+            requests_rx,
+            slots,
+            arrivals,
+            limiter,
+            workers,
+        }
+    }
+}
+
+/// Add a version to the registry and point its aliases at it. The version must have been
+/// checked with `check_new_version`.
+fn insert(models: &mut HashMap<String, ModelVersions>, entry: ModelEntry) {
+    let config = entry.state.config.clone();
+    let model = models.entry(config.name.clone()).or_default();
+    model.versions.insert(config.version.clone(), entry);
+
+    for alias in config.aliases {
+        model.aliases.insert(alias, config.version.clone());
+    }
+}
+
+/// Check that a version and its aliases can be added under a model name
+fn check_new_version(model: Option<&ModelVersions>, config: &ModelConfig) -> Result<(), Error> {
+    let versions = model.map(|model| &model.versions);
+    let alias_clash = config.aliases.iter().find(|alias| {
+        **alias == config.version || versions.is_some_and(|versions| versions.contains_key(*alias))
+    });
+    if let Some(alias) = alias_clash {
+        return Err(Error::InvalidArgument(format!(
+            "alias {} is already the name of a version",
+            alias
+        )));
+    }
+
+    let Some(model) = model else {
+        return Ok(());
+    };
+    if model.versions.contains_key(&config.version) {
+        return Err(Error::AlreadyExists(format!(
+            "version {} of model {} is already loaded",
+            config.version, config.name
+        )));
+    }
+    if model.aliases.contains_key(&config.version) {
+        return Err(Error::InvalidArgument(format!(
+            "version {} of model {} is already the name of an alias",
+            config.version, config.name
+        )));
+    }
+    Ok(())
+}
+
+fn unknown_version(name: &str, version: &str) -> Error {
+    Error::ModelNotFound(format!("unknown version {} of model {}", version, name))
+}
+
+/// Order versions numerically when both are numbers, e.g. 2 before 10
+fn compare_versions(a: &str, b: &str) -> cmp::Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+fn check_path(config: &ModelConfig) -> Result<(), Error> {
+    if Path::new(&config.path).is_file() {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument(format!(
+            "model file {} not found",
+            config.path
+        )))
+    }
+}
+
+/// Wait until every replica of a model is ready. Fails if a worker crashes in the meantime,
+/// which usually means the model couldn't be loaded.
+async fn wait_ready(state: &ModelState) -> Result<(), Error> {
+    loop {
+        if state.readiness().iter().all(|&ready| ready) {
+            return Ok(());
+        }
+        if state.crashes.load(Ordering::Relaxed) > 0 {
+            return Err(Error::Internal(format!(
+                "model {} failed to load from {}",
+                state.config.name, state.config.path
+            )));
+        }
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+/// Close the model's queue and wait for its workers to finish the requests already queued.
+/// Blocks, so it should be run off the async runtime.
+fn stop(entry: ModelEntry) {
+    entry.state.stopping.store(true, Ordering::Relaxed);
+    drop(entry.queue_tx);
+
+    match entry.workers {
+        Workers::Dedicated(supervisors) => {
+            for supervisor in supervisors {
+                if supervisor.join().is_err() {
+                    tracing::error!("{:?} supervisor panicked", entry.state.config.name);
+                }
+            }
+        }
+        // Only ever disconnects
+        Workers::Pool(done) => {
+            let _ = done.into_inner().unwrap().recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A registry whose models never load, which is enough to route by version and alias
+    fn registry() -> ModelRegistry {
+        let server: ServerConfig =
+            serde_yaml::from_str("{num_threads: 1, buffer_size: 2, port: 0, grpc_port: 0}")
+                .unwrap();
+        ModelRegistry::new(server)
+    }
+
+    fn model(version: &str, aliases: &[&str]) -> ModelConfig {
+        serde_yaml::from_str(&format!(
+            "{{name: resnet, version: '{}', path: missing.onnx, aliases: [{}]}}",
+            version,
+            aliases.join(", ")
+        ))
+        .unwrap()
+    }
+
+    fn routed_to(registry: &ModelRegistry, version: Option<&str>) -> Result<String, Error> {
+        registry.queue("resnet", version).map(|queue| queue.version)
+    }
+
+    #[test]
+    fn orders_numeric_versions_by_value() {
+        assert_eq!(compare_versions("2", "10"), cmp::Ordering::Less);
+        assert_eq!(compare_versions("10", "10"), cmp::Ordering::Equal);
+        assert_eq!(compare_versions("b", "a"), cmp::Ordering::Greater);
+        assert_eq!(compare_versions("10", "2-rc"), cmp::Ordering::Less);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_version_go_to_the_latest() {
+        let registry = registry();
+        for version in ["2", "10", "1"] {
+            registry.start(model(version, &[])).unwrap();
+        }
+
+        assert_eq!(registry.default_version("resnet").unwrap(), "10");
+        assert_eq!(routed_to(&registry, None).unwrap(), "10");
+        assert_eq!(routed_to(&registry, Some("2")).unwrap(), "2");
+
+        let summary = &registry.list()[0];
+        assert_eq!(summary.default_version, "10");
+        let versions: Vec<&str> = summary
+            .versions
+            .iter()
+            .map(|status| status.version.as_str())
+            .collect();
+        assert_eq!(versions, ["1", "2", "10"]);
+
+        let err = routed_to(&registry, Some("3")).unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)), "{:?}", err);
+        let err = registry.queue("vgg", None).unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn config_aliases_point_at_their_version() {
+        let registry = registry();
+        registry.start(model("1", &["default", "stable"])).unwrap();
+        registry.start(model("2", &["canary"])).unwrap();
+
+        assert_eq!(registry.default_version("resnet").unwrap(), "1");
+        assert_eq!(routed_to(&registry, Some("stable")).unwrap(), "1");
+        assert_eq!(routed_to(&registry, Some("canary")).unwrap(), "2");
+        assert_eq!(registry.alias("resnet", "stable").as_deref(), Some("1"));
+        assert_eq!(registry.alias("resnet", "beta"), None);
+    }
+
+    #[tokio::test]
+    async fn versions_and_aliases_cant_share_a_name() {
+        let registry = registry();
+        registry.start(model("1", &["stable"])).unwrap();
+
+        let err = registry.start(model("1", &[])).unwrap_err();
+        assert!(matches!(err, Error::AlreadyExists(_)), "{:?}", err);
+        let err = registry.start(model("stable", &[])).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+        let err = registry.start(model("2", &["1"])).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+        let err = registry.set_alias("resnet", "1", "1").unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn aliases_move_between_versions() {
+        let registry = registry();
+        registry.start(model("1", &[])).unwrap();
+        registry.start(model("2", &[])).unwrap();
+
+        registry.set_alias("resnet", "stable", "1").unwrap();
+        assert_eq!(routed_to(&registry, Some("stable")).unwrap(), "1");
+        registry.set_alias("resnet", "stable", "2").unwrap();
+        assert_eq!(routed_to(&registry, Some("stable")).unwrap(), "2");
+
+        // An alias given as the target resolves to its version, so the two move separately
+        registry
+            .set_alias("resnet", DEFAULT_ALIAS, "stable")
+            .unwrap();
+        registry.set_alias("resnet", "stable", "1").unwrap();
+        assert_eq!(registry.default_version("resnet").unwrap(), "2");
+
+        registry.remove_alias("resnet", DEFAULT_ALIAS).unwrap();
+        registry.set_alias("resnet", DEFAULT_ALIAS, "1").unwrap();
+        assert_eq!(registry.default_version("resnet").unwrap(), "1");
+        registry.remove_alias("resnet", DEFAULT_ALIAS).unwrap();
+        assert_eq!(registry.default_version("resnet").unwrap(), "2");
+
+        let err = registry.remove_alias("resnet", "beta").unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)), "{:?}", err);
+        let err = registry.set_alias("resnet", "beta", "3").unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)), "{:?}", err);
+        let err = registry.set_alias("vgg", "beta", "1").unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn unloading_a_version_removes_its_aliases() {
+        let registry = registry();
+        registry.start(model("1", &["stable"])).unwrap();
+        registry.start(model("2", &["default", "canary"])).unwrap();
+
+        registry.unload("resnet", Some("canary")).await.unwrap();
+        assert_eq!(registry.alias("resnet", "canary"), None);
+        assert_eq!(registry.alias("resnet", DEFAULT_ALIAS), None);
+        assert_eq!(registry.default_version("resnet").unwrap(), "1");
+        assert_eq!(routed_to(&registry, Some("stable")).unwrap(), "1");
+
+        registry.unload("resnet", Some("1")).await.unwrap();
+        assert!(registry.list().is_empty());
+        let err = registry.unload("resnet", None).await.unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)), "{:?}", err);
+    }
+}
@@ -0,0 +1,283 @@
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Extension, Path};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Arc;
+
+use crate::canary::{self, CanaryReport};
+use crate::config::{AdminConfig, ModelConfig, RuleConfig};
+use crate::error::Error;
+use crate::registry::ModelStatus;
+use crate::rules;
+use crate::state::SharedState;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ReloadRequest {
+    /// Load the model from a new file instead of its current path
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AliasRequest {
+    /// Version, or another alias, to point at
+    pub version: String,
+}
+
+/// Reject admin requests that don't carry the configured bearer token
+pub async fn require_token<B>(
+    Extension(state): Extension<Arc<SharedState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    authorize(&state.config.server.admin, authorization)?;
+
+    Ok(next.run(request).await)
+}
+
+/// Check an `authorization` header against the admin token. Used by both servers.
+pub(crate) fn authorize(config: &AdminConfig, authorization: Option<&str>) -> Result<(), Error> {
+    let Some(token) = &config.token else {
+        return Err(Error::Unauthenticated(
+            "admin endpoints are disabled, set server.admin.token to enable them".into(),
+        ));
+    };
+    let sent = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // Compare every byte so the time taken doesn't reveal how much of the token matched
+    let matches = sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    match matches {
+        true => Ok(()),
+        false => Err(Error::Unauthenticated("invalid admin token".into())),
+    }
+}
+
+/// Resolve the path of a model loaded through the admin endpoints, which must be a file
+/// inside `model_dir`. Used by both servers.
+pub(crate) fn model_path(config: &AdminConfig, path: &str) -> Result<String, Error> {
+    let model_dir = fs::canonicalize(&config.model_dir)
+        .map_err(|err| Error::Internal(format!("model directory {}: {}", config.model_dir, err)))?;
+    let resolved = fs::canonicalize(model_dir.join(path))
+        .map_err(|err| Error::InvalidArgument(format!("model path {}: {}", path, err)))?;
+
+    if !resolved.starts_with(&model_dir) || !resolved.is_file() {
+        return Err(Error::InvalidArgument(format!(
+            "model path {} isn't a file in {}",
+            path, config.model_dir
+        )));
+    }
+    Ok(resolved.to_string_lossy().into_owned())
+}
+
+/// Load a model, taking the same settings as a model in `config.yaml`. Responds once all
+/// replicas are ready.
+pub async fn load_model(
+    Extension(state): Extension<Arc<SharedState>>,
+    payload: Result<Json<ModelConfig>, JsonRejection>,
+) -> Result<(StatusCode, Json<ModelStatus>), Error> {
+    let Json(mut config) =
+        payload.map_err(|rejection| Error::InvalidArgument(rejection.body_text()))?;
+    config.path = model_path(&state.config.server.admin, &config.path)?;
+    if let Some(canary) = config.canary.as_mut() {
+        canary.path = model_path(&state.config.server.admin, &canary.path)?;
+    }
+    tracing::info!("admin loading model={} from {}", &config.name, &config.path);
+    config
+        .validate()
+        .map_err(|err| Error::InvalidArgument(format!("model {} {}", config.name, err)))?;
+    if config.canary.is_some() {
+        canary::check_idle(&state, &config.name)?;
+    }
+
+    let status = state.models.load(config.clone()).await?;
+    canary::start(Arc::clone(&state), &config)?;
+
+    Ok((StatusCode::CREATED, Json(status)))
+}
+
+/// Stop routing requests to every version of a model and wait for their queues to drain
+pub async fn unload_model(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<StatusCode, Error> {
+    tracing::info!("admin unloading model={}", &name);
+    state.models.unload(&name, None).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop routing requests to a version of a model and wait for its queue to drain. Aliases
+/// pointing at the version are removed.
+pub async fn unload_model_version(
+    Path((name, version)): Path<(String, String)>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<StatusCode, Error> {
+    tracing::info!("admin unloading model={} version={}", &name, &version);
+    state.models.unload(&name, Some(&version)).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Start a fresh copy of the default version of a model and swap it in once it is ready.
+/// The body is optional.
+pub async fn reload_model(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<SharedState>>,
+    payload: Option<Json<ReloadRequest>>,
+) -> Result<Json<ModelStatus>, Error> {
+    let request = payload.map(|Json(request)| request).unwrap_or_default();
+    tracing::info!("admin reloading model={}", &name);
+    let path = request
+        .path
+        .map(|path| model_path(&state.config.server.admin, &path))
+        .transpose()?;
+
+    state.models.reload(&name, None, path).await.map(Json)
+}
+
+/// Same as `reload_model` for a given version or alias
+pub async fn reload_model_version(
+    Path((name, version)): Path<(String, String)>,
+    Extension(state): Extension<Arc<SharedState>>,
+    payload: Option<Json<ReloadRequest>>,
+) -> Result<Json<ModelStatus>, Error> {
+    let request = payload.map(|Json(request)| request).unwrap_or_default();
+    tracing::info!("admin reloading model={} version={}", &name, &version);
+    let path = request
+        .path
+        .map(|path| model_path(&state.config.server.admin, &path))
+        .transpose()?;
+
+    state
+        .models
+        .reload(&name, Some(&version), path)
+        .await
+        .map(Json)
+}
+
+/// Point an alias at a version of a model, moving it if it already exists
+pub async fn set_alias(
+    Path((name, alias)): Path<(String, String)>,
+    Extension(state): Extension<Arc<SharedState>>,
+    payload: Result<Json<AliasRequest>, JsonRejection>,
+) -> Result<StatusCode, Error> {
+    let Json(request) =
+        payload.map_err(|rejection| Error::InvalidArgument(rejection.body_text()))?;
+    state.models.set_alias(&name, &alias, &request.version)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_alias(
+    Path((name, alias)): Path<(String, String)>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<StatusCode, Error> {
+    state.models.remove_alias(&name, &alias)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// State and decision history of every canary, including finished ones
+pub async fn get_canaries(
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Json<Vec<CanaryReport>> {
+    let mut canaries: Vec<CanaryReport> = state
+        .canaries
+        .read()
+        .unwrap()
+        .values()
+        .map(|canary| canary.report())
+        .collect();
+    canaries.sort_by(|a, b| a.model.cmp(&b.model));
+
+    Json(canaries)
+}
+
+pub async fn get_canary(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<CanaryReport>, Error> {
+    state
+        .canaries
+        .read()
+        .unwrap()
+        .get(&name)
+        .map(|canary| Json(canary.report()))
+        .ok_or_else(|| Error::ModelNotFound(format!("no canary for model {}", name)))
+}
+
+/// Routing rules in effect, in the order they are tried
+pub async fn get_rules(Extension(state): Extension<Arc<SharedState>>) -> Json<Vec<RuleConfig>> {
+    Json(state.rules.read().unwrap().rules.clone())
+}
+
+/// Replace the routing rules with the ones currently in `config.yaml`. Nothing else in the
+/// file is reloaded.
+pub async fn reload_rules(
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<Vec<RuleConfig>>, Error> {
+    rules::reload(&state).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config(token: Option<&str>, model_dir: &str) -> AdminConfig {
+        AdminConfig {
+            token: token.map(String::from),
+            model_dir: model_dir.to_string(),
+        }
+    }
+
+    #[test]
+    fn authorize_needs_the_configured_token() {
+        let config = config(Some("secret"), ".");
+        assert!(authorize(&config, Some("Bearer secret")).is_ok());
+        assert!(authorize(&config, Some("Bearer secre")).is_err());
+        assert!(authorize(&config, Some("secret")).is_err());
+        assert!(authorize(&config, None).is_err());
+    }
+
+    #[test]
+    fn authorize_refuses_everything_without_a_token() {
+        let config = config(None, ".");
+        assert!(matches!(
+            authorize(&config, Some("Bearer ")),
+            Err(Error::Unauthenticated(_))
+        ));
+    }
+
+    #[test]
+    fn model_path_stays_inside_the_model_dir() {
+        let model_dir = env::temp_dir().join(format!("proton-models-{}", std::process::id()));
+        fs::create_dir_all(&model_dir).unwrap();
+        fs::write(model_dir.join("model.onnx"), b"").unwrap();
+        let config = config(Some("secret"), model_dir.to_str().unwrap());
+
+        let resolved = model_path(&config, "model.onnx").unwrap();
+        assert!(resolved.ends_with("model.onnx"));
+        assert!(model_path(&config, "../model.onnx").is_err());
+        assert!(model_path(&config, "/etc/passwd").is_err());
+        assert!(model_path(&config, "missing.onnx").is_err());
+        // The directory itself isn't a model
+        assert!(model_path(&config, ".").is_err());
+
+        fs::remove_dir_all(&model_dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::bandit::BanditReport;
+use crate::error::Error;
+use crate::state::SharedState;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FeedbackRequest {
+    /// `prediction_id` from the response of a request routed by a bandit
+    pub prediction_id: Uuid,
+    pub reward: f64,
+}
+
+/// Reward the bandit arm that served a prediction. Each prediction can be rewarded once,
+/// within the bandit's `feedback_ttl_secs`.
+pub async fn post_feedback(
+    Extension(state): Extension<Arc<SharedState>>,
+    payload: Result<Json<FeedbackRequest>, JsonRejection>,
+) -> Result<StatusCode, Error> {
+    let Json(request) =
+        payload.map_err(|rejection| Error::InvalidArgument(rejection.body_text()))?;
+    state.feedback(&request.prediction_id, request.reward)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Statistics of the arms of every bandit
+pub async fn get_bandits(Extension(state): Extension<Arc<SharedState>>) -> Json<Vec<BanditReport>> {
+    let mut bandits: Vec<BanditReport> = state
+        .bandits
+        .values()
+        .map(|bandit| bandit.report())
+        .collect();
+    bandits.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Json(bandits)
+}
+
+pub async fn get_bandit(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<BanditReport>, Error> {
+    state
+        .bandits
+        .get(&name)
+        .map(|bandit| Json(bandit.report()))
+        .ok_or_else(|| Error::ModelNotFound(format!("no bandit named {}", name)))
+}
@@ -0,0 +1,49 @@
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::jobs::Job;
+use crate::routes::predict::{self, InferenceRequest, RequestHeaders};
+use crate::state::SharedState;
+
+/// Queue a prediction and respond straight away with its `prediction_id`, which the result
+/// is then polled for. Takes the same body and headers as `/predict`, with `X-Timeout-Ms`
+/// counting from submission. Fails with `RESOURCE_EXHAUSTED` once `max_pending` jobs are
+/// pending.
+pub async fn submit_job(
+    Extension(state): Extension<Arc<SharedState>>,
+    headers: HeaderMap,
+    payload: Result<Json<InferenceRequest>, JsonRejection>,
+) -> Result<(StatusCode, Json<Job>), Error> {
+    let Json(request) =
+        payload.map_err(|rejection| Error::InvalidArgument(rejection.body_text()))?;
+    let headers = RequestHeaders::parse(&headers)?;
+
+    let prediction_id = Uuid::new_v4();
+    let run_state = Arc::clone(&state);
+    let job = state.jobs.submit(prediction_id, async move {
+        let result = predict::run(&run_state, prediction_id, request, &headers).await;
+        run_state.jobs.finish(&prediction_id, result);
+    })?;
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+pub async fn get_job(
+    Path(prediction_id): Path<Uuid>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<Job>, Error> {
+    state.jobs.get(&prediction_id).map(Json)
+}
+
+/// Cancel a pending job, dropping its request if it is still queued
+pub async fn cancel_job(
+    Path(prediction_id): Path<Uuid>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<Job>, Error> {
+    state.jobs.cancel(&prediction_id).map(Json)
+}
@@ -1,3 +1,6 @@
+pub mod admin;
+pub mod feedback;
+pub mod jobs;
 pub mod models;
 pub mod predict;
 pub mod ready;
@@ -1,30 +1,41 @@
-use axum::extract::Extension;
+use axum::extract::{Extension, Path};
 use axum::response::IntoResponse;
 use axum::Json;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+use crate::config::SplitConfig;
+use crate::error::Error;
+use crate::metadata::ModelMetadata;
+use crate::registry::ModelSummary;
 use crate::state::SharedState;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ModelsResponse {
-    pub models: Vec<Model>,
+    /// Each model with its versions, their readiness and its aliases
+    pub models: Vec<ModelSummary>,
+    /// Traffic splits and the weights of their variants
+    pub splits: Vec<SplitConfig>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Model {
-    pub name: String,
+pub async fn get_models(Extension(state): Extension<Arc<SharedState>>) -> impl IntoResponse {
+    let models = state.models.list();
+    let splits = state.config.splits.clone();
+
+    Json(ModelsResponse { models, splits })
 }
 
-pub async fn get_models(Extension(state): Extension<Arc<SharedState>>) -> impl IntoResponse {
-    let models: Vec<Model> = state
-        .config
-        .models
-        .iter()
-        .map(|model_config| Model {
-            name: model_config.name.clone(),
-        })
-        .collect();
+pub async fn get_model(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<ModelMetadata>, Error> {
+    state.models.metadata(&name, None).map(Json)
+}
 
-    Json(ModelsResponse { models })
+/// Metadata of a version of a model, or of the version an alias points at
+pub async fn get_model_version(
+    Path((name, version)): Path<(String, String)>,
+    Extension(state): Extension<Arc<SharedState>>,
+) -> Result<Json<ModelMetadata>, Error> {
+    state.models.metadata(&name, Some(&version)).map(Json)
 }
@@ -1,68 +1,267 @@
+use axum::extract::rejection::JsonRejection;
 use axum::extract::Extension;
-use axum::response::IntoResponse;
+use axum::http::HeaderMap;
 use axum::Json;
-use ndarray::{Array, IxDyn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-use crate::worker::Message;
+use crate::dispatch::{self, Target};
+use crate::ensemble::{Disagreement, MemberReport};
+use crate::error::{Error, ErrorBody};
+use crate::pipeline::StepReport;
+use crate::priority::Priority;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct InferenceRequest {
     pub model_name: String,
-    pub data: Array<f32, IxDyn>,
+    /// A version or alias of the model. Uses the default version when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
+    /// Key for sticky assignment to a variant when `model_name` is a traffic split
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub routing_key: Option<String>,
+    /// Return the outputs of every step when `model_name` is a pipeline, or of every member
+    /// and how much they disagree when it is an ensemble
+    #[serde(default)]
+    pub include_intermediate: bool,
+    /// Tenant the request is made for, which routing rules can match on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Free-form labels routing rules can match on
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    pub inputs: HashMap<String, Tensor>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct InferenceResponse {
     pub prediction_id: Uuid,
+    /// The model that served the request, which differs from the requested name when the
+    /// request went through a traffic split, bandit or cascade
     pub model_name: String,
-    pub data: Array<f32, IxDyn>,
+    pub model_version: String,
+    /// Variant of the traffic split, arm of the bandit or stage of the cascade that served
+    /// the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    pub outputs: HashMap<String, Tensor>,
+    /// Steps run when `model_name` is a pipeline, in the order they ran
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepReport>,
+    /// Members of an ensemble, when asking for intermediate outputs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<MemberReport>,
+    /// Disagreement between members of an ensemble on each output, when asking for
+    /// intermediate outputs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub disagreement: HashMap<String, Disagreement>,
+    /// Routing rule that picked the model, if one matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+}
+
+/// Several items, for one model or several, run concurrently. Headers such as
+/// `X-Timeout-Ms` and `X-Priority` apply to every item.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchInferenceRequest {
+    pub items: Vec<InferenceRequest>,
+}
+
+/// Outcome of one item of a batch, with either `response` or `error` set
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchItemResult {
+    pub prediction_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<InferenceResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchInferenceResponse {
+    /// One result per item, in the order of the items
+    pub results: Vec<BatchItemResult>,
+}
+
+/// Header setting how long the client is willing to wait, in milliseconds
+pub const TIMEOUT_HEADER: &str = "x-timeout-ms";
+
+/// Header setting the priority class of the request: `high`, `normal` or `low`. The same
+/// name is used for gRPC metadata.
+pub const PRIORITY_HEADER: &str = "x-priority";
+
 pub async fn handle_inference(
-    Extension(queues_tx): Extension<Arc<HashMap<String, mpsc::Sender<Message>>>>,
-    Json(request): Json<InferenceRequest>,
-) -> impl IntoResponse {
-    let prediction_id = Uuid::new_v4();
-    let model_name = request.model_name.clone();
+    Extension(state): Extension<Arc<SharedState>>,
+    headers: HeaderMap,
+    payload: Result<Json<InferenceRequest>, JsonRejection>,
+) -> Result<Json<InferenceResponse>, Error> {
+    let Json(request) =
+        payload.map_err(|rejection| Error::InvalidArgument(rejection.body_text()))?;
+    let headers = RequestHeaders::parse(&headers)?;
+
+    let response = run(&state, Uuid::new_v4(), request, &headers).await?;
+    Ok(Json(response))
+}
+
+/// Run the items concurrently, each with its own prediction ID. Items fail on their own, so
+/// the batch responds 200 unless the body itself is malformed or has too many items, and
+/// results come back in the order of the items.
+pub async fn handle_batch_inference(
+    Extension(state): Extension<Arc<SharedState>>,
+    headers: HeaderMap,
+    payload: Result<Json<BatchInferenceRequest>, JsonRejection>,
+) -> Result<Json<BatchInferenceResponse>, Error> {
+    let Json(request) =
+        payload.map_err(|rejection| Error::InvalidArgument(rejection.body_text()))?;
+    let headers = RequestHeaders::parse(&headers)?;
+
+    let config = &state.config.server.batch_requests;
+    let results = dispatch::run_batch(config, request.items, |item| {
+        let prediction_id = Uuid::new_v4();
+        let (state, headers) = (&state, &headers);
+        async move {
+            let result = run(state, prediction_id, item, headers).await;
+            BatchItemResult {
+                prediction_id,
+                error: result.as_ref().err().map(ErrorBody::from),
+                response: result.ok(),
+            }
+        }
+    })
+    .await?;
+
+    Ok(Json(BatchInferenceResponse { results }))
+}
+
+/// Request settings taken from the headers, shared by every item of a batch
+pub(crate) struct RequestHeaders {
+    deadline: Option<Instant>,
+    priority: Priority,
+    /// Headers with valid string values, which routing rules can match on
+    headers: HashMap<String, String>,
+}
+
+impl RequestHeaders {
+    pub(crate) fn parse(headers: &HeaderMap) -> Result<Self, Error> {
+        let deadline = match headers.get(TIMEOUT_HEADER) {
+            // Timeouts too far out to represent are rejected rather than overflowing
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .and_then(|timeout_ms| {
+                    Instant::now().checked_add(Duration::from_millis(timeout_ms))
+                })
+                .map(Some)
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!("invalid {} header", TIMEOUT_HEADER))
+                })?,
+            None => None,
+        };
+        let priority = match headers.get(PRIORITY_HEADER) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| Error::InvalidArgument(format!("invalid {} header", PRIORITY_HEADER)))?
+                .parse()
+                .map_err(Error::InvalidArgument)?,
+            None => Priority::default(),
+        };
+
+        Ok(Self {
+            deadline,
+            priority,
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Route one request to its model and wait for the outputs
+pub(crate) async fn run(
+    state: &SharedState,
+    prediction_id: Uuid,
+    request: InferenceRequest,
+    headers: &RequestHeaders,
+) -> Result<InferenceResponse, Error> {
+    let target = Target {
+        model_name: request.model_name,
+        model_version: request.model_version,
+        routing_key: request.routing_key,
+        include_intermediate: request.include_intermediate,
+        tenant: request.tenant,
+        labels: request.labels,
+        headers: headers.headers.clone(),
+        deadline: headers.deadline,
+        priority: headers.priority,
+    };
     tracing::info!(
         "handler created prediction_id={:?} for model={}",
         prediction_id,
-        &model_name
+        &target.model_name
     );
 
-    // Create a channel to receive the inference result
-    let (tx, rx) = oneshot::channel();
-
-    let message = Message {
-        prediction_id,
-        model_name: model_name.clone(),
-        input_data: request.data,
-        response_tx: tx,
-    };
-
-    queues_tx
-        .get(&model_name)
-        .unwrap()
-        .send(message)
-        .await
-        .unwrap();
-
-    let response = rx.await.unwrap();
+    let prediction = dispatch::predict(state, prediction_id, &target, request.inputs).await?;
 
     tracing::info!(
         "handler received prediction_id={:?} for model={}",
         prediction_id,
-        &model_name
+        &target.model_name
     );
 
-    Json(InferenceResponse {
+    Ok(InferenceResponse {
         prediction_id,
-        model_name: request.model_name.clone(),
-        data: response,
+        model_name: prediction.model_name,
+        model_version: prediction.model_version,
+        variant: prediction.variant,
+        outputs: prediction.outputs,
+        steps: prediction.steps,
+        members: prediction.members,
+        disagreement: prediction.disagreement,
+        rule: prediction.rule,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn parse(timeout: &'static str) -> Result<RequestHeaders, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static(timeout));
+        RequestHeaders::parse(&headers)
+    }
+
+    #[test]
+    fn timeout_header_sets_the_deadline() {
+        let before = Instant::now();
+        let deadline = parse("250").unwrap().deadline.unwrap();
+        assert!(deadline >= before + Duration::from_millis(250));
+        assert!(RequestHeaders::parse(&HeaderMap::new())
+            .unwrap()
+            .deadline
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_timeouts_are_rejected() {
+        for timeout in ["soon", "-1", "1.5", "18446744073709551616"] {
+            let err = parse(timeout).err().unwrap();
+            assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+        }
+        // Whether the largest timeout fits depends on the platform's clock, but it never
+        // panics
+        if let Err(err) = parse("18446744073709551615") {
+            assert!(matches!(err, Error::InvalidArgument(_)), "{:?}", err);
+        }
+    }
+}
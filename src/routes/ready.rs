@@ -2,7 +2,7 @@ use axum::extract::Extension;
 use axum::response::IntoResponse;
 use axum::Json;
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::Ordering;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::state::SharedState;
@@ -10,14 +10,19 @@ use crate::state::SharedState;
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ReadyResponse {
     pub healthy: bool,
+    /// Readiness of each replica of each version of each model
+    pub models: HashMap<String, HashMap<String, Vec<bool>>>,
 }
 
 pub async fn get_health(Extension(state): Extension<Arc<SharedState>>) -> impl IntoResponse {
-    // Service is ready once all models have been initialized
-    let healthy = state
-        .ready
-        .iter()
-        .all(|(_, ready)| ready.load(Ordering::Relaxed));
+    let models = state.models.readiness();
 
-    Json(ReadyResponse { healthy })
+    // Service is ready once all replicas of all models have been initialized
+    let healthy = models
+        .values()
+        .flat_map(HashMap::values)
+        .flatten()
+        .all(|&ready| ready);
+
+    Json(ReadyResponse { healthy, models })
 }
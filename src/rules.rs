@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use crate::config::{Config, RuleConditions, RuleConfig, CONFIG_PATH};
+use crate::dispatch::Target;
+use crate::error::Error;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
+
+/// `RoutingRules` picks a new target for requests based on their properties, before any
+/// other routing happens.
+///
+/// Rules are tried in order and the first whose conditions all hold wins. Only requests
+/// that don't name a version are considered, and the rule's target is routed like any other
+/// request, so it can be a traffic split, pipeline and so on, but rules aren't applied again.
+///
+#[derive(Clone, Debug, Default)]
+pub struct RoutingRules {
+    pub rules: Vec<RuleConfig>,
+}
+
+impl RoutingRules {
+    pub fn new(rules: Vec<RuleConfig>) -> Self {
+        Self { rules }
+    }
+
+    /// The first rule matching the request, if any
+    pub fn matching(
+        &self,
+        target: &Target,
+        inputs: &HashMap<String, Tensor>,
+    ) -> Option<&RuleConfig> {
+        if target.model_version.is_some() {
+            return None;
+        }
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.model == target.model_name)
+            .find(|rule| matches(&rule.when, target, inputs))
+    }
+}
+
+/// Replace the routing rules with the ones currently in the config file. Only the `rules`
+/// section is read, the rest of the file is left for the next restart. Requests already
+/// being routed keep the old rules.
+pub fn reload(shared_state: &SharedState) -> Result<Vec<RuleConfig>, Error> {
+    let rules = Config::load_rules(CONFIG_PATH).map_err(|err| {
+        Error::InvalidArgument(format!(
+            "failed to load rules from {}: {}",
+            CONFIG_PATH, err
+        ))
+    })?;
+    tracing::info!("reloaded {} routing rules", rules.len());
+
+    *shared_state.rules.write().unwrap() = RoutingRules::new(rules.clone());
+    Ok(rules)
+}
+
+fn matches(when: &RuleConditions, target: &Target, inputs: &HashMap<String, Tensor>) -> bool {
+    let headers = when
+        .headers
+        .iter()
+        .all(|(name, value)| target.headers.get(&name.to_ascii_lowercase()) == Some(value));
+    let tenant = when
+        .tenant
+        .as_ref()
+        .is_none_or(|tenant| target.tenant.as_ref() == Some(tenant));
+    let labels = when
+        .labels
+        .iter()
+        .all(|(name, value)| target.labels.get(name) == Some(value));
+    let shapes = when.inputs.iter().all(|(name, condition)| {
+        inputs.get(name).is_some_and(|tensor| {
+            let elements: usize = tensor.shape().iter().product();
+            condition.min_elements.is_none_or(|min| elements >= min)
+                && condition.max_elements.is_none_or(|max| elements <= max)
+        })
+    });
+
+    headers && tenant && labels && shapes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RuleTarget, ShapeCondition};
+    use ndarray::ArrayD;
+
+    fn rule(name: &str, when: RuleConditions) -> RuleConfig {
+        RuleConfig {
+            name: name.to_string(),
+            model: "resnet".to_string(),
+            when,
+            route_to: RuleTarget {
+                model: format!("resnet-{}", name),
+                version: None,
+            },
+        }
+    }
+
+    fn target() -> Target {
+        Target {
+            model_name: "resnet".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn inputs(shape: &[usize]) -> HashMap<String, Tensor> {
+        let tensor = Tensor::Float32(ArrayD::zeros(shape.to_vec()));
+        HashMap::from([("data_0".to_string(), tensor)])
+    }
+
+    fn matched<'a>(
+        rules: &'a RoutingRules,
+        target: &Target,
+        inputs: &HashMap<String, Tensor>,
+    ) -> Option<&'a str> {
+        rules
+            .matching(target, inputs)
+            .map(|rule| rule.name.as_str())
+    }
+
+    #[test]
+    fn header_names_match_case_insensitively() {
+        let when = RuleConditions {
+            headers: HashMap::from([("X-Client-Tier".to_string(), "gold".to_string())]),
+            ..Default::default()
+        };
+        let rules = RoutingRules::new(vec![rule("gold", when)]);
+
+        let mut target = target();
+        target
+            .headers
+            .insert("x-client-tier".to_string(), "gold".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), Some("gold"));
+
+        // Values are compared as they are
+        target
+            .headers
+            .insert("x-client-tier".to_string(), "Gold".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), None);
+        assert_eq!(matched(&rules, &self::target(), &HashMap::new()), None);
+    }
+
+    #[test]
+    fn tenant_and_labels_must_all_match() {
+        let when = RuleConditions {
+            tenant: Some("acme".to_string()),
+            labels: HashMap::from([
+                ("region".to_string(), "eu".to_string()),
+                ("tier".to_string(), "batch".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let rules = RoutingRules::new(vec![rule("acme-eu", when)]);
+
+        let mut target = target();
+        target.tenant = Some("acme".to_string());
+        target.labels.insert("region".to_string(), "eu".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), None);
+
+        target
+            .labels
+            .insert("tier".to_string(), "batch".to_string());
+        target
+            .labels
+            .insert("extra".to_string(), "ignored".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), Some("acme-eu"));
+
+        target.tenant = Some("globex".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn input_shape_bounds_are_inclusive() {
+        let when = RuleConditions {
+            inputs: HashMap::from([(
+                "data_0".to_string(),
+                ShapeCondition {
+                    min_elements: Some(4),
+                    max_elements: Some(8),
+                },
+            )]),
+            ..Default::default()
+        };
+        let rules = RoutingRules::new(vec![rule("medium", when)]);
+
+        let target = target();
+        assert_eq!(matched(&rules, &target, &inputs(&[1, 3])), None);
+        assert_eq!(matched(&rules, &target, &inputs(&[1, 4])), Some("medium"));
+        assert_eq!(matched(&rules, &target, &inputs(&[2, 4])), Some("medium"));
+        assert_eq!(matched(&rules, &target, &inputs(&[3, 3])), None);
+        // The input has to be there
+        assert_eq!(matched(&rules, &target, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let tenant = RuleConditions {
+            tenant: Some("acme".to_string()),
+            ..Default::default()
+        };
+        let mut other_model = rule("other", RuleConditions::default());
+        other_model.model = "vgg".to_string();
+        let rules = RoutingRules::new(vec![
+            other_model,
+            rule("acme", tenant),
+            rule("fallback", RuleConditions::default()),
+        ]);
+
+        let mut target = target();
+        assert_eq!(matched(&rules, &target, &HashMap::new()), Some("fallback"));
+        target.tenant = Some("acme".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), Some("acme"));
+
+        // Requests naming a version aren't rerouted
+        target.model_version = Some("2".to_string());
+        assert_eq!(matched(&rules, &target, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn validate_rejects_inverted_bounds() {
+        let mut when = RuleConditions::default();
+        when.inputs.insert(
+            "data_0".to_string(),
+            ShapeCondition {
+                min_elements: Some(8),
+                max_elements: Some(4),
+            },
+        );
+        assert!(rule("inverted", when).validate().is_err());
+        assert!(rule("any", RuleConditions::default()).validate().is_ok());
+
+        let mut unrouted = rule("unrouted", RuleConditions::default());
+        unrouted.route_to.model = String::new();
+        assert!(unrouted.validate().is_err());
+    }
+
+    #[test]
+    fn load_rules_reads_only_the_rules() {
+        let path = std::env::temp_dir().join(format!("proton-rules-{}.yaml", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        // The rest of the file wouldn't load as a full config
+        std::fs::write(
+            &path,
+            "models: not a list\nrules:\n  - {name: small, model: resnet, route_to: {model: tiny}}\n",
+        )
+        .unwrap();
+        let rules = Config::load_rules(&path).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].route_to.model, "tiny");
+
+        std::fs::write(&path, "server: {}\n").unwrap();
+        assert!(Config::load_rules(&path).unwrap().is_empty());
+
+        let inverted = "rules:\n  - name: inverted\n    model: resnet\n    when: {inputs: {data_0: {min_elements: 8, max_elements: 4}}}\n    route_to: {model: tiny}\n";
+        std::fs::write(&path, inverted).unwrap();
+        let loaded = Config::load_rules(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+}
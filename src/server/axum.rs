@@ -1,31 +1,59 @@
 use axum::extract::Extension;
-use axum::routing::{get, post};
+use axum::middleware;
+use axum::routing::{delete, get, post, put};
 use axum::{Router, Server};
 use axum_prometheus::PrometheusMetricLayer;
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc::Sender;
 
-use crate::routes::{models, predict, ready};
+use crate::routes::{admin, feedback, jobs, models, predict, ready};
 use crate::state::SharedState;
-use crate::worker::Message;
 
-pub async fn build(
-    port: u16,
-    queues_tx: Arc<HashMap<String, Sender<Message>>>,
-    shared_state: Arc<SharedState>,
-) -> Result<(), hyper::Error> {
+pub async fn build(port: u16, shared_state: Arc<SharedState>) -> Result<(), hyper::Error> {
     let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
 
+    // Admin routes need the configured bearer token
+    let admin_routes = Router::new()
+        .route("/admin/models", post(admin::load_model))
+        .route("/admin/models/:name", delete(admin::unload_model))
+        .route("/admin/models/:name/reload", post(admin::reload_model))
+        .route(
+            "/admin/models/:name/versions/:version",
+            delete(admin::unload_model_version),
+        )
+        .route(
+            "/admin/models/:name/versions/:version/reload",
+            post(admin::reload_model_version),
+        )
+        .route("/admin/rules", get(admin::get_rules))
+        .route("/admin/rules/reload", post(admin::reload_rules))
+        .route("/admin/canaries", get(admin::get_canaries))
+        .route("/admin/canaries/:name", get(admin::get_canary))
+        .route(
+            "/admin/models/:name/aliases/:alias",
+            put(admin::set_alias).delete(admin::remove_alias),
+        )
+        .route_layer(middleware::from_fn(admin::require_token));
+
     let app = Router::new()
         .route("/predict", post(predict::handle_inference))
+        .route("/predict/batch", post(predict::handle_batch_inference))
+        .route("/jobs", post(jobs::submit_job))
+        .route("/jobs/:id", get(jobs::get_job).delete(jobs::cancel_job))
         .route("/models", get(models::get_models))
+        .route("/models/:name", get(models::get_model))
+        .route(
+            "/models/:name/versions/:version",
+            get(models::get_model_version),
+        )
         .route("/ready", get(ready::get_health))
+        .route("/feedback", post(feedback::post_feedback))
+        .route("/bandits", get(feedback::get_bandits))
+        .route("/bandits/:name", get(feedback::get_bandit))
+        .merge(admin_routes)
         .route("/metrics", get(|| async move { metric_handle.render() }))
         .layer(prometheus_layer)
-        .layer(Extension(shared_state))
-        .layer(Extension(queues_tx));
+        .layer(Extension(shared_state));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("Starting axum server, binding to port {:?}", port);
@@ -1,36 +1,57 @@
-use ndarray::{Array, IxDyn};
+use half::f16;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::make_service_fn;
+use ndarray::{ArrayD, IxDyn};
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::oneshot;
-use tonic::transport::{Error, Server};
+use std::time::{Duration, Instant};
+use tonic::metadata::{KeyAndValueRef, MetadataMap};
+use tonic::transport::Server;
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
+use crate::config::{ModelConfig, OverloadPolicy, PriorityConfig, SchedulingConfig, SessionConfig};
+use crate::dispatch::{self, Target};
+use crate::ensemble::{Disagreement, MemberReport};
+use crate::error::Error;
+use crate::metadata::{ModelMetadata, TensorInfo};
+use crate::pipeline::StepReport;
+use crate::predictor::model_manager_server::{ModelManager, ModelManagerServer};
 use crate::predictor::predictor_server::{Predictor, PredictorServer};
-use crate::predictor::{InferenceRequest, InferenceResponse};
-use crate::worker::Message;
+use crate::predictor::{
+    self, AliasResponse, BatchInferenceRequest, BatchInferenceResponse, BatchItemError,
+    BatchItemResult, FeedbackRequest, FeedbackResponse, InferenceRequest, InferenceResponse,
+    LoadModelRequest, MemberResult, ModelMetadataRequest, ModelMetadataResponse, OperatorSet,
+    ReloadModelRequest, ReloadRulesRequest, ReloadRulesResponse, RemoveAliasRequest,
+    SetAliasRequest, StepResult, TensorMetadata, UnloadModelRequest, UnloadModelResponse,
+};
+use crate::priority::Priority;
+use crate::registry::ModelStatus;
+use crate::routes::admin;
+use crate::routes::predict::PRIORITY_HEADER;
+use crate::rules;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct PredictService {
-    queues_tx: Arc<HashMap<String, Sender<Message>>>,
+    shared_state: Arc<SharedState>,
 }
 
 impl PredictService {
-    fn new(queues_tx: Arc<HashMap<String, Sender<Message>>>) -> Self {
-        Self { queues_tx }
+    fn new(shared_state: Arc<SharedState>) -> Self {
+        Self { shared_state }
     }
-}
 
-#[tonic::async_trait]
-impl Predictor for PredictService {
-    async fn predict(
+    /// Route one request to its model and wait for the outputs
+    async fn run(
         &self,
-        request: Request<InferenceRequest>,
-    ) -> Result<Response<InferenceResponse>, Status> {
-        let prediction_id = Uuid::new_v4();
-        let request = request.into_inner();
+        prediction_id: Uuid,
+        request: InferenceRequest,
+        metadata: &RequestMetadata,
+    ) -> Result<InferenceResponse, Error> {
         let model_name = request.model_name.clone();
         tracing::info!(
             "gRPC handler created prediction_id={:?} for model={}",
@@ -38,66 +59,640 @@ impl Predictor for PredictService {
             &model_name
         );
 
-        // Create a channel to receive the inference result
-        let (response_tx, response_rx) = oneshot::channel();
-
-        let input_shape: Vec<usize> = request
-            .shape
-            .iter()
-            .map(|&value| value as usize)
-            .collect::<Vec<usize>>();
-        let input_data = Array::from_shape_vec(IxDyn(&input_shape[..]), request.data).unwrap();
+        let inputs = request
+            .inputs
+            .into_iter()
+            .map(|(name, tensor)| Ok((name, to_tensor(tensor)?)))
+            .collect::<Result<HashMap<String, Tensor>, Error>>()?;
 
-        let message = Message {
-            prediction_id,
-            input_data,
-            response_tx,
+        let target = Target {
             model_name: model_name.clone(),
+            model_version: non_empty(&request.model_version).map(String::from),
+            routing_key: non_empty(&request.routing_key).map(String::from),
+            include_intermediate: request.include_intermediate,
+            tenant: non_empty(&request.tenant).map(String::from),
+            labels: request.labels,
+            headers: metadata.headers.clone(),
+            deadline: metadata.deadline,
+            priority: metadata.priority,
         };
-
-        // Send the prediction to the queue for this model
-        self.queues_tx
-            .get(&model_name)
-            .unwrap()
-            .send(message)
-            .await
-            .unwrap();
-
-        // Wait for the prediction
-        let response = response_rx.await.unwrap();
+        let prediction =
+            dispatch::predict(&self.shared_state, prediction_id, &target, inputs).await?;
         tracing::info!(
             "gRPC handler received prediction_id={:?} for model={}",
             prediction_id,
             &model_name
         );
 
-        let shape = response
-            .shape()
-            .to_vec()
+        Ok(InferenceResponse {
+            model_name: prediction.model_name,
+            prediction_id: prediction_id.to_string(),
+            model_version: prediction.model_version,
+            variant: prediction.variant.unwrap_or_default(),
+            rule: prediction.rule.unwrap_or_default(),
+            outputs: prediction
+                .outputs
+                .into_iter()
+                .map(|(name, tensor)| (name, from_tensor(tensor)))
+                .collect(),
+            steps: prediction.steps.into_iter().map(to_step_result).collect(),
+            members: prediction
+                .members
+                .into_iter()
+                .map(to_member_result)
+                .collect(),
+            disagreement: prediction
+                .disagreement
+                .into_iter()
+                .map(|(name, disagreement)| (name, to_disagreement(disagreement)))
+                .collect(),
+        })
+    }
+}
+
+/// Request settings taken from the metadata, shared by every item of a batch
+#[derive(Debug)]
+struct RequestMetadata {
+    deadline: Option<Instant>,
+    priority: Priority,
+    /// Ascii metadata, which routing rules can match on
+    headers: HashMap<String, String>,
+}
+
+impl RequestMetadata {
+    fn parse(metadata: &MetadataMap) -> Result<Self, Error> {
+        let deadline = parse_deadline(metadata)?;
+        let priority = match metadata.get(PRIORITY_HEADER) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| {
+                    Error::InvalidArgument(format!("invalid {} metadata", PRIORITY_HEADER))
+                })?
+                .parse()
+                .map_err(Error::InvalidArgument)?,
+            None => Priority::default(),
+        };
+        let headers = metadata
             .iter()
-            .map(|&value| value as i32)
+            .filter_map(|entry| match entry {
+                KeyAndValueRef::Ascii(name, value) => {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                }
+                KeyAndValueRef::Binary(..) => None,
+            })
             .collect();
 
-        Ok(Response::new(InferenceResponse {
-            model_name: request.model_name,
-            prediction_id: prediction_id.to_string(),
-            data: response.into_raw_vec(),
-            shape,
+        Ok(Self {
+            deadline,
+            priority,
+            headers,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Predictor for PredictService {
+    async fn predict(
+        &self,
+        request: Request<InferenceRequest>,
+    ) -> Result<Response<InferenceResponse>, Status> {
+        let metadata = RequestMetadata::parse(request.metadata())?;
+        let response = self
+            .run(Uuid::new_v4(), request.into_inner(), &metadata)
+            .await?;
+
+        Ok(Response::new(response))
+    }
+
+    /// Run the items concurrently, each with its own prediction ID. Items fail on their own
+    /// and results come back in the order of the items.
+    async fn predict_batch(
+        &self,
+        request: Request<BatchInferenceRequest>,
+    ) -> Result<Response<BatchInferenceResponse>, Status> {
+        let metadata = RequestMetadata::parse(request.metadata())?;
+        let config = &self.shared_state.config.server.batch_requests;
+        let items = request.into_inner().items;
+        let results = dispatch::run_batch(config, items, |item| {
+            let prediction_id = Uuid::new_v4();
+            let metadata = &metadata;
+            async move {
+                let result = self.run(prediction_id, item, metadata).await;
+                BatchItemResult {
+                    prediction_id: prediction_id.to_string(),
+                    error: result.as_ref().err().map(|err| BatchItemError {
+                        code: err.code_name().to_string(),
+                        message: err.to_string(),
+                    }),
+                    response: result.ok(),
+                }
+            }
+        })
+        .await?;
+
+        Ok(Response::new(BatchInferenceResponse { results }))
+    }
+
+    async fn get_model_metadata(
+        &self,
+        request: Request<ModelMetadataRequest>,
+    ) -> Result<Response<ModelMetadataResponse>, Status> {
+        let request = request.into_inner();
+        let metadata = self
+            .shared_state
+            .models
+            .metadata(&request.model_name, non_empty(&request.model_version))?;
+
+        Ok(Response::new(to_metadata_response(metadata)))
+    }
+
+    async fn feedback(
+        &self,
+        request: Request<FeedbackRequest>,
+    ) -> Result<Response<FeedbackResponse>, Status> {
+        let request = request.into_inner();
+        let prediction_id = Uuid::parse_str(&request.prediction_id).map_err(|err| {
+            Error::InvalidArgument(format!(
+                "invalid prediction_id {}: {}",
+                &request.prediction_id, err
+            ))
+        })?;
+        self.shared_state.feedback(&prediction_id, request.reward)?;
+
+        Ok(Response::new(FeedbackResponse {}))
+    }
+}
+
+#[derive(Debug)]
+pub struct ModelManagerService {
+    shared_state: Arc<SharedState>,
+}
+
+impl ModelManagerService {
+    fn new(shared_state: Arc<SharedState>) -> Self {
+        Self { shared_state }
+    }
+}
+
+#[tonic::async_trait]
+impl ModelManager for ModelManagerService {
+    async fn load_model(
+        &self,
+        request: Request<LoadModelRequest>,
+    ) -> Result<Response<predictor::ModelStatus>, Status> {
+        let request = request.into_inner();
+        tracing::info!(
+            "gRPC admin loading model={} from {}",
+            &request.model_name,
+            &request.path
+        );
+
+        let path = admin::model_path(&self.shared_state.config.server.admin, &request.path)?;
+        let config = ModelConfig {
+            name: request.model_name,
+            version: non_empty(&request.model_version).unwrap_or("1").to_string(),
+            path,
+            aliases: request.aliases,
+            cast_inputs: request.cast_inputs,
+            session: SessionConfig::default(),
+            batching: None,
+            replicas: request.replicas.max(1) as usize,
+            max_requests_per_worker: None,
+            timeout_ms: Some(request.timeout_ms).filter(|&timeout_ms| timeout_ms > 0),
+            overload: OverloadPolicy::default(),
+            concurrency: None,
+            priority: PriorityConfig::default(),
+            scheduling: SchedulingConfig::default(),
+            canary: None,
+        };
+        let status = self.shared_state.models.load(config).await?;
+
+        Ok(Response::new(to_model_status(status)))
+    }
+
+    async fn unload_model(
+        &self,
+        request: Request<UnloadModelRequest>,
+    ) -> Result<Response<UnloadModelResponse>, Status> {
+        let request = request.into_inner();
+        tracing::info!("gRPC admin unloading model={}", &request.model_name);
+        self.shared_state
+            .models
+            .unload(&request.model_name, non_empty(&request.model_version))
+            .await?;
+
+        Ok(Response::new(UnloadModelResponse {}))
+    }
+
+    async fn reload_model(
+        &self,
+        request: Request<ReloadModelRequest>,
+    ) -> Result<Response<predictor::ModelStatus>, Status> {
+        let request = request.into_inner();
+        tracing::info!("gRPC admin reloading model={}", &request.model_name);
+
+        let path = Some(request.path)
+            .filter(|path| !path.is_empty())
+            .map(|path| admin::model_path(&self.shared_state.config.server.admin, &path))
+            .transpose()?;
+        let status = self
+            .shared_state
+            .models
+            .reload(&request.model_name, non_empty(&request.model_version), path)
+            .await?;
+
+        Ok(Response::new(to_model_status(status)))
+    }
+
+    async fn set_alias(
+        &self,
+        request: Request<SetAliasRequest>,
+    ) -> Result<Response<AliasResponse>, Status> {
+        let request = request.into_inner();
+        self.shared_state.models.set_alias(
+            &request.model_name,
+            &request.alias,
+            &request.model_version,
+        )?;
+
+        Ok(Response::new(AliasResponse {}))
+    }
+
+    async fn remove_alias(
+        &self,
+        request: Request<RemoveAliasRequest>,
+    ) -> Result<Response<AliasResponse>, Status> {
+        let request = request.into_inner();
+        self.shared_state
+            .models
+            .remove_alias(&request.model_name, &request.alias)?;
+
+        Ok(Response::new(AliasResponse {}))
+    }
+
+    async fn reload_rules(
+        &self,
+        _request: Request<ReloadRulesRequest>,
+    ) -> Result<Response<ReloadRulesResponse>, Status> {
+        let rules = rules::reload(&self.shared_state)?;
+
+        Ok(Response::new(ReloadRulesResponse {
+            rules: rules.into_iter().map(|rule| rule.name).collect(),
         }))
     }
 }
 
-pub async fn build(
-    port: u16,
-    queues_tx: Arc<HashMap<String, Sender<Message>>>,
-) -> Result<(), Error> {
+fn to_step_result(report: StepReport) -> StepResult {
+    StepResult {
+        name: report.name,
+        model_name: report.model_name,
+        model_version: report.model_version,
+        duration_seconds: report.duration_seconds,
+        outputs: report
+            .outputs
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, tensor)| (name, from_tensor(tensor)))
+            .collect(),
+    }
+}
+
+fn to_member_result(report: MemberReport) -> MemberResult {
+    MemberResult {
+        model_name: report.model_name,
+        model_version: report.model_version,
+        duration_seconds: report.duration_seconds,
+        outputs: report
+            .outputs
+            .into_iter()
+            .map(|(name, tensor)| (name, from_tensor(tensor)))
+            .collect(),
+    }
+}
+
+fn to_disagreement(disagreement: Disagreement) -> predictor::Disagreement {
+    predictor::Disagreement {
+        variance: disagreement.variance,
+        vote_agreement: disagreement.vote_agreement.unwrap_or_default(),
+    }
+}
+
+/// Deadline set by the client through `grpc-timeout`, rejecting values that don't parse
+fn parse_deadline(metadata: &MetadataMap) -> Result<Option<Instant>, Error> {
+    let Some(value) = metadata.get("grpc-timeout") else {
+        return Ok(None);
+    };
+    let deadline = value
+        .to_str()
+        .ok()
+        .and_then(parse_grpc_timeout)
+        .and_then(|timeout| Instant::now().checked_add(timeout))
+        .ok_or_else(|| Error::InvalidArgument("invalid grpc-timeout metadata".to_string()))?;
+    Ok(Some(deadline))
+}
+
+/// Parse a `grpc-timeout` value: up to 8 digits followed by a unit, e.g. `100m`
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    if amount.is_empty() || amount.len() > 8 || !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount.checked_mul(60 * 60)?),
+        "M" => Duration::from_secs(amount.checked_mul(60)?),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// Proto3 strings can't be unset, so an empty string stands in for a missing value
+fn non_empty(value: &str) -> Option<&str> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+fn to_model_status(status: ModelStatus) -> predictor::ModelStatus {
+    predictor::ModelStatus {
+        model_name: status.name,
+        model_version: status.version,
+        path: status.path,
+        ready: status.ready,
+    }
+}
+
+fn to_tensor_metadata(info: TensorInfo) -> TensorMetadata {
+    TensorMetadata {
+        name: info.name,
+        dtype: info.dtype,
+        shape: info
+            .shape
+            .iter()
+            .map(|dim| dim.map_or(-1, |dim| dim as i64))
+            .collect(),
+    }
+}
+
+fn to_metadata_response(metadata: ModelMetadata) -> ModelMetadataResponse {
+    ModelMetadataResponse {
+        model_name: metadata.name,
+        inputs: metadata
+            .inputs
+            .into_iter()
+            .map(to_tensor_metadata)
+            .collect(),
+        outputs: metadata
+            .outputs
+            .into_iter()
+            .map(to_tensor_metadata)
+            .collect(),
+        ir_version: metadata.ir_version,
+        producer_name: metadata.producer_name,
+        producer_version: metadata.producer_version,
+        domain: metadata.domain,
+        model_version: metadata.model_version,
+        description: metadata.description,
+        opset_import: metadata
+            .opset_import
+            .into_iter()
+            .map(|opset| OperatorSet {
+                domain: opset.domain,
+                version: opset.version,
+            })
+            .collect(),
+        metadata_props: metadata.metadata_props,
+        version: metadata.version,
+    }
+}
+
+/// Convert a protobuf tensor into a typed ndarray using its shape and dtype
+fn to_tensor(tensor: predictor::Tensor) -> Result<Tensor, Error> {
+    let shape: Vec<usize> = tensor.shape.iter().map(|&value| value as usize).collect();
+    let shape = IxDyn(&shape[..]);
+
+    let result = match tensor.dtype() {
+        predictor::DataType::Float32 => {
+            ArrayD::from_shape_vec(shape, tensor.float_data).map(Tensor::Float32)
+        }
+        predictor::DataType::Float64 => {
+            ArrayD::from_shape_vec(shape, tensor.double_data).map(Tensor::Float64)
+        }
+        predictor::DataType::Float16 => {
+            let data = tensor
+                .half_data
+                .into_iter()
+                .map(|bits| f16::from_bits(bits as u16))
+                .collect();
+            ArrayD::from_shape_vec(shape, data).map(Tensor::Float16)
+        }
+        predictor::DataType::Int64 => {
+            ArrayD::from_shape_vec(shape, tensor.int64_data).map(Tensor::Int64)
+        }
+        predictor::DataType::Int32 => {
+            ArrayD::from_shape_vec(shape, tensor.int32_data).map(Tensor::Int32)
+        }
+        predictor::DataType::Uint8 => {
+            ArrayD::from_shape_vec(shape, tensor.uint8_data).map(Tensor::Uint8)
+        }
+        predictor::DataType::Bool => {
+            ArrayD::from_shape_vec(shape, tensor.bool_data).map(Tensor::Bool)
+        }
+        predictor::DataType::String => {
+            let data = tensor
+                .string_data
+                .into_iter()
+                .map(String::from_utf8)
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(|err| Error::InvalidArgument(format!("invalid string data: {}", err)))?;
+            ArrayD::from_shape_vec(shape, data).map(Tensor::String)
+        }
+    };
+
+    result.map_err(|err| Error::InvalidArgument(format!("invalid tensor shape: {}", err)))
+}
+
+/// Flatten a typed ndarray into a protobuf tensor
+fn from_tensor(tensor: Tensor) -> predictor::Tensor {
+    let mut proto = predictor::Tensor {
+        shape: tensor.shape().iter().map(|&value| value as i32).collect(),
+        ..Default::default()
+    };
+
+    match tensor {
+        Tensor::Float32(array) => {
+            proto.set_dtype(predictor::DataType::Float32);
+            proto.float_data = into_vec(array);
+        }
+        Tensor::Float64(array) => {
+            proto.set_dtype(predictor::DataType::Float64);
+            proto.double_data = into_vec(array);
+        }
+        Tensor::Float16(array) => {
+            proto.set_dtype(predictor::DataType::Float16);
+            proto.half_data = array.iter().map(|value| value.to_bits() as u32).collect();
+        }
+        Tensor::Int64(array) => {
+            proto.set_dtype(predictor::DataType::Int64);
+            proto.int64_data = into_vec(array);
+        }
+        Tensor::Int32(array) => {
+            proto.set_dtype(predictor::DataType::Int32);
+            proto.int32_data = into_vec(array);
+        }
+        Tensor::Uint8(array) => {
+            proto.set_dtype(predictor::DataType::Uint8);
+            proto.uint8_data = into_vec(array);
+        }
+        Tensor::Bool(array) => {
+            proto.set_dtype(predictor::DataType::Bool);
+            proto.bool_data = into_vec(array);
+        }
+        Tensor::String(array) => {
+            proto.set_dtype(predictor::DataType::String);
+            proto.string_data = array
+                .iter()
+                .map(|value| value.as_bytes().to_vec())
+                .collect();
+        }
+    }
+
+    proto
+}
+
+/// Take the elements of an array in row-major order
+fn into_vec<A: Clone>(array: ArrayD<A>) -> Vec<A> {
+    if array.is_standard_layout() {
+        array.into_raw_vec()
+    } else {
+        array.iter().cloned().collect()
+    }
+}
+
+pub async fn build(port: u16, shared_state: Arc<SharedState>) -> Result<(), hyper::Error> {
     let grpc_addr = SocketAddr::from(([0, 0, 0, 0], port));
     tracing::info!("Starting gRPC server, binding to port {:?}", port);
 
-    let predict_service = PredictService::new(queues_tx);
+    serve(hyper::Server::try_bind(&grpc_addr)?, shared_state).await
+}
+
+/// Serve both gRPC services over HTTP/2.
+///
+/// tonic's own server races every call against its `grpc-timeout` and answers `CANCELLED`
+/// when it passes first, so the services are served by hyper directly and the handlers
+/// answer `DEADLINE_EXCEEDED` themselves.
+async fn serve(
+    builder: hyper::server::Builder<AddrIncoming>,
+    shared_state: Arc<SharedState>,
+) -> Result<(), hyper::Error> {
+    let predict_service = PredictService::new(Arc::clone(&shared_state));
+    let admin_config = shared_state.config.server.admin.clone();
+    let model_manager_service = ModelManagerService::new(shared_state);
+    // Admin calls need the configured bearer token in their `authorization` metadata. tonic
+    // interceptors return a `Status`, however large.
+    #[allow(clippy::result_large_err)]
+    let authorize = move |request: Request<()>| {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        admin::authorize(&admin_config, authorization)?;
+        Ok(request)
+    };
 
-    Server::builder()
+    let routes = Server::builder()
         .add_service(PredictorServer::new(predict_service))
-        .serve(grpc_addr)
-        .await
+        .add_service(ModelManagerServer::with_interceptor(
+            model_manager_service,
+            authorize,
+        ))
+        .into_service();
+    let make_service = make_service_fn(move |_| {
+        let routes = routes.clone();
+        async move { Ok::<_, Infallible>(routes) }
+    });
+
+    builder.http2_only(true).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use prost::Message;
+    use tonic::Code;
+
+    #[test]
+    fn grpc_timeout_units() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("4S"), Some(Duration::from_secs(4)));
+        assert_eq!(parse_grpc_timeout("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_grpc_timeout("5u"), Some(Duration::from_micros(5)));
+        assert_eq!(
+            parse_grpc_timeout("99999999n"),
+            Some(Duration::from_nanos(99999999))
+        );
+    }
+
+    #[test]
+    fn malformed_grpc_timeout_is_rejected() {
+        for value in ["", "m", "100", "100x", "+5S", "-5S", "1.5S", "123456789H"] {
+            assert_eq!(parse_grpc_timeout(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn malformed_grpc_timeout_is_invalid_argument() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("grpc-timeout", "123456789H".parse().unwrap());
+        let err = RequestMetadata::parse(&metadata).unwrap_err();
+        assert_eq!(Status::from(err).code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn expired_deadline_is_deadline_exceeded() {
+        // The model can't load, so requests wait in its queue until their deadline
+        let config: Config = serde_yaml::from_str(
+            "
+            log_level: INFO
+            server: {num_threads: 1, buffer_size: 4, port: 0, grpc_port: 0}
+            models:
+              - {name: stalled, path: missing.onnx}
+            ",
+        )
+        .unwrap();
+        let model_config = config.models[0].clone();
+        let shared_state = Arc::new(SharedState::new(config));
+        shared_state.models.start(model_config).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let builder = hyper::Server::from_tcp(listener).unwrap();
+        tokio::spawn(serve(builder, shared_state));
+
+        // tonic's client gives up on its own at the deadline, so the call is made over plain
+        // HTTP/2 to see what the server answers
+        let request = InferenceRequest {
+            model_name: "stalled".to_string(),
+            ..Default::default()
+        };
+        let mut body = vec![0];
+        body.extend((request.encoded_len() as u32).to_be_bytes());
+        body.extend(request.encode_to_vec());
+        let request = hyper::Request::post(format!("http://{}/predict.Predictor/Predict", addr))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .header("grpc-timeout", "200m")
+            .body(hyper::Body::from(body))
+            .unwrap();
+        let client = hyper::Client::builder().http2_only(true).build_http();
+        let response = client.request(request).await.unwrap();
+
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), Code::DeadlineExceeded, "{:?}", status);
+    }
 }
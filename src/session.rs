@@ -0,0 +1,509 @@
+use half::f16;
+use ndarray::ArrayD;
+use onnxruntime_sys as sys;
+use std::ffi::{CStr, CString};
+use std::fmt::Debug;
+use std::os::raw::{c_char, c_int, c_void};
+use std::{mem, ptr, slice};
+
+use crate::config::{OptimizationLevel, SessionConfig};
+use crate::tensor::{DataType, Element, Tensor};
+
+/// Call a function of the onnxruntime C API, turning a failed status into its message
+macro_rules! call {
+    ($function:ident $(, $arg:expr)* $(,)?) => {
+        status(unsafe { api().$function.unwrap()($($arg),*) })
+    };
+}
+
+/// The onnxruntime C API, which is shared by every session
+struct Api(*const sys::OrtApi);
+
+unsafe impl Send for Api {}
+unsafe impl Sync for Api {}
+
+/// The process wide onnxruntime environment, which lives as long as the server
+struct Environment(*mut sys::OrtEnv);
+
+unsafe impl Send for Environment {}
+unsafe impl Sync for Environment {}
+
+lazy_static! {
+    static ref API: Api = {
+        let base = unsafe { sys::OrtGetApiBase() };
+        assert!(!base.is_null(), "onnxruntime has no API base");
+        let api = unsafe { (*base).GetApi.unwrap()(sys::ORT_API_VERSION) };
+        assert!(
+            !api.is_null(),
+            "onnxruntime doesn't support API version {}",
+            sys::ORT_API_VERSION
+        );
+        Api(api)
+    };
+    static ref ENVIRONMENT: Environment = {
+        let name = CString::new("proton").unwrap();
+        let mut env = ptr::null_mut();
+        call!(
+            CreateEnv,
+            sys::OrtLoggingLevel::ORT_LOGGING_LEVEL_WARNING,
+            name.as_ptr(),
+            &mut env
+        )
+        .unwrap();
+        Environment(env)
+    };
+}
+
+fn api() -> &'static sys::OrtApi {
+    unsafe { &*API.0 }
+}
+
+fn status(status: sys::OrtStatusPtr) -> Result<(), String> {
+    if status.is_null() {
+        return Ok(());
+    }
+    let message = unsafe { CStr::from_ptr(api().GetErrorMessage.unwrap()(status)) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { api().ReleaseStatus.unwrap()(status) };
+    Err(message)
+}
+
+/// Name, element type and shape of a session input or output
+#[derive(Clone, Debug)]
+pub struct TensorSpec {
+    pub name: String,
+    /// `None` when we can't represent the element type, or the value isn't a tensor
+    pub dtype: Option<DataType>,
+    /// onnxruntime's name for the type, e.g. `int8`, or `sequence` for values that aren't
+    /// tensors
+    pub onnx_type: String,
+    /// Dynamic dimensions are `None`
+    pub dimensions: Vec<Option<usize>>,
+}
+
+/// `Session` is an onnxruntime session driven through the C API.
+///
+/// The onnxruntime crate runs a session with one element type shared by every input and
+/// another shared by every output. Models such as MaskRCNN mix float and integer outputs,
+/// so each input is handed over and each output read back with its own element type.
+///
+pub struct Session {
+    ptr: *mut sys::OrtSession,
+    /// Describes the memory of the input tensors, which stay owned by us
+    memory_info: *mut sys::OrtMemoryInfo,
+    pub inputs: Vec<TensorSpec>,
+    pub outputs: Vec<TensorSpec>,
+    input_names: Vec<CString>,
+    output_names: Vec<CString>,
+}
+
+impl Session {
+    pub fn new(path: &str, config: &SessionConfig) -> Result<Self, String> {
+        let options = SessionOptions::new(config)?;
+        let model_path = CString::new(path).map_err(|_| format!("invalid path {:?}", path))?;
+        let mut session = Session {
+            ptr: ptr::null_mut(),
+            memory_info: ptr::null_mut(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            input_names: Vec::new(),
+            output_names: Vec::new(),
+        };
+        call!(
+            CreateSession,
+            ENVIRONMENT.0,
+            model_path.as_ptr(),
+            options.0,
+            &mut session.ptr
+        )?;
+
+        call!(
+            CreateCpuMemoryInfo,
+            sys::OrtAllocatorType::OrtArenaAllocator,
+            sys::OrtMemType::OrtMemTypeDefault,
+            &mut session.memory_info
+        )?;
+
+        session.inputs = session.specs(true)?;
+        session.outputs = session.specs(false)?;
+        session.input_names = c_names(&session.inputs);
+        session.output_names = c_names(&session.outputs);
+        Ok(session)
+    }
+
+    /// Run the session on one tensor per input, in the order of `inputs`. Returns one
+    /// tensor per output, in the order of `outputs`, each with the element type the model
+    /// produced.
+    pub fn run(&mut self, mut inputs: Vec<Tensor>) -> Result<Vec<Tensor>, String> {
+        // Input values point into the tensors, which must outlive them
+        let values = inputs
+            .iter_mut()
+            .map(|tensor| self.input_value(tensor))
+            .collect::<Result<Vec<Value>, String>>()?;
+        let value_ptrs: Vec<*const sys::OrtValue> = values
+            .iter()
+            .map(|value| value.0 as *const sys::OrtValue)
+            .collect();
+        let input_names: Vec<*const c_char> =
+            self.input_names.iter().map(|name| name.as_ptr()).collect();
+        let output_names: Vec<*const c_char> =
+            self.output_names.iter().map(|name| name.as_ptr()).collect();
+
+        let mut output_ptrs = vec![ptr::null_mut(); output_names.len()];
+        call!(
+            Run,
+            self.ptr,
+            ptr::null(),
+            input_names.as_ptr(),
+            value_ptrs.as_ptr(),
+            value_ptrs.len(),
+            output_names.as_ptr(),
+            output_names.len(),
+            output_ptrs.as_mut_ptr()
+        )?;
+        let outputs: Vec<Value> = output_ptrs.into_iter().map(Value).collect();
+        drop(values);
+
+        outputs.iter().map(output_tensor).collect()
+    }
+
+    /// Read the names and types of the inputs, or of the outputs
+    fn specs(&self, inputs: bool) -> Result<Vec<TensorSpec>, String> {
+        let api = api();
+        let (count_fn, name_fn, type_info_fn) = match inputs {
+            true => (
+                api.SessionGetInputCount.unwrap(),
+                api.SessionGetInputName.unwrap(),
+                api.SessionGetInputTypeInfo.unwrap(),
+            ),
+            false => (
+                api.SessionGetOutputCount.unwrap(),
+                api.SessionGetOutputName.unwrap(),
+                api.SessionGetOutputTypeInfo.unwrap(),
+            ),
+        };
+
+        let mut count = 0;
+        status(unsafe { count_fn(self.ptr, &mut count) })?;
+        let allocator = default_allocator()?;
+
+        (0..count)
+            .map(|index| {
+                let mut raw_name = ptr::null_mut();
+                status(unsafe { name_fn(self.ptr, index, allocator, &mut raw_name) })?;
+                let name = unsafe { CStr::from_ptr(raw_name) }
+                    .to_string_lossy()
+                    .into_owned();
+                call!(AllocatorFree, allocator, raw_name as *mut c_void)?;
+
+                let mut type_info = ptr::null_mut();
+                status(unsafe { type_info_fn(self.ptr, index, &mut type_info) })?;
+                let spec = tensor_spec(name, type_info);
+                unsafe { api.ReleaseTypeInfo.unwrap()(type_info) };
+                spec
+            })
+            .collect()
+    }
+
+    fn input_value(&self, tensor: &mut Tensor) -> Result<Value, String> {
+        use sys::ONNXTensorElementDataType::*;
+
+        match tensor {
+            Tensor::Float32(array) => {
+                self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT)
+            }
+            Tensor::Float64(array) => {
+                self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_DOUBLE)
+            }
+            Tensor::Int64(array) => self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_INT64),
+            Tensor::Int32(array) => self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_INT32),
+            Tensor::Uint8(array) => self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_UINT8),
+            Tensor::Float16(array) => {
+                self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT16)
+            }
+            // Rust and onnxruntime both store a bool as one byte holding 0 or 1
+            Tensor::Bool(array) => self.numeric_value(array, ONNX_TENSOR_ELEMENT_DATA_TYPE_BOOL),
+            Tensor::String(array) => string_value(array),
+        }
+    }
+
+    /// Wrap the tensor's buffer in a value without copying it
+    fn numeric_value<T: Clone>(
+        &self,
+        array: &mut ArrayD<T>,
+        element_type: sys::ONNXTensorElementDataType,
+    ) -> Result<Value, String> {
+        if !array.is_standard_layout() {
+            *array = array.as_standard_layout().into_owned();
+        }
+        let shape = shape_i64(array.shape());
+
+        let mut value = Value(ptr::null_mut());
+        call!(
+            CreateTensorWithDataAsOrtValue,
+            self.memory_info,
+            array.as_mut_ptr() as *mut c_void,
+            array.len() * mem::size_of::<T>(),
+            shape.as_ptr(),
+            shape.len(),
+            element_type,
+            &mut value.0
+        )?;
+        Ok(value)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if !self.memory_info.is_null() {
+            unsafe { api().ReleaseMemoryInfo.unwrap()(self.memory_info) };
+        }
+        if !self.ptr.is_null() {
+            unsafe { api().ReleaseSession.unwrap()(self.ptr) };
+        }
+    }
+}
+
+/// Options a session is created with, released once it has been
+struct SessionOptions(*mut sys::OrtSessionOptions);
+
+impl SessionOptions {
+    fn new(config: &SessionConfig) -> Result<Self, String> {
+        let mut options = SessionOptions(ptr::null_mut());
+        call!(CreateSessionOptions, &mut options.0)?;
+
+        let optimization_level = match config.optimization_level.unwrap_or_default() {
+            OptimizationLevel::DisableAll => sys::GraphOptimizationLevel::ORT_DISABLE_ALL,
+            OptimizationLevel::Basic => sys::GraphOptimizationLevel::ORT_ENABLE_BASIC,
+            OptimizationLevel::Extended => sys::GraphOptimizationLevel::ORT_ENABLE_EXTENDED,
+            OptimizationLevel::All => sys::GraphOptimizationLevel::ORT_ENABLE_ALL,
+        };
+        call!(
+            SetSessionGraphOptimizationLevel,
+            options.0,
+            optimization_level
+        )?;
+        let intra_op_threads = config.intra_op_threads.unwrap_or(1) as c_int;
+        call!(SetIntraOpNumThreads, options.0, intra_op_threads)?;
+        match config.memory_arena.unwrap_or(true) {
+            true => call!(EnableCpuMemArena, options.0)?,
+            false => call!(DisableCpuMemArena, options.0)?,
+        }
+        match config.memory_pattern.unwrap_or(true) {
+            true => call!(EnableMemPattern, options.0)?,
+            false => call!(DisableMemPattern, options.0)?,
+        }
+
+        Ok(options)
+    }
+}
+
+impl Drop for SessionOptions {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { api().ReleaseSessionOptions.unwrap()(self.0) };
+        }
+    }
+}
+
+/// A value owned by us, released on drop
+struct Value(*mut sys::OrtValue);
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { api().ReleaseValue.unwrap()(self.0) };
+        }
+    }
+}
+
+fn default_allocator() -> Result<*mut sys::OrtAllocator, String> {
+    let mut allocator = ptr::null_mut();
+    call!(GetAllocatorWithDefaultOptions, &mut allocator)?;
+    Ok(allocator)
+}
+
+fn c_names(specs: &[TensorSpec]) -> Vec<CString> {
+    specs
+        .iter()
+        .map(|spec| CString::new(spec.name.as_str()).unwrap())
+        .collect()
+}
+
+fn shape_i64(shape: &[usize]) -> Vec<i64> {
+    shape.iter().map(|&dim| dim as i64).collect()
+}
+
+/// Lowercased onnxruntime name of a type, e.g. `int8` for
+/// `ONNX_TENSOR_ELEMENT_DATA_TYPE_INT8`
+fn type_name(onnx_type: impl Debug, prefix: &str) -> String {
+    format!("{:?}", onnx_type)
+        .trim_start_matches(prefix)
+        .to_lowercase()
+}
+
+fn element_type_name(element_type: sys::ONNXTensorElementDataType) -> String {
+    type_name(element_type, "ONNX_TENSOR_ELEMENT_DATA_TYPE_")
+}
+
+fn tensor_spec(name: String, type_info: *const sys::OrtTypeInfo) -> Result<TensorSpec, String> {
+    let mut onnx_type = sys::ONNXType::ONNX_TYPE_UNKNOWN;
+    call!(GetOnnxTypeFromTypeInfo, type_info, &mut onnx_type)?;
+    if onnx_type != sys::ONNXType::ONNX_TYPE_TENSOR {
+        return Ok(TensorSpec {
+            name,
+            dtype: None,
+            onnx_type: type_name(onnx_type, "ONNX_TYPE_"),
+            dimensions: Vec::new(),
+        });
+    }
+
+    // Owned by the type info
+    let mut info = ptr::null();
+    call!(CastTypeInfoToTensorInfo, type_info, &mut info)?;
+    let (element_type, dimensions) = element_type_and_shape(info)?;
+
+    Ok(TensorSpec {
+        name,
+        dtype: DataType::from_onnx(element_type),
+        onnx_type: element_type_name(element_type),
+        dimensions: dimensions
+            .into_iter()
+            .map(|dim| (dim >= 0).then_some(dim as usize))
+            .collect(),
+    })
+}
+
+/// Element type and dimensions, with dynamic dimensions as -1
+fn element_type_and_shape(
+    info: *const sys::OrtTensorTypeAndShapeInfo,
+) -> Result<(sys::ONNXTensorElementDataType, Vec<i64>), String> {
+    let mut element_type = sys::ONNXTensorElementDataType::ONNX_TENSOR_ELEMENT_DATA_TYPE_UNDEFINED;
+    call!(GetTensorElementType, info, &mut element_type)?;
+    let mut count = 0;
+    call!(GetDimensionsCount, info, &mut count)?;
+    let mut dimensions = vec![0; count];
+    call!(GetDimensions, info, dimensions.as_mut_ptr(), count)?;
+
+    Ok((element_type, dimensions))
+}
+
+fn string_value(array: &ArrayD<String>) -> Result<Value, String> {
+    let strings = array
+        .iter()
+        .map(|value| CString::new(value.as_str()))
+        .collect::<Result<Vec<CString>, _>>()
+        .map_err(|_| "strings can't contain NUL characters".to_string())?;
+    let string_ptrs: Vec<*const c_char> = strings.iter().map(|value| value.as_ptr()).collect();
+    let shape = shape_i64(array.shape());
+
+    let mut value = Value(ptr::null_mut());
+    call!(
+        CreateTensorAsOrtValue,
+        default_allocator()?,
+        shape.as_ptr(),
+        shape.len(),
+        sys::ONNXTensorElementDataType::ONNX_TENSOR_ELEMENT_DATA_TYPE_STRING,
+        &mut value.0
+    )?;
+    call!(
+        FillStringTensor,
+        value.0,
+        string_ptrs.as_ptr(),
+        string_ptrs.len()
+    )?;
+    Ok(value)
+}
+
+/// Copy an output into a tensor of its own element type
+fn output_tensor(value: &Value) -> Result<Tensor, String> {
+    use sys::ONNXTensorElementDataType::*;
+
+    let mut info = ptr::null_mut();
+    call!(GetTensorTypeAndShape, value.0, &mut info)?;
+    let mut count = 0;
+    let read = element_type_and_shape(info)
+        .and_then(|read| call!(GetTensorShapeElementCount, info, &mut count).map(|_| read));
+    unsafe { api().ReleaseTensorTypeAndShapeInfo.unwrap()(info) };
+    let (element_type, dimensions) = read?;
+    let shape: Vec<usize> = dimensions.into_iter().map(|dim| dim as usize).collect();
+
+    match element_type {
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT => numeric_output::<f32>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_DOUBLE => numeric_output::<f64>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_INT64 => numeric_output::<i64>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_INT32 => numeric_output::<i32>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_UINT8 => numeric_output::<u8>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT16 => numeric_output::<f16>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_BOOL => numeric_output::<bool>(value, shape, count),
+        ONNX_TENSOR_ELEMENT_DATA_TYPE_STRING => string_output(value, shape, count),
+        element_type => Err(format!(
+            "{} outputs are not supported",
+            element_type_name(element_type)
+        )),
+    }
+}
+
+fn numeric_output<T: Element + Clone>(
+    value: &Value,
+    shape: Vec<usize>,
+    count: usize,
+) -> Result<Tensor, String> {
+    let mut data = ptr::null_mut();
+    call!(GetTensorMutableData, value.0, &mut data)?;
+    let data = match count {
+        0 => Vec::new(),
+        count => unsafe { slice::from_raw_parts(data as *const T, count) }.to_vec(),
+    };
+
+    ArrayD::from_shape_vec(shape, data)
+        .map(T::into_tensor)
+        .map_err(|err| err.to_string())
+}
+
+fn string_output(value: &Value, shape: Vec<usize>, count: usize) -> Result<Tensor, String> {
+    let mut length = 0;
+    call!(GetStringTensorDataLength, value.0, &mut length)?;
+    let mut content = vec![0u8; length];
+    let mut offsets = vec![0; count];
+    call!(
+        GetStringTensorContent,
+        value.0,
+        content.as_mut_ptr() as *mut c_void,
+        length,
+        offsets.as_mut_ptr(),
+        count
+    )?;
+
+    ArrayD::from_shape_vec(shape, split_strings(&content, &offsets))
+        .map(Tensor::String)
+        .map_err(|err| err.to_string())
+}
+
+/// Split the concatenated content of a string tensor at the offset where each string starts
+fn split_strings(content: &[u8], offsets: &[usize]) -> Vec<String> {
+    offsets
+        .iter()
+        .enumerate()
+        .map(|(index, &start)| {
+            let end = offsets.get(index + 1).copied().unwrap_or(content.len());
+            String::from_utf8_lossy(&content[start..end]).into_owned()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_strings_at_offsets() {
+        let content = b"catdogmouse";
+        assert_eq!(
+            split_strings(content, &[0, 3, 3, 6]),
+            vec!["cat", "", "dog", "mouse"]
+        );
+        assert_eq!(split_strings(b"", &[]), Vec::<String>::new());
+    }
+}
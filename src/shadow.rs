@@ -0,0 +1,289 @@
+use ndarray::{ArrayD, ArrayView1, Axis};
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::config::ShadowConfig;
+use crate::priority::Priority;
+use crate::state::SharedState;
+use crate::tensor::Tensor;
+use crate::worker::Message;
+
+type Outputs = HashMap<String, Tensor>;
+
+/// A copy of a request sent to a shadow model. Hand it the primary result with
+/// [`ShadowRun::compare`] once the primary has responded.
+#[derive(Debug)]
+pub struct ShadowRun {
+    primary_tx: oneshot::Sender<(Option<Outputs>, Duration)>,
+}
+
+/// Send a copy of the inputs to the shadow of `model_name` if it has one and the request is
+/// sampled. The copy is dropped rather than waiting when the shadow's queue is full, so the
+/// primary request is never held up.
+///
+/// A background task waits for the shadow and records its latency, and how far its outputs
+/// diverge from the primary's, in metrics labelled by model and shadow.
+pub fn mirror(
+    shared_state: &SharedState,
+    prediction_id: Uuid,
+    model_name: &str,
+    inputs: &Outputs,
+) -> Option<ShadowRun> {
+    let config = shared_state.shadows.get(model_name)?;
+    // The fraction was checked to be between 0 and 1 when loading the config
+    if !rand::thread_rng().gen_bool(config.fraction) {
+        return None;
+    }
+    let labels = labels(config);
+
+    let queue = match shared_state
+        .models
+        .queue(&config.shadow_model, config.shadow_version.as_deref())
+    {
+        Ok(queue) => queue,
+        Err(err) => {
+            tracing::warn!("shadow {} unavailable: {}", &config.shadow_model, err);
+            metrics::increment_counter!("proton_shadow_dropped_total", &labels);
+            return None;
+        }
+    };
+
+    let (response_tx, response_rx) = oneshot::channel();
+    let message = Message {
+        prediction_id,
+        model_name: config.shadow_model.clone(),
+        inputs: inputs.clone(),
+        deadline: queue
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout)),
+        // Mirrored traffic shouldn't hold up real requests to the shadow model
+        priority: Priority::Low,
+        enqueued_at: Instant::now(),
+        response_tx,
+    };
+    if queue.try_send(message).is_err() {
+        metrics::increment_counter!("proton_shadow_dropped_total", &labels);
+        return None;
+    }
+    metrics::increment_counter!("proton_shadow_requests_total", &labels);
+
+    let started = Instant::now();
+    let (primary_tx, primary_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let shadow = response_rx.await;
+        let shadow_duration = started.elapsed();
+
+        let shadow = match shadow {
+            Ok(Ok(outputs)) => outputs,
+            Ok(Err(err)) => {
+                tracing::warn!("shadow failed prediction_id={:?}: {}", prediction_id, err);
+                metrics::increment_counter!("proton_shadow_errors_total", &labels);
+                return;
+            }
+            Err(_) => {
+                metrics::increment_counter!("proton_shadow_errors_total", &labels);
+                return;
+            }
+        };
+        record_latency(&labels, "shadow", shadow_duration);
+
+        // The primary result doesn't arrive if the request was cancelled or failed
+        if let Ok((Some(primary), primary_duration)) = primary_rx.await {
+            record_latency(&labels, "primary", primary_duration);
+            record_divergence(&labels, &primary, &shadow);
+        }
+    });
+
+    Some(ShadowRun { primary_tx })
+}
+
+impl ShadowRun {
+    /// Hand over the primary outputs, if the primary succeeded, and how long it took
+    pub fn compare(self, primary: Option<&Outputs>, primary_duration: Duration) {
+        let _ = self.primary_tx.send((primary.cloned(), primary_duration));
+    }
+}
+
+fn labels(config: &ShadowConfig) -> Vec<(&'static str, String)> {
+    vec![
+        ("model", config.model.clone()),
+        ("shadow", config.shadow_model.clone()),
+    ]
+}
+
+fn record_latency(labels: &[(&'static str, String)], role: &'static str, duration: Duration) {
+    let mut labels = labels.to_vec();
+    labels.push(("role", role.to_string()));
+    metrics::histogram!(
+        "proton_shadow_latency_seconds",
+        duration.as_secs_f64(),
+        &labels
+    );
+}
+
+/// How far a shadow output is from the primary output of the same name
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub max_abs_diff: f64,
+    /// Unset for scalar outputs, which have no classes to pick from
+    pub argmax_agreement: Option<f64>,
+}
+
+/// Compare each primary output with the shadow output of the same name
+fn record_divergence(labels: &[(&'static str, String)], primary: &Outputs, shadow: &Outputs) {
+    for (name, primary) in primary {
+        let mut labels = labels.to_vec();
+        labels.push(("output", name.clone()));
+
+        let Some(divergence) = compare(primary, shadow.get(name)) else {
+            metrics::increment_counter!("proton_shadow_mismatch_total", &labels);
+            continue;
+        };
+        metrics::histogram!(
+            "proton_shadow_max_abs_diff",
+            divergence.max_abs_diff,
+            &labels
+        );
+        if let Some(agreement) = divergence.argmax_agreement {
+            metrics::histogram!("proton_shadow_argmax_agreement", agreement, &labels);
+        }
+    }
+}
+
+/// Compare a primary output with the shadow's. Returns `None` if the shadow didn't produce
+/// the output, or the two can't be compared because their shapes differ or they hold
+/// strings.
+pub(crate) fn compare(primary: &Tensor, shadow: Option<&Tensor>) -> Option<Divergence> {
+    let (primary, shadow) = (primary.to_f64()?, shadow?.to_f64()?);
+    if primary.shape() != shadow.shape() {
+        return None;
+    }
+
+    Some(Divergence {
+        max_abs_diff: max_abs_diff(&primary, &shadow),
+        argmax_agreement: argmax_agreement(&primary, &shadow),
+    })
+}
+
+/// Largest difference between values at the same position. A NaN on one side only counts
+/// as an infinite difference rather than being skipped.
+pub(crate) fn max_abs_diff(primary: &ArrayD<f64>, shadow: &ArrayD<f64>) -> f64 {
+    primary
+        .iter()
+        .zip(shadow.iter())
+        .map(|(a, b)| match (a.is_nan(), b.is_nan()) {
+            (true, true) => 0.0,
+            (true, false) | (false, true) => f64::INFINITY,
+            // Equal infinities would give NaN
+            (false, false) if a == b => 0.0,
+            (false, false) => (a - b).abs(),
+        })
+        .fold(0.0, f64::max)
+}
+
+/// Fraction of rows, taken along the last axis, where both outputs pick the same class
+pub(crate) fn argmax_agreement(primary: &ArrayD<f64>, shadow: &ArrayD<f64>) -> Option<f64> {
+    let axis = Axis(primary.ndim().checked_sub(1)?);
+    let rows = primary.lanes(axis).into_iter().zip(shadow.lanes(axis));
+
+    let (total, agreed) = rows.fold((0, 0), |(total, agreed), (a, b)| {
+        (total + 1, agreed + (argmax(a) == argmax(b)) as usize)
+    });
+    (total > 0).then(|| agreed as f64 / total as f64)
+}
+
+pub(crate) fn argmax(row: ArrayView1<f64>) -> Option<usize> {
+    row.iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr1, arr2};
+
+    fn floats(rows: &[[f32; 3]]) -> Tensor {
+        Tensor::Float32(arr2(rows).into_dyn())
+    }
+
+    #[test]
+    fn measures_the_largest_difference() {
+        let primary = floats(&[[0.1, 0.7, 0.2], [0.5, 0.3, 0.2]]);
+        let shadow = floats(&[[0.2, 0.6, 0.2], [0.2, 0.6, 0.2]]);
+
+        let divergence = compare(&primary, Some(&shadow)).unwrap();
+        assert!((divergence.max_abs_diff - 0.3).abs() < 1e-6);
+        assert_eq!(divergence.argmax_agreement, Some(0.5));
+
+        let same = compare(&primary, Some(&primary)).unwrap();
+        assert_eq!(same.max_abs_diff, 0.0);
+        assert_eq!(same.argmax_agreement, Some(1.0));
+    }
+
+    #[test]
+    fn compares_integer_and_boolean_outputs_as_numbers() {
+        let primary = Tensor::Int64(arr2(&[[1, 5, 2]]).into_dyn());
+        let shadow = Tensor::Int32(arr2(&[[1, 2, 4]]).into_dyn());
+        let divergence = compare(&primary, Some(&shadow)).unwrap();
+        assert_eq!(divergence.max_abs_diff, 3.0);
+        assert_eq!(divergence.argmax_agreement, Some(0.0));
+
+        let primary = Tensor::Bool(arr2(&[[false, true], [true, false]]).into_dyn());
+        let shadow = Tensor::Bool(arr2(&[[false, true], [false, true]]).into_dyn());
+        let divergence = compare(&primary, Some(&shadow)).unwrap();
+        assert_eq!(divergence.max_abs_diff, 1.0);
+        assert_eq!(divergence.argmax_agreement, Some(0.5));
+    }
+
+    #[test]
+    fn scalars_have_no_argmax_agreement() {
+        let primary = Tensor::Float64(ArrayD::from_elem(vec![], 1.0));
+        let shadow = Tensor::Float64(ArrayD::from_elem(vec![], 1.5));
+        let divergence = compare(&primary, Some(&shadow)).unwrap();
+        assert_eq!(divergence.max_abs_diff, 0.5);
+        assert_eq!(divergence.argmax_agreement, None);
+    }
+
+    #[test]
+    fn outputs_that_cant_be_compared_are_mismatches() {
+        let primary = floats(&[[0.1, 0.7, 0.2]]);
+        assert_eq!(compare(&primary, None), None);
+
+        let wider = Tensor::Float32(arr2(&[[0.1, 0.7, 0.1, 0.1]]).into_dyn());
+        assert_eq!(compare(&primary, Some(&wider)), None);
+        let flat = Tensor::Float32(arr1(&[0.1, 0.7, 0.2]).into_dyn());
+        assert_eq!(compare(&primary, Some(&flat)), None);
+
+        let strings = Tensor::String(arr1(&["a".to_string()]).into_dyn());
+        assert_eq!(compare(&strings, Some(&strings)), None);
+        let labels = ["a", "b", "c"].map(String::from);
+        let labels = Tensor::String(arr2(&[labels]).into_dyn());
+        assert_eq!(compare(&primary, Some(&labels)), None);
+    }
+
+    #[test]
+    fn nan_on_one_side_is_an_infinite_difference() {
+        let primary = arr1(&[1.0, f64::NAN, 3.0]).into_dyn();
+        let shadow = arr1(&[1.0, 2.0, 3.0]).into_dyn();
+        assert_eq!(max_abs_diff(&primary, &shadow), f64::INFINITY);
+        assert_eq!(max_abs_diff(&shadow, &primary), f64::INFINITY);
+
+        let both = arr1(&[f64::NAN, 1.0, f64::INFINITY]).into_dyn();
+        assert_eq!(max_abs_diff(&both, &both), 0.0);
+    }
+
+    #[test]
+    fn argmax_agreement_counts_rows_picking_the_same_class() {
+        let primary = arr2(&[[0.1, 0.9], [0.8, 0.2], [0.4, 0.6], [0.3, f64::NAN]]).into_dyn();
+        let shadow = arr2(&[[0.2, 0.8], [0.1, 0.9], [0.4, 0.6], [0.7, 0.3]]).into_dyn();
+        assert_eq!(argmax_agreement(&primary, &shadow), Some(0.5));
+
+        let empty = ArrayD::<f64>::zeros(vec![0, 2]);
+        assert_eq!(argmax_agreement(&empty, &empty), None);
+    }
+}
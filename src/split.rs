@@ -0,0 +1,111 @@
+use rand::Rng;
+
+use crate::config::{SplitConfig, VariantConfig};
+
+/// `TrafficSplit` picks which variant of a logical model serves a request.
+///
+/// Variants get a share of requests proportional to their weight. Requests carrying a
+/// routing key, such as a hash of the user ID, are assigned by hashing the key so the same
+/// key keeps getting the same variant as long as the weights don't change. Other requests
+/// are assigned at random.
+///
+#[derive(Clone, Debug)]
+pub struct TrafficSplit {
+    pub config: SplitConfig,
+    total_weight: u64,
+}
+
+impl TrafficSplit {
+    pub fn new(config: SplitConfig) -> Self {
+        let total_weight = config
+            .variants
+            .iter()
+            .map(|variant| variant.weight as u64)
+            .sum();
+
+        Self {
+            config,
+            total_weight,
+        }
+    }
+
+    pub fn choose(&self, routing_key: Option<&str>) -> &VariantConfig {
+        let point = match routing_key {
+            Some(key) => fnv1a(key.as_bytes()) % self.total_weight,
+            None => rand::thread_rng().gen_range(0..self.total_weight),
+        };
+
+        let mut upper = 0;
+        for variant in self.config.variants.iter() {
+            upper += variant.weight as u64;
+            if point < upper {
+                return variant;
+            }
+        }
+        unreachable!("point is below the total weight")
+    }
+}
+
+/// 64 bit FNV-1a. Unlike the std hasher its output is fixed, so assignments survive
+/// restarts and upgrades.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(weights: &[u32]) -> TrafficSplit {
+        let variants = weights
+            .iter()
+            .enumerate()
+            .map(|(index, &weight)| VariantConfig {
+                name: format!("variant-{}", index),
+                model: "squeezenet".to_string(),
+                version: None,
+                weight,
+            })
+            .collect();
+
+        TrafficSplit::new(SplitConfig {
+            name: "squeezenet".to_string(),
+            variants,
+        })
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn routing_key_sticks_to_a_variant() {
+        let split = split(&[50, 50]);
+        for user in 0..100 {
+            let key = format!("user-{}", user);
+            let first = &split.choose(Some(&key)).name;
+            assert!((0..10).all(|_| &split.choose(Some(&key)).name == first));
+        }
+    }
+
+    #[test]
+    fn routing_keys_follow_the_weights() {
+        let split = split(&[90, 10]);
+        let first = (0..10_000)
+            .filter(|user| split.choose(Some(&format!("user-{}", user))).name == "variant-0")
+            .count();
+        assert!((8_500..9_500).contains(&first), "{} of 10000", first);
+    }
+
+    #[test]
+    fn zero_weight_variant_is_never_chosen() {
+        let split = split(&[0, 1]);
+        assert!((0..1_000).all(|_| split.choose(None).name == "variant-1"));
+        assert_eq!(split.choose(Some("user-1")).name, "variant-1");
+    }
+}
@@ -1,26 +1,112 @@
 use std::collections::HashMap;
-use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
 
-use crate::config::Config;
+use crate::bandit::Bandit;
+use crate::canary::Canary;
+use crate::cascade::Cascade;
+use crate::config::{Config, ShadowConfig};
+use crate::ensemble::Ensemble;
+use crate::error::Error;
+use crate::jobs::JobStore;
+use crate::pipeline::Pipeline;
+use crate::registry::ModelRegistry;
+use crate::rules::RoutingRules;
+use crate::split::TrafficSplit;
 
 #[derive(Debug)]
 pub struct SharedState {
     pub config: Config,
-    pub ready: HashMap<String, AtomicBool>,
+    /// Models being served, their queues and readiness
+    pub models: ModelRegistry,
+    /// Traffic splits keyed by the logical model name they serve
+    pub splits: HashMap<String, TrafficSplit>,
+    /// Shadows keyed by the model whose requests they mirror
+    pub shadows: HashMap<String, ShadowConfig>,
+    /// Bandits keyed by the logical model name they serve
+    pub bandits: HashMap<String, Bandit>,
+    /// Pipelines keyed by name
+    pub pipelines: HashMap<String, Pipeline>,
+    /// Ensembles keyed by name
+    pub ensembles: HashMap<String, Ensemble>,
+    /// Cascades keyed by name
+    pub cascades: HashMap<String, Cascade>,
+    /// Routing rules, replaced when they are reloaded
+    pub rules: RwLock<RoutingRules>,
+    /// Canaries keyed by model name, kept after they finish so their history can be read
+    pub canaries: RwLock<HashMap<String, Arc<Canary>>>,
+    /// Predictions submitted through `/jobs`
+    pub jobs: JobStore,
 }
 
 impl SharedState {
     pub fn new(config: Config) -> Self {
-        let ready: HashMap<String, AtomicBool> = config
-            .models
+        let models = ModelRegistry::new(config.server.clone());
+        let splits = config
+            .splits
             .iter()
-            .map(|model_config| {
-                // Initialize models as not ready. InferenceWorker
-                // is responsible for updating these flags once models come online
-                (model_config.name.clone(), AtomicBool::new(false))
+            .map(|split| (split.name.clone(), TrafficSplit::new(split.clone())))
+            .collect();
+        let shadows = config
+            .shadows
+            .iter()
+            .map(|shadow| (shadow.model.clone(), shadow.clone()))
+            .collect();
+        let bandits = config
+            .bandits
+            .iter()
+            .map(|bandit| (bandit.name.clone(), Bandit::new(bandit.clone())))
+            .collect();
+        // Pipelines have already been checked when loading the config
+        let pipelines = config
+            .pipelines
+            .iter()
+            .map(|pipeline| {
+                let name = pipeline.name.clone();
+                (name, Pipeline::new(pipeline.clone()).unwrap())
             })
             .collect();
+        let ensembles = config
+            .ensembles
+            .iter()
+            .map(|ensemble| (ensemble.name.clone(), Ensemble::new(ensemble.clone())))
+            .collect();
+        let cascades = config
+            .cascades
+            .iter()
+            .map(|cascade| (cascade.name.clone(), Cascade::new(cascade.clone())))
+            .collect();
+        let rules = RwLock::new(RoutingRules::new(config.rules.clone()));
+        let jobs = JobStore::new(config.jobs.clone());
+
+        SharedState {
+            config,
+            models,
+            splits,
+            shadows,
+            bandits,
+            pipelines,
+            ensembles,
+            cascades,
+            rules,
+            canaries: RwLock::new(HashMap::new()),
+            jobs,
+        }
+    }
+
+    /// Credit the bandit arm that served a prediction with a reward
+    pub fn feedback(&self, prediction_id: &Uuid, reward: f64) -> Result<(), Error> {
+        let bandit = self
+            .bandits
+            .values()
+            .find(|bandit| bandit.is_pending(prediction_id))
+            .ok_or_else(|| {
+                Error::ModelNotFound(format!(
+                    "prediction {} is unknown or has expired",
+                    prediction_id
+                ))
+            })?;
 
-        SharedState { config, ready }
+        bandit.feedback(prediction_id, reward)
     }
 }
@@ -0,0 +1,277 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+use crate::priority::PriorityQueue;
+use crate::registry::ModelState;
+use crate::worker::{InferenceWorker, WorkerExit};
+
+/// Delay before restarting a worker after its first crash, doubled on each crash after that
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// `Supervisor` keeps one replica of a model running.
+///
+/// The worker runs on its own thread so that a panic only takes down that thread. When the
+/// worker dies the supervisor marks the replica as not ready, counts the crash and starts a
+/// new worker with a fresh session after an exponential backoff. Requests the worker was
+/// handling fail with `UNAVAILABLE` as their response channels are dropped, while queued
+/// requests wait for another replica or the restarted worker.
+///
+/// The supervisor stops once the worker returns because the model's queue was closed, or
+/// after a crash once the model is being unloaded.
+///
+/// Delay before each restart, growing with consecutive crashes
+#[derive(Debug)]
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            next: INITIAL_BACKOFF,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.next = INITIAL_BACKOFF;
+    }
+
+    /// Delay before restarting a worker that crashed after running for `uptime`
+    fn after_crash(&mut self, uptime: Duration) -> Duration {
+        // A worker that stayed up for a while is treated as a fresh start
+        if uptime > MAX_BACKOFF {
+            self.reset();
+        }
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+pub struct Supervisor {
+    model: Arc<ModelState>,
+    replica: usize,
+    runtime: Handle,
+}
+
+impl Supervisor {
+    /// Must be called from within the tokio runtime, which is handed to each worker
+    pub fn new(model: Arc<ModelState>, replica: usize) -> Self {
+        Self {
+            model,
+            replica,
+            runtime: Handle::current(),
+        }
+    }
+
+    pub fn run(self, requests_rx: Arc<Mutex<PriorityQueue>>) {
+        self.supervise(requests_rx, |mut worker, requests_rx| {
+            worker.run(requests_rx)
+        });
+    }
+
+    /// Keep running `work` with a fresh worker on its own thread, see [`Supervisor`]
+    fn supervise<F>(self, requests_rx: Arc<Mutex<PriorityQueue>>, work: F)
+    where
+        F: Fn(InferenceWorker, Arc<Mutex<PriorityQueue>>) -> WorkerExit + Clone + Send + 'static,
+    {
+        let mut backoff = Backoff::new();
+
+        loop {
+            let worker =
+                InferenceWorker::new(Arc::clone(&self.model), self.replica, self.runtime.clone());
+            let requests_rx = Arc::clone(&requests_rx);
+            let work = work.clone();
+
+            let started = Instant::now();
+            let handle = thread::Builder::new()
+                .name(format!(
+                    "{}-{}-{}",
+                    self.model.config.name, self.model.config.version, self.replica
+                ))
+                .spawn(move || work(worker, requests_rx))
+                .unwrap();
+            let exit = handle.join();
+            self.set_ready(false);
+
+            match exit {
+                Ok(WorkerExit::QueueClosed) => return,
+                Ok(WorkerExit::Recycled) => {
+                    tracing::info!(
+                        "{:?} replica {} recycling worker",
+                        &self.model.config.name,
+                        self.replica
+                    );
+                    metrics::increment_counter!("proton_worker_recycles_total", &self.labels());
+                    backoff.reset();
+                }
+                Err(_) => {
+                    self.model.crashes.fetch_add(1, Ordering::Relaxed);
+                    metrics::increment_counter!("proton_worker_crashes_total", &self.labels());
+                    if self.model.stopping.load(Ordering::Relaxed) {
+                        return;
+                    }
+
+                    let delay = backoff.after_crash(started.elapsed());
+                    tracing::error!(
+                        "{:?} replica {} worker crashed, restarting in {:?}",
+                        &self.model.config.name,
+                        self.replica,
+                        delay
+                    );
+                    thread::sleep(delay);
+                    if self.model.stopping.load(Ordering::Relaxed) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_ready(&self, ready: bool) {
+        self.model.ready[self.replica].store(ready, Ordering::Relaxed);
+        metrics::gauge!(
+            "proton_worker_ready",
+            if ready { 1.0 } else { 0.0 },
+            &self.labels()
+        );
+    }
+
+    /// Metric labels identifying this replica
+    fn labels(&self) -> [(&'static str, String); 3] {
+        [
+            ("model", self.model.config.name.clone()),
+            ("version", self.model.config.version.clone()),
+            ("replica", self.replica.to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModelConfig, PriorityConfig};
+    use crate::priority::Priority;
+    use crate::worker::Message;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc::channel;
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    #[test]
+    fn backoff_doubles_up_to_its_cap() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<Duration> = (0..12)
+            .map(|_| backoff.after_crash(Duration::ZERO))
+            .collect();
+
+        assert_eq!(delays[0], INITIAL_BACKOFF);
+        assert_eq!(delays[1], INITIAL_BACKOFF * 2);
+        assert_eq!(delays[2], INITIAL_BACKOFF * 4);
+        assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(delays[10], MAX_BACKOFF);
+        assert_eq!(delays[11], MAX_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_starts_over_after_a_long_run_or_a_recycle() {
+        let mut backoff = Backoff::new();
+        backoff.after_crash(Duration::ZERO);
+        backoff.after_crash(Duration::ZERO);
+        assert_eq!(backoff.after_crash(MAX_BACKOFF * 2), INITIAL_BACKOFF);
+
+        backoff.after_crash(Duration::ZERO);
+        backoff.reset();
+        assert_eq!(backoff.after_crash(Duration::ZERO), INITIAL_BACKOFF);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restarts_a_crashed_worker_until_the_queue_closes() {
+        let config: ModelConfig =
+            serde_yaml::from_str("{name: crashy, path: crashy.onnx}").unwrap();
+        let model = Arc::new(ModelState::new(config));
+        let (queue_tx, queue_rx) = channel(2);
+        let requests_rx = Arc::new(Mutex::new(PriorityQueue::new(
+            queue_rx,
+            PriorityConfig::default(),
+            2,
+        )));
+
+        let (response_tx, response_rx) = oneshot::channel();
+        queue_tx
+            .try_send(Message {
+                prediction_id: Uuid::new_v4(),
+                model_name: "crashy".to_string(),
+                inputs: HashMap::new(),
+                deadline: None,
+                priority: Priority::Normal,
+                enqueued_at: Instant::now(),
+                response_tx,
+            })
+            .unwrap();
+
+        // Readiness each worker saw when it started
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let work = {
+            let model = Arc::clone(&model);
+            let starts = Arc::clone(&starts);
+            move |_: InferenceWorker, requests_rx: Arc<Mutex<PriorityQueue>>| {
+                let started = {
+                    let mut starts = starts.lock().unwrap();
+                    starts.push(model.ready[0].load(Ordering::Relaxed));
+                    starts.len()
+                };
+                model.ready[0].store(true, Ordering::Relaxed);
+                match started {
+                    1 => {
+                        // Crash while holding a request
+                        let _message = requests_rx.lock().unwrap().try_recv().unwrap();
+                        panic!("worker crashed mid request");
+                    }
+                    2 => panic!("worker crashed again"),
+                    _ => WorkerExit::QueueClosed,
+                }
+            }
+        };
+
+        let supervisor = Supervisor::new(Arc::clone(&model), 0);
+        let started = Instant::now();
+        tokio::task::spawn_blocking(move || supervisor.supervise(requests_rx, work))
+            .await
+            .unwrap();
+
+        assert_eq!(*starts.lock().unwrap(), vec![false, false, false]);
+        assert_eq!(model.crashes.load(Ordering::Relaxed), 2);
+        assert!(!model.ready[0].load(Ordering::Relaxed));
+        assert!(started.elapsed() >= INITIAL_BACKOFF * 3);
+        // The request's sender was dropped with the crashed worker, see `dispatch::send`
+        assert!(response_rx.await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_restarting_once_the_model_is_stopping() {
+        let config: ModelConfig =
+            serde_yaml::from_str("{name: crashy, path: crashy.onnx}").unwrap();
+        let model = Arc::new(ModelState::new(config));
+        let (_queue_tx, queue_rx) = channel(1);
+        let requests_rx = Arc::new(Mutex::new(PriorityQueue::new(
+            queue_rx,
+            PriorityConfig::default(),
+            1,
+        )));
+        model.stopping.store(true, Ordering::Relaxed);
+
+        let supervisor = Supervisor::new(Arc::clone(&model), 0);
+        tokio::task::spawn_blocking(move || {
+            supervisor.supervise(requests_rx, |_, _| panic!("worker crashed"))
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(model.crashes.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,505 @@
+use half::f16;
+use ndarray::{ArrayD, Axis, Slice};
+use onnxruntime_sys::ONNXTensorElementDataType;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Element types a tensor can carry over REST and gRPC
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    Float32,
+    Float64,
+    Float16,
+    Int64,
+    Int32,
+    Uint8,
+    Bool,
+    String,
+}
+
+impl DataType {
+    /// Map the element type reported by an onnxruntime session to our data type. Returns
+    /// `None` for element types we can't represent.
+    pub fn from_onnx(element_type: ONNXTensorElementDataType) -> Option<Self> {
+        use ONNXTensorElementDataType::*;
+
+        match element_type {
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT => Some(DataType::Float32),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_DOUBLE => Some(DataType::Float64),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_FLOAT16 => Some(DataType::Float16),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_INT64 => Some(DataType::Int64),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_INT32 => Some(DataType::Int32),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_UINT8 => Some(DataType::Uint8),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_BOOL => Some(DataType::Bool),
+            ONNX_TENSOR_ELEMENT_DATA_TYPE_STRING => Some(DataType::String),
+            _ => None,
+        }
+    }
+
+    /// Whether values of this type can be converted to `target` without losing information
+    pub fn can_cast_to(self, target: DataType) -> bool {
+        use DataType::*;
+
+        match (self, target) {
+            (source, target) if source == target => true,
+            (Bool, Uint8 | Int32 | Int64) => true,
+            (Uint8, Int32 | Int64 | Float16 | Float32 | Float64) => true,
+            (Int32, Int64 | Float64) => true,
+            (Float16, Float32 | Float64) => true,
+            (Float32, Float64) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DataType::Float32 => "float32",
+            DataType::Float64 => "float64",
+            DataType::Float16 => "float16",
+            DataType::Int64 => "int64",
+            DataType::Int32 => "int32",
+            DataType::Uint8 => "uint8",
+            DataType::Bool => "bool",
+            DataType::String => "string",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A dense n-dimensional array tagged with its element type.
+///
+/// Serialized as the ndarray representation with an extra `dtype` field, e.g.
+/// `{"dtype": "int64", "v": 1, "dim": [1, 3], "data": [101, 2023, 102]}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "dtype", rename_all = "lowercase")]
+pub enum Tensor {
+    Float32(ArrayD<f32>),
+    Float64(ArrayD<f64>),
+    Float16(#[serde(with = "float16_array")] ArrayD<f16>),
+    Int64(ArrayD<i64>),
+    Int32(ArrayD<i32>),
+    Uint8(ArrayD<u8>),
+    Bool(ArrayD<bool>),
+    String(ArrayD<String>),
+}
+
+/// Serialize float16 values as regular JSON numbers rather than their bit patterns
+mod float16_array {
+    use half::f16;
+    use ndarray::ArrayD;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(array: &ArrayD<f16>, serializer: S) -> Result<S::Ok, S::Error> {
+        array.mapv(f32::from).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ArrayD<f16>, D::Error> {
+        let array = ArrayD::<f32>::deserialize(deserializer)?;
+        Ok(array.mapv(f16::from_f32))
+    }
+}
+
+impl Tensor {
+    pub fn dtype(&self) -> DataType {
+        match self {
+            Tensor::Float32(_) => DataType::Float32,
+            Tensor::Float64(_) => DataType::Float64,
+            Tensor::Float16(_) => DataType::Float16,
+            Tensor::Int64(_) => DataType::Int64,
+            Tensor::Int32(_) => DataType::Int32,
+            Tensor::Uint8(_) => DataType::Uint8,
+            Tensor::Bool(_) => DataType::Bool,
+            Tensor::String(_) => DataType::String,
+        }
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        match self {
+            Tensor::Float32(array) => array.shape(),
+            Tensor::Float64(array) => array.shape(),
+            Tensor::Float16(array) => array.shape(),
+            Tensor::Int64(array) => array.shape(),
+            Tensor::Int32(array) => array.shape(),
+            Tensor::Uint8(array) => array.shape(),
+            Tensor::Bool(array) => array.shape(),
+            Tensor::String(array) => array.shape(),
+        }
+    }
+
+    /// Numeric values of the tensor as f64, for comparing outputs. Booleans become 0 or 1
+    /// and integers may lose precision. Returns `None` for strings.
+    pub fn to_f64(&self) -> Option<ArrayD<f64>> {
+        let array = match self {
+            Tensor::Float32(array) => array.mapv(f64::from),
+            Tensor::Float64(array) => array.clone(),
+            Tensor::Float16(array) => array.mapv(f64::from),
+            Tensor::Int64(array) => array.mapv(|value| value as f64),
+            Tensor::Int32(array) => array.mapv(f64::from),
+            Tensor::Uint8(array) => array.mapv(f64::from),
+            Tensor::Bool(array) => array.mapv(|value| value as u8 as f64),
+            Tensor::String(_) => return None,
+        };
+        Some(array)
+    }
+
+    /// Convert the tensor to `target`. Only lossless conversions are supported, see
+    /// [`DataType::can_cast_to`].
+    pub fn cast(self, target: DataType) -> Option<Tensor> {
+        let tensor = match (self, target) {
+            (tensor, target) if tensor.dtype() == target => tensor,
+            (Tensor::Bool(a), DataType::Uint8) => Tensor::Uint8(a.mapv(u8::from)),
+            (Tensor::Bool(a), DataType::Int32) => Tensor::Int32(a.mapv(i32::from)),
+            (Tensor::Bool(a), DataType::Int64) => Tensor::Int64(a.mapv(i64::from)),
+            (Tensor::Uint8(a), DataType::Int32) => Tensor::Int32(a.mapv(i32::from)),
+            (Tensor::Uint8(a), DataType::Int64) => Tensor::Int64(a.mapv(i64::from)),
+            (Tensor::Uint8(a), DataType::Float16) => Tensor::Float16(a.mapv(f16::from)),
+            (Tensor::Uint8(a), DataType::Float32) => Tensor::Float32(a.mapv(f32::from)),
+            (Tensor::Uint8(a), DataType::Float64) => Tensor::Float64(a.mapv(f64::from)),
+            (Tensor::Int32(a), DataType::Int64) => Tensor::Int64(a.mapv(i64::from)),
+            (Tensor::Int32(a), DataType::Float64) => Tensor::Float64(a.mapv(f64::from)),
+            (Tensor::Float16(a), DataType::Float32) => Tensor::Float32(a.mapv(f32::from)),
+            (Tensor::Float16(a), DataType::Float64) => Tensor::Float64(a.mapv(f64::from)),
+            (Tensor::Float32(a), DataType::Float64) => Tensor::Float64(a.mapv(f64::from)),
+            _ => return None,
+        };
+
+        Some(tensor)
+    }
+
+    /// Stack tensors of the same dtype along the first axis. Returns `None` if the dtypes
+    /// or the remaining dimensions differ.
+    pub fn concatenate(tensors: Vec<Tensor>) -> Option<Tensor> {
+        match tensors.first()?.dtype() {
+            DataType::Float32 => concatenate_arrays::<f32>(tensors),
+            DataType::Float64 => concatenate_arrays::<f64>(tensors),
+            DataType::Float16 => concatenate_arrays::<f16>(tensors),
+            DataType::Int64 => concatenate_arrays::<i64>(tensors),
+            DataType::Int32 => concatenate_arrays::<i32>(tensors),
+            DataType::Uint8 => concatenate_arrays::<u8>(tensors),
+            DataType::Bool => concatenate_arrays::<bool>(tensors),
+            DataType::String => concatenate_arrays::<String>(tensors),
+        }
+    }
+
+    /// Split the tensor along the first axis into consecutive parts of the given sizes.
+    /// Returns `None` if the sizes don't add up to the length of the first axis.
+    pub fn split(self, sizes: &[usize]) -> Option<Vec<Tensor>> {
+        if self.shape().first() != Some(&sizes.iter().sum()) {
+            return None;
+        }
+
+        let parts = match self {
+            Tensor::Float32(array) => split_array(array, sizes),
+            Tensor::Float64(array) => split_array(array, sizes),
+            Tensor::Float16(array) => split_array(array, sizes),
+            Tensor::Int64(array) => split_array(array, sizes),
+            Tensor::Int32(array) => split_array(array, sizes),
+            Tensor::Uint8(array) => split_array(array, sizes),
+            Tensor::Bool(array) => split_array(array, sizes),
+            Tensor::String(array) => split_array(array, sizes),
+        };
+
+        Some(parts)
+    }
+}
+
+fn concatenate_arrays<A: Element + Clone>(tensors: Vec<Tensor>) -> Option<Tensor> {
+    let arrays = tensors
+        .into_iter()
+        .map(A::from_tensor)
+        .collect::<Option<Vec<ArrayD<A>>>>()?;
+    let first = arrays.first().filter(|array| array.ndim() > 0)?;
+    // `ndarray::concatenate` needs `Copy` elements, which strings aren't
+    if arrays
+        .iter()
+        .any(|array| array.ndim() != first.ndim() || array.shape()[1..] != first.shape()[1..])
+    {
+        return None;
+    }
+
+    let mut shape = first.shape().to_vec();
+    shape[0] = arrays.iter().map(|array| array.shape()[0]).sum();
+    let data = arrays
+        .iter()
+        .flat_map(|array| array.iter().cloned())
+        .collect();
+    ArrayD::from_shape_vec(shape, data).ok().map(A::into_tensor)
+}
+
+fn split_array<A: Element + Clone>(array: ArrayD<A>, sizes: &[usize]) -> Vec<Tensor> {
+    let mut start = 0;
+    sizes
+        .iter()
+        .map(|&size| {
+            let part = array.slice_axis(Axis(0), Slice::from(start..start + size));
+            start += size;
+            A::into_tensor(part.to_owned())
+        })
+        .collect()
+}
+
+/// Rust element types that can be moved in and out of a [`Tensor`]
+pub trait Element: Sized {
+    fn from_tensor(tensor: Tensor) -> Option<ArrayD<Self>>;
+
+    fn into_tensor(array: ArrayD<Self>) -> Tensor;
+}
+
+macro_rules! impl_element {
+    ($type:ty, $variant:ident) => {
+        impl Element for $type {
+            fn from_tensor(tensor: Tensor) -> Option<ArrayD<Self>> {
+                match tensor {
+                    Tensor::$variant(array) => Some(array),
+                    _ => None,
+                }
+            }
+
+            fn into_tensor(array: ArrayD<Self>) -> Tensor {
+                Tensor::$variant(array)
+            }
+        }
+    };
+}
+
+impl_element!(f32, Float32);
+impl_element!(f64, Float64);
+impl_element!(f16, Float16);
+impl_element!(i64, Int64);
+impl_element!(i32, Int32);
+impl_element!(u8, Uint8);
+impl_element!(bool, Bool);
+impl_element!(String, String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{arr1, arr2};
+    use serde_json::json;
+
+    #[test]
+    fn casts_only_without_losing_information() {
+        let tensor = Tensor::Bool(arr1(&[true, false]).into_dyn());
+        let Some(Tensor::Int64(array)) = tensor.cast(DataType::Int64) else {
+            panic!("bool should cast to int64");
+        };
+        assert_eq!(array, arr1(&[1, 0]).into_dyn());
+
+        let tensor = Tensor::Float16(arr1(&[f16::from_f32(0.5)]).into_dyn());
+        let Some(Tensor::Float32(array)) = tensor.cast(DataType::Float32) else {
+            panic!("float16 should cast to float32");
+        };
+        assert_eq!(array, arr1(&[0.5]).into_dyn());
+
+        let tensor = Tensor::Int32(arr1(&[7]).into_dyn());
+        assert_eq!(
+            tensor.clone().cast(DataType::Int32).unwrap().dtype(),
+            DataType::Int32
+        );
+        assert!(tensor.clone().cast(DataType::Float32).is_none());
+        assert!(tensor.cast(DataType::Uint8).is_none());
+
+        let tensor = Tensor::String(arr1(&["1".to_string()]).into_dyn());
+        assert!(tensor.cast(DataType::Int64).is_none());
+    }
+
+    #[test]
+    fn cast_agrees_with_can_cast_to() {
+        let dtypes = [
+            DataType::Float32,
+            DataType::Float64,
+            DataType::Float16,
+            DataType::Int64,
+            DataType::Int32,
+            DataType::Uint8,
+            DataType::Bool,
+            DataType::String,
+        ];
+        let tensors = [
+            Tensor::Float32(arr1(&[1.0]).into_dyn()),
+            Tensor::Float64(arr1(&[1.0]).into_dyn()),
+            Tensor::Float16(arr1(&[f16::ONE]).into_dyn()),
+            Tensor::Int64(arr1(&[1]).into_dyn()),
+            Tensor::Int32(arr1(&[1]).into_dyn()),
+            Tensor::Uint8(arr1(&[1]).into_dyn()),
+            Tensor::Bool(arr1(&[true]).into_dyn()),
+            Tensor::String(arr1(&["1".to_string()]).into_dyn()),
+        ];
+        for tensor in tensors {
+            for target in dtypes {
+                let source = tensor.dtype();
+                let cast = tensor.clone().cast(target);
+                assert_eq!(
+                    cast.is_some(),
+                    source.can_cast_to(target),
+                    "{} to {}",
+                    source,
+                    target
+                );
+                if let Some(cast) = cast {
+                    assert_eq!(cast.dtype(), target);
+                    assert_eq!(cast.shape(), &[1]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn concatenates_along_the_first_axis() {
+        let tensors = vec![
+            Tensor::Int64(arr2(&[[1, 2]]).into_dyn()),
+            Tensor::Int64(arr2(&[[3, 4], [5, 6]]).into_dyn()),
+        ];
+        let Some(Tensor::Int64(array)) = Tensor::concatenate(tensors) else {
+            panic!("tensors should concatenate");
+        };
+        assert_eq!(array, arr2(&[[1, 2], [3, 4], [5, 6]]).into_dyn());
+
+        let strings = vec![
+            Tensor::String(arr1(&["a".to_string()]).into_dyn()),
+            Tensor::String(arr1(&["b".to_string(), "c".to_string()]).into_dyn()),
+        ];
+        let Some(Tensor::String(array)) = Tensor::concatenate(strings) else {
+            panic!("strings should concatenate");
+        };
+        assert_eq!(array.iter().collect::<Vec<_>>(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn concatenate_rejects_mismatched_tensors() {
+        assert!(Tensor::concatenate(vec![]).is_none());
+
+        let dtypes = vec![
+            Tensor::Int64(arr1(&[1]).into_dyn()),
+            Tensor::Int32(arr1(&[2]).into_dyn()),
+        ];
+        assert!(Tensor::concatenate(dtypes).is_none());
+
+        let shapes = vec![
+            Tensor::Float32(arr2(&[[1.0, 2.0]]).into_dyn()),
+            Tensor::Float32(arr2(&[[3.0, 4.0, 5.0]]).into_dyn()),
+        ];
+        assert!(Tensor::concatenate(shapes).is_none());
+
+        let ranks = vec![
+            Tensor::Float32(arr1(&[1.0, 2.0]).into_dyn()),
+            Tensor::Float32(arr2(&[[3.0, 4.0]]).into_dyn()),
+        ];
+        assert!(Tensor::concatenate(ranks).is_none());
+
+        let scalars = vec![Tensor::Float32(ArrayD::from_elem(vec![], 1.0))];
+        assert!(Tensor::concatenate(scalars).is_none());
+    }
+
+    #[test]
+    fn splits_into_parts_of_the_given_sizes() {
+        let tensor = Tensor::Int32(arr2(&[[1, 2], [3, 4], [5, 6]]).into_dyn());
+        let parts = tensor.split(&[1, 0, 2]).unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0].shape(), &[1, 2]);
+        assert_eq!(parts[1].shape(), &[0, 2]);
+        let Tensor::Int32(last) = &parts[2] else {
+            panic!("parts should keep their dtype");
+        };
+        assert_eq!(last, &arr2(&[[3, 4], [5, 6]]).into_dyn());
+
+        let strings = Tensor::String(arr1(&["a".to_string(), "b".to_string()]).into_dyn());
+        let parts = strings.split(&[1, 1]).unwrap();
+        let Tensor::String(second) = &parts[1] else {
+            panic!("parts should keep their dtype");
+        };
+        assert_eq!(second.iter().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn split_rejects_sizes_not_adding_up() {
+        let tensor = Tensor::Float32(arr1(&[1.0, 2.0, 3.0]).into_dyn());
+        assert!(tensor.clone().split(&[1, 1]).is_none());
+        assert!(tensor.split(&[2, 2]).is_none());
+
+        let scalar = Tensor::Float32(ArrayD::from_elem(vec![], 1.0));
+        assert!(scalar.split(&[1]).is_none());
+    }
+
+    #[test]
+    fn elements_move_in_and_out_of_their_own_variant_only() {
+        let array = arr1(&[1_i64, 2]).into_dyn();
+        let tensor = i64::into_tensor(array.clone());
+        assert_eq!(tensor.dtype(), DataType::Int64);
+        assert_eq!(i64::from_tensor(tensor.clone()), Some(array));
+        assert!(i32::from_tensor(tensor.clone()).is_none());
+        assert!(f64::from_tensor(tensor).is_none());
+
+        let tensor = bool::into_tensor(arr1(&[true]).into_dyn());
+        assert_eq!(tensor.dtype(), DataType::Bool);
+        assert!(u8::from_tensor(tensor).is_none());
+
+        let tensor = String::into_tensor(arr1(&["a".to_string()]).into_dyn());
+        assert_eq!(tensor.dtype(), DataType::String);
+        assert!(String::from_tensor(tensor).is_some());
+    }
+
+    #[test]
+    fn serializes_with_a_dtype_tag() {
+        let tensor = Tensor::Int64(arr2(&[[101, 2023, 102]]).into_dyn());
+        assert_eq!(
+            serde_json::to_value(&tensor).unwrap(),
+            json!({"dtype": "int64", "v": 1, "dim": [1, 3], "data": [101, 2023, 102]})
+        );
+
+        let tensor: Tensor =
+            serde_json::from_value(json!({"dtype": "uint8", "v": 1, "dim": [2], "data": [0, 255]}))
+                .unwrap();
+        assert_eq!(tensor.dtype(), DataType::Uint8);
+        assert_eq!(tensor.shape(), &[2]);
+
+        let unknown = json!({"dtype": "complex64", "v": 1, "dim": [1], "data": [1]});
+        assert!(serde_json::from_value::<Tensor>(unknown).is_err());
+        let untagged = json!({"v": 1, "dim": [1], "data": [1.0]});
+        assert!(serde_json::from_value::<Tensor>(untagged).is_err());
+    }
+
+    #[test]
+    fn float16_values_are_json_numbers() {
+        let tensor = Tensor::Float16(arr1(&[f16::from_f32(0.5), f16::from_f32(-2.0)]).into_dyn());
+        assert_eq!(
+            serde_json::to_value(&tensor).unwrap(),
+            json!({"dtype": "float16", "v": 1, "dim": [2], "data": [0.5, -2.0]})
+        );
+
+        let tensor: Tensor =
+            serde_json::from_value(json!({"dtype": "float16", "v": 1, "dim": [1], "data": [1.5]}))
+                .unwrap();
+        let Tensor::Float16(array) = tensor else {
+            panic!("should deserialize as float16");
+        };
+        assert_eq!(array, arr1(&[f16::from_f32(1.5)]).into_dyn());
+    }
+
+    #[test]
+    fn rejects_data_not_matching_the_shape() {
+        let short = json!({"dtype": "float32", "v": 1, "dim": [2, 2], "data": [1.0, 2.0, 3.0]});
+        assert!(serde_json::from_value::<Tensor>(short).is_err());
+        let long = json!({"dtype": "float16", "v": 1, "dim": [1], "data": [1.0, 2.0]});
+        assert!(serde_json::from_value::<Tensor>(long).is_err());
+        let mistyped = json!({"dtype": "int32", "v": 1, "dim": [1], "data": [1.5]});
+        assert!(serde_json::from_value::<Tensor>(mistyped).is_err());
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let value = json!({"dtype": "string", "v": 1, "dim": [2], "data": ["a", "héllo"]});
+        let tensor: Tensor = serde_json::from_value(value.clone()).unwrap();
+        let Tensor::String(array) = &tensor else {
+            panic!("should deserialize as string");
+        };
+        assert_eq!(array.iter().collect::<Vec<_>>(), ["a", "héllo"]);
+        assert_eq!(serde_json::to_value(&tensor).unwrap(), value);
+        assert!(tensor.to_f64().is_none());
+    }
+}
@@ -2,9 +2,13 @@ use ndarray::{Array, Dimension, IxDyn};
 use std::collections::HashMap;
 use std::time::Duration;
 
+use crate::tensor::Tensor;
+
 pub trait Model {
     fn name(&self) -> String;
 
+    fn input_name(&self) -> String;
+
     fn input_shape(&self) -> Vec<usize>;
 
     fn dummy_data(&self) -> Array<f32, IxDyn> {
@@ -13,6 +17,10 @@ pub trait Model {
             .into_shape(shape)
             .unwrap()
     }
+
+    fn dummy_inputs(&self) -> HashMap<String, Tensor> {
+        HashMap::from([(self.input_name(), Tensor::Float32(self.dummy_data()))])
+    }
 }
 
 pub struct Squeezenet;
@@ -22,6 +30,10 @@ impl Model for Squeezenet {
         "squeezenet".to_string()
     }
 
+    fn input_name(&self) -> String {
+        "data_0".to_string()
+    }
+
     fn input_shape(&self) -> Vec<usize> {
         vec![1, 3, 224, 224]
     }
@@ -34,6 +46,10 @@ impl Model for MaskRCNN {
         "maskrcnn".to_string()
     }
 
+    fn input_name(&self) -> String {
+        "image".to_string()
+    }
+
     fn input_shape(&self) -> Vec<usize> {
         vec![3, 224, 224]
     }
@@ -1,24 +1,49 @@
-use ndarray::{Array, IxDyn};
+use std::collections::HashMap;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{oneshot, Notify};
 use uuid::Uuid;
 
-use crate::config::ModelConfig;
+use crate::batch;
+use crate::config::{BatchingConfig, ModelConfig};
+use crate::error::Error;
 use crate::model::Model;
-use crate::state::SharedState;
+use crate::priority::{Priority, PriorityQueue};
+use crate::registry::ModelState;
+use crate::tensor::Tensor;
 
 #[derive(Debug)]
 pub struct Message {
     pub prediction_id: Uuid,
     pub model_name: String,
-    pub input_data: Array<f32, IxDyn>,
-    pub response_tx: oneshot::Sender<Array<f32, IxDyn>>,
+    pub inputs: HashMap<String, Tensor>,
+    /// The request is dropped instead of run once this has passed
+    pub deadline: Option<Instant>,
+    pub priority: Priority,
+    /// When the request was put on the queue
+    pub enqueued_at: Instant,
+    pub response_tx: oneshot::Sender<Result<HashMap<String, Tensor>, Error>>,
+}
+
+/// Why a worker stopped without crashing
+#[derive(Debug)]
+pub enum WorkerExit {
+    /// The model's queue was closed, so there is no more work to do
+    QueueClosed,
+    /// The worker handled `max_requests_per_worker` requests and should be replaced
+    Recycled,
 }
 
 pub struct InferenceWorker {
     pub config: ModelConfig,
-    shared_state: Arc<SharedState>,
+    pub replica: usize,
+    state: Arc<ModelState>,
+    runtime: Handle,
+    /// A request received while collecting a batch that didn't fit in it
+    pending: Option<Message>,
 }
 
 /// `InferenceWorker` is responsible for running inference on a specific ONNX model.
@@ -29,38 +54,362 @@ pub struct InferenceWorker {
 /// own. If so, it runs the inference and sends the result back to the request sender
 /// through a one-shot channel.
 ///
+/// A model can run several replicas, each with its own session, that share the model's
+/// queue. A replica holds the queue only while it takes requests off it, so the others
+/// can pick up new requests while it waits to fill a batch or runs inference. The queue
+/// hands out requests by priority, see [`PriorityQueue`].
+///
+/// With batching enabled, the worker waits up to `max_queue_delay_ms` for more requests
+/// after the first one arrives, stacks compatible requests along the batch dimension and
+/// runs them through the session together.
+///
 impl InferenceWorker {
-    pub fn new(config: ModelConfig, shared_state: Arc<SharedState>) -> Self {
+    /// The runtime is used to wait on the queue with a timeout while collecting batches
+    pub fn new(state: Arc<ModelState>, replica: usize, runtime: Handle) -> Self {
         Self {
-            config,
-            shared_state,
+            config: state.config.clone(),
+            replica,
+            state,
+            runtime,
+            pending: None,
         }
     }
 
-    pub fn run(&mut self, mut requests_rx: mpsc::Receiver<Message>) {
-        let mut model = Model::new(&self.config);
-        tracing::info!("{:?} model ready", &self.config.name);
+    pub fn run(&mut self, requests_rx: Arc<Mutex<PriorityQueue>>) -> WorkerExit {
+        let mut model = self.load();
+        let arrivals = lock(&requests_rx).arrivals();
+
+        // Run the worker loop until the queue is closed or the worker is due for recycling
+        let mut handled = 0;
+        loop {
+            if let Some(max_requests) = self.config.max_requests_per_worker {
+                if handled >= max_requests {
+                    // Finish the request held over from the last batch before handing over
+                    if let Some(message) = self.pending.take() {
+                        self.run_group(&mut model, vec![message]);
+                    }
+                    return WorkerExit::Recycled;
+                }
+            }
 
-        // Update shared state to flag this model as ready
-        self.shared_state
-            .ready
-            .get(&self.config.name)
-            .unwrap()
-            .store(true, Ordering::Relaxed);
+            let messages = match self.config.batching.clone() {
+                Some(batching) => self.next_batch(&requests_rx, &arrivals, &batching),
+                None => lock(&requests_rx)
+                    .blocking_recv()
+                    .map(|message| vec![message]),
+            };
+            let Some(messages) = messages else {
+                tracing::info!(
+                    "{:?} replica {} queue closed",
+                    &self.config.name,
+                    self.replica
+                );
+                return WorkerExit::QueueClosed;
+            };
 
-        // Run the worker loop
+            handled += messages.len();
+            self.handle(&mut model, messages);
+        }
+    }
+
+    /// Create a session for the model, publish its signature and flag this replica as ready
+    pub fn load(&self) -> Model {
+        let model = Model::new(&self.config);
+        tracing::info!(
+            "{:?} replica {} model ready",
+            &self.config.name,
+            self.replica
+        );
+
+        *self.state.metadata.write().unwrap() = Some(model.metadata.clone());
+        self.state.ready[self.replica].store(true, Ordering::Relaxed);
+        metrics::gauge!("proton_worker_ready", 1.0, &self.labels());
+        model
+    }
+
+    /// Take the requests already waiting in the queue, up to a full batch when batching,
+    /// without waiting for one to arrive. Returns `None` once the queue is closed and
+    /// drained. Used by workers sharing a thread pool, which can't block on one model.
+    ///
+    /// Unlike `next_batch` this doesn't wait `max_queue_delay_ms` for a batch to fill up,
+    /// as that would hold up every other model on the thread. Batches are only as large as
+    /// the backlog, so they tend to be smaller under light load and fill up as requests
+    /// queue behind a busy thread.
+    pub fn try_collect(&mut self, requests_rx: &mut PriorityQueue) -> Option<Vec<Message>> {
+        let first = match self.pending.take() {
+            Some(message) => message,
+            None => match requests_rx.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return Some(Vec::new()),
+                Err(TryRecvError::Disconnected) => return None,
+            },
+        };
+
+        let mut messages = vec![first];
+        if let Some(batching) = self.config.batching.clone() {
+            self.take_queued(requests_rx, &mut messages, &batching);
+        }
+        Some(messages)
+    }
+
+    /// Run the requests through the model, stacking those that can share a batch unless
+    /// the model has a fixed batch size
+    pub fn handle(&self, model: &mut Model, messages: Vec<Message>) {
+        self.record_waits(&messages);
+        for group in batch::group(messages, model.batchable) {
+            self.run_group(model, group);
+        }
+    }
+
+    /// Metric labels identifying this replica
+    fn labels(&self) -> [(&'static str, String); 3] {
+        [
+            ("model", self.config.name.clone()),
+            ("version", self.config.version.clone()),
+            ("replica", self.replica.to_string()),
+        ]
+    }
+
+    /// Record how long each request waited in the queue, by priority
+    fn record_waits(&self, messages: &[Message]) {
+        for message in messages {
+            metrics::histogram!(
+                "proton_queue_wait_seconds",
+                message.enqueued_at.elapsed().as_secs_f64(),
+                "model" => self.config.name.clone(),
+                "version" => self.config.version.clone(),
+                "priority" => message.priority.name()
+            );
+        }
+    }
+
+    /// Wait for a request, then keep collecting requests until the batch is full or the
+    /// queue delay has passed. Returns `None` once the queue is closed.
+    ///
+    /// The queue is only held while taking requests off it, not while waiting for more to
+    /// arrive, so other replicas can pick up work in the meantime.
+    fn next_batch(
+        &mut self,
+        requests_rx: &Mutex<PriorityQueue>,
+        arrivals: &Notify,
+        batching: &BatchingConfig,
+    ) -> Option<Vec<Message>> {
+        let first = match self.pending.take() {
+            Some(message) => message,
+            None => lock(requests_rx).blocking_recv()?,
+        };
+        let deadline = Instant::now() + Duration::from_millis(batching.max_queue_delay_ms);
+
+        let mut messages = vec![first];
         loop {
-            let request = requests_rx.blocking_recv().unwrap();
-            let model_name = request.model_name;
-            let id = request.prediction_id;
+            // Listen before looking at the queue, so a request sent in between isn't missed
+            let arrived = arrivals.notified();
+            tokio::pin!(arrived);
+            arrived.as_mut().enable();
+
+            if !self.take_queued(&mut lock(requests_rx), &mut messages, batching) {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let _ = self
+                .runtime
+                .block_on(tokio::time::timeout(remaining, arrived));
+        }
+
+        Some(messages)
+    }
+
+    /// Add the requests already waiting in the queue to the batch, until it is full. Returns
+    /// whether the batch has room for more and the queue is still open.
+    fn take_queued(
+        &mut self,
+        requests_rx: &mut PriorityQueue,
+        messages: &mut Vec<Message>,
+        batching: &BatchingConfig,
+    ) -> bool {
+        let mut size: usize = messages
+            .iter()
+            .map(|message| batch::batch_size(&message.inputs).unwrap_or(1))
+            .sum();
+        while size < batching.max_batch_size {
+            let message = match requests_rx.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            };
+
+            let message_size = batch::batch_size(&message.inputs).unwrap_or(1);
+            if size + message_size > batching.max_batch_size {
+                self.pending = Some(message);
+                return false;
+            }
+            size += message_size;
+            messages.push(message);
+        }
 
-            tracing::info!("{:?} got prediction_id={:?}", model_name, id);
+        false
+    }
+
+    /// Run a group of compatible requests through the model in one go
+    fn run_group(&self, model: &mut Model, group: Vec<Message>) {
+        let mut group = self.drop_expired(group);
+        if group.is_empty() {
+            return;
+        }
+        let model_name = &self.config.name;
+        let ids: Vec<Uuid> = group.iter().map(|message| message.prediction_id).collect();
+        tracing::info!(
+            "{:?} replica {} got prediction_ids={:?}",
+            model_name,
+            self.replica,
+            ids
+        );
 
-            let output = model.predict(vec![request.input_data]);
+        if group.len() == 1 {
+            let message = group.pop().unwrap();
+            let size = batch::batch_size(&message.inputs).unwrap_or(1);
+            metrics::histogram!("proton_batch_size", size as f64, &self.labels());
 
-            // Send the prediction back to the handler
-            request.response_tx.send(output).unwrap();
-            tracing::info!("{:?} sent prediction_id={:?}", model_name, id);
+            let result = self.predict(model, message.inputs);
+            self.respond(message.prediction_id, message.response_tx, result);
+            return;
+        }
+
+        let (inputs, senders): (Vec<_>, Vec<_>) = group
+            .into_iter()
+            .map(|message| (message.inputs, (message.prediction_id, message.response_tx)))
+            .unzip();
+
+        // Requests in a group share a signature so they stack, and fail, together
+        let Some((inputs, sizes)) = batch::stack(inputs) else {
+            let err = Error::Internal(format!(
+                "couldn't stack a batch of requests for model {}",
+                model_name
+            ));
+            for (id, response_tx) in senders {
+                self.respond(id, response_tx, Err(err.clone()));
+            }
+            return;
+        };
+        metrics::histogram!(
+            "proton_batch_size",
+            sizes.iter().sum::<usize>() as f64,
+            &self.labels()
+        );
+
+        let results = self.predict(model, inputs).and_then(|outputs| {
+            batch::unstack(outputs, &sizes).ok_or_else(|| {
+                Error::Internal(format!(
+                    "outputs of model {} aren't batched along the first axis",
+                    model_name
+                ))
+            })
+        });
+
+        match results {
+            Ok(outputs) => {
+                for ((id, response_tx), outputs) in senders.into_iter().zip(outputs) {
+                    self.respond(id, response_tx, Ok(outputs));
+                }
+            }
+            Err(err) => {
+                for (id, response_tx) in senders {
+                    self.respond(id, response_tx, Err(err.clone()));
+                }
+            }
         }
     }
+
+    /// Drop requests whose handler has gone away, e.g. because the client disconnected, or
+    /// whose deadline has passed, so the model doesn't run for nobody
+    fn drop_expired(&self, group: Vec<Message>) -> Vec<Message> {
+        let now = Instant::now();
+        let mut live = Vec::with_capacity(group.len());
+
+        for message in group {
+            // Nobody is waiting for the response of a cancelled request
+            if message.response_tx.is_closed() {
+                self.record_drop(&message, "cancelled");
+                continue;
+            }
+            if message.deadline.is_some_and(|deadline| deadline <= now) {
+                self.record_drop(&message, "deadline");
+                let _ = message
+                    .response_tx
+                    .send(Err(Error::DeadlineExceeded(format!(
+                        "deadline passed before model {} ran",
+                        self.config.name
+                    ))));
+                continue;
+            }
+            live.push(message);
+        }
+
+        live
+    }
+
+    fn record_drop(&self, message: &Message, reason: &'static str) {
+        let mut labels = self.labels().to_vec();
+        labels.push(("reason", reason.to_string()));
+        metrics::increment_counter!("proton_requests_dropped_total", &labels);
+        tracing::warn!(
+            "{:?} dropped prediction_id={:?}: {}",
+            self.config.name,
+            message.prediction_id,
+            reason
+        );
+    }
+
+    /// Run the model, recording how long inference takes on this replica
+    fn predict(
+        &self,
+        model: &mut Model,
+        inputs: HashMap<String, Tensor>,
+    ) -> Result<HashMap<String, Tensor>, Error> {
+        let start = Instant::now();
+        let result = model.predict(inputs);
+        metrics::histogram!(
+            "proton_inference_duration_seconds",
+            start.elapsed().as_secs_f64(),
+            &self.labels()
+        );
+
+        result.map_err(Error::from)
+    }
+
+    /// Send the prediction, or the reason it failed, back to the handler
+    fn respond(
+        &self,
+        id: Uuid,
+        response_tx: oneshot::Sender<Result<HashMap<String, Tensor>, Error>>,
+        result: Result<HashMap<String, Tensor>, Error>,
+    ) {
+        if let Err(err) = &result {
+            tracing::error!(
+                "{:?} failed prediction_id={:?}: {}",
+                self.config.name,
+                id,
+                err
+            );
+        }
+
+        if response_tx.send(result).is_err() {
+            tracing::warn!(
+                "{:?} handler went away for prediction_id={:?}",
+                self.config.name,
+                id
+            );
+            return;
+        }
+        tracing::info!("{:?} sent prediction_id={:?}", self.config.name, id);
+    }
+}
+
+/// Lock the model's queue. A replica that panicked while holding the queue leaves it
+/// poisoned, but the receiver itself is still usable.
+fn lock(requests_rx: &Mutex<PriorityQueue>) -> MutexGuard<'_, PriorityQueue> {
+    requests_rx.lock().unwrap_or_else(PoisonError::into_inner)
 }